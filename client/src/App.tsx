@@ -1,10 +1,15 @@
 import React from 'react';
 
+import { LoginForm } from 'client/LoginForm';
 import { ServerButton } from 'client/ServerButton';
-import { ServerSocket } from 'client/ServerMsgs';
+import { ServerSocket, ServerSummary, authorized } from 'client/ServerMsgs';
 import { AsyncSocketContext } from 'client/util/async_sockets';
+import { isOk } from 'client/util/status';
 import { inSecureEnvironment } from 'client/util/util';
 
+/** Where the session token is kept, so reloading doesn't log the user out. */
+const TOKEN_KEY = 'session_token';
+
 const socket: ServerSocket = new AsyncSocketContext(
   `${inSecureEnvironment() ? 'wss' : 'ws'}://${
     window.location.hostname
@@ -14,5 +19,80 @@ const socket: ServerSocket = new AsyncSocketContext(
 );
 
 export function App() {
-  return <ServerButton socket={socket} />;
+  const [token, setToken] = React.useState<string | null>(() =>
+    localStorage.getItem(TOKEN_KEY)
+  );
+
+  const logIn = (newToken: string) => {
+    localStorage.setItem(TOKEN_KEY, newToken);
+    setToken(newToken);
+  };
+  const forgetSession = () => {
+    localStorage.removeItem(TOKEN_KEY);
+    setToken(null);
+  };
+
+  const [servers, setServers] = React.useState<ServerSummary[]>([]);
+  const [serverId, setServerId] = React.useState<string | undefined>(
+    undefined
+  );
+  React.useEffect(() => {
+    if (token === null) {
+      return;
+    }
+    socket
+      .awaitOpen()
+      .then(() => socket.call('list_servers', token))
+      .then((res) => {
+        const status = authorized(res, forgetSession);
+        if (status === undefined) {
+          return;
+        }
+        if (isOk(status)) {
+          setServers(status.value.servers);
+          setServerId((selected) => selected ?? status.value.servers[0]?.id);
+        } else {
+          console.error(`Error: ${status.status} ${status.message}`);
+        }
+      });
+  }, [token]);
+
+  if (token === null) {
+    return <LoginForm socket={socket} onLogin={logIn} />;
+  }
+  return (
+    <>
+      {servers.length > 1 && (
+        <select
+          value={serverId}
+          onChange={(event) => setServerId(event.target.value)}
+        >
+          {servers.map((server) => (
+            <option key={server.id} value={server.id}>
+              {server.display_name}
+            </option>
+          ))}
+        </select>
+      )}
+      {serverId !== undefined && (
+        // Remounted when another server is picked, to fetch its state.
+        <ServerButton
+          key={serverId}
+          socket={socket}
+          serverId={serverId}
+          token={token}
+          onUnauthorized={forgetSession}
+        />
+      )}
+      <br />
+      <div
+        onClick={() => {
+          socket.call('logout', token);
+          forgetSession();
+        }}
+      >
+        Log Out
+      </div>
+    </>
+  );
 }
@@ -0,0 +1,54 @@
+import React from 'react';
+
+import { ServerSocket, isUnauthorized } from 'client/ServerMsgs';
+import { isOk } from 'client/util/status';
+
+export interface LoginFormProps {
+  socket: ServerSocket;
+  /** Called with the session token once the user has logged in. */
+  onLogin: (token: string) => void;
+}
+
+export function LoginForm(props: LoginFormProps) {
+  const [username, setUsername] = React.useState('');
+  const [password, setPassword] = React.useState('');
+  const [error, setError] = React.useState<string | undefined>(undefined);
+
+  return (
+    <form
+      onSubmit={(event) => {
+        event.preventDefault();
+        props.socket
+          .awaitOpen()
+          .then(() => props.socket.call('login', username, password))
+          .then((status) => {
+            if (!isOk(status)) {
+              setError(`Error: ${status.status} ${status.message}`);
+            } else if (isUnauthorized(status.value)) {
+              setError(status.value.reason);
+            } else {
+              setPassword('');
+              props.onLogin(status.value.token);
+            }
+          });
+      }}
+    >
+      <input
+        type='text'
+        placeholder='Username'
+        autoComplete='username'
+        value={username}
+        onChange={(event) => setUsername(event.target.value)}
+      />
+      <input
+        type='password'
+        placeholder='Password'
+        autoComplete='current-password'
+        value={password}
+        onChange={(event) => setPassword(event.target.value)}
+      />
+      <button type='submit'>Log In</button>
+      {error && <div>{error}</div>}
+    </form>
+  );
+}
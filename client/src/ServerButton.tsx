@@ -1,22 +1,56 @@
 import React from 'react';
 
-import { ServerSocket } from 'client/ServerMsgs';
+import { ServerInfo, ServerSocket, authorized } from 'client/ServerMsgs';
 import { isOk } from 'client/util/status';
 import { ServerState } from 'proto/mc_server';
 
-const SERVER_STATE_REFRESH_INTERVAL = 5000;
-
-async function getMcServerStatus(socket: ServerSocket): Promise<ServerState> {
+async function getMcServerStatus(
+  socket: ServerSocket,
+  token: string,
+  serverId: string,
+  onUnauthorized: () => void
+): Promise<ServerState> {
   await socket.awaitOpen();
-  const status = await socket.call('mc_server_status');
-  if (isOk(status)) {
+  const status = authorized(
+    await socket.call('mc_server_status', token, serverId),
+    onUnauthorized
+  );
+  if (status && isOk(status)) {
     return status.value.state;
   }
   return ServerState.UNKNOWN;
 }
 
+async function getMcServerInfo(
+  socket: ServerSocket,
+  token: string,
+  serverId: string,
+  onUnauthorized: () => void
+): Promise<ServerInfo | undefined> {
+  const status = authorized(
+    await socket.call('mc_server_info', token, serverId),
+    onUnauthorized
+  );
+  if (status && isOk(status)) {
+    return status.value.info;
+  }
+  return undefined;
+}
+
+function playersOnline(info: ServerInfo): string {
+  const online = `${info.players_online}/${info.players_max} online`;
+  return info.players.length > 0
+    ? `${online}: ${info.players.join(', ')}`
+    : online;
+}
+
 export interface ServerButtonProps {
   socket: ServerSocket;
+  serverId: string;
+  /** The session token every request is authenticated with. */
+  token: string;
+  /** Called when the server rejects the session, e.g. once it expires. */
+  onUnauthorized: () => void;
 }
 
 export function ServerButton(props: ServerButtonProps) {
@@ -25,22 +59,48 @@ export function ServerButton(props: ServerButtonProps) {
   stateRef.current = state;
   const setStateRef = React.useRef(setState);
   setStateRef.current = setState;
+  const [info, setInfo] = React.useState<ServerInfo | undefined>(undefined);
+  const [notice, setNotice] = React.useState<string | undefined>(undefined);
 
   React.useEffect(() => {
-    getMcServerStatus(props.socket).then(setStateRef.current);
+    if (state === ServerState.ON) {
+      getMcServerInfo(
+        props.socket,
+        props.token,
+        props.serverId,
+        props.onUnauthorized
+      ).then(setInfo);
+    } else if (state === ServerState.BOOTING) {
+      setNotice(undefined);
+      setInfo(undefined);
+    } else {
+      setInfo(undefined);
+    }
+  }, [state]);
 
-    const refreshStateIntervalId = setInterval(
-      () => {
-        getMcServerStatus(props.socket).then(setStateRef.current);
-      },
-      SERVER_STATE_REFRESH_INTERVAL,
+  React.useEffect(() => {
+    // The server pushes every state change after this initial fetch.
+    props.socket.on('server_state_changed', (serverId, newState) => {
+      if (serverId === props.serverId) {
+        setStateRef.current(newState);
+      }
+    });
+    props.socket.on('idle_shutdown_warning', (serverId, shutdownInSecs) => {
+      if (serverId === props.serverId) {
+        setNotice(`No one is online, shutting down in ${shutdownInSecs}s`);
+      }
+    });
+    props.socket.on('auto_shutdown', (serverId, reason) => {
+      if (serverId === props.serverId) {
+        setNotice(`Shut down automatically: ${reason}`);
+      }
+    });
+    getMcServerStatus(
       props.socket,
-      setStateRef
-    );
-
-    return () => {
-      clearInterval(refreshStateIntervalId);
-    };
+      props.token,
+      props.serverId,
+      props.onUnauthorized
+    ).then(setStateRef.current);
   }, []);
 
   let action;
@@ -65,6 +125,14 @@ export function ServerButton(props: ServerButtonProps) {
       action = 'Turning Server Off...';
       break;
     }
+    case ServerState.RESTARTING: {
+      action = 'Restarting Server...';
+      break;
+    }
+    case ServerState.FAILED: {
+      action = 'Server Failed, Reset';
+      break;
+    }
   }
 
   return (
@@ -72,26 +140,47 @@ export function ServerButton(props: ServerButtonProps) {
       <div
         onClick={() => {
           if (state === ServerState.OFF) {
-            props.socket.call('boot_server').then((status) => {
-              if (isOk(status)) {
-                if (stateRef.current === ServerState.OFF) {
-                  setStateRef.current(ServerState.BOOTING);
+            props.socket
+              .call('boot_server', props.token, props.serverId)
+              .then((res) => {
+                const status = authorized(res, props.onUnauthorized);
+                if (status === undefined) {
+                  return;
+                }
+                if (isOk(status)) {
+                  if (stateRef.current === ServerState.OFF) {
+                    setStateRef.current(ServerState.BOOTING);
+                  }
+                } else {
+                  console.error(`Error: ${status.status} ${status.message}`);
                 }
-              } else {
-                console.error(`Error: ${status.status} ${status.message}`);
-              }
-            });
+              });
+          } else if (state === ServerState.FAILED) {
+            props.socket
+              .call('reset_server', props.token, props.serverId)
+              .then((res) => {
+                const status = authorized(res, props.onUnauthorized);
+                if (status && !isOk(status)) {
+                  console.error(`Error: ${status.status} ${status.message}`);
+                }
+              });
           } else if (state === ServerState.ON) {
             setState(ServerState.SHUTDOWN);
-            props.socket.call('shutdown_server').then((status) => {
-              if (isOk(status)) {
-                if (stateRef.current === ServerState.SHUTDOWN) {
-                  setStateRef.current(ServerState.OFF);
+            props.socket
+              .call('shutdown_server', props.token, props.serverId)
+              .then((res) => {
+                const status = authorized(res, props.onUnauthorized);
+                if (status === undefined) {
+                  return;
+                }
+                if (isOk(status)) {
+                  if (stateRef.current === ServerState.SHUTDOWN) {
+                    setStateRef.current(ServerState.OFF);
+                  }
+                } else {
+                  console.error(`Error: ${status.status} ${status.message}`);
                 }
-              } else {
-                console.error(`Error: ${status.status} ${status.message}`);
-              }
-            });
+              });
           }
         }}
       >
@@ -99,6 +188,8 @@ export function ServerButton(props: ServerButtonProps) {
       </div>
       <br />
       <div>Current Status: {state}</div>
+      {info && <div>{playersOnline(info)}</div>}
+      {notice && <div>{notice}</div>}
     </>
   );
 }
@@ -1,21 +1,105 @@
 import { AsyncSocketContext } from 'client/util/async_sockets';
-import { Status } from 'client/util/status';
+import { Status, isOk } from 'client/util/status';
 import { Empty } from 'client/util/util';
 import { ServerState } from 'proto/mc_server';
 
+export interface ServerInfo {
+  motd: string;
+  version: string;
+  /* eslint-disable @typescript-eslint/naming-convention */
+  players_online: number;
+  players_max: number;
+  /* eslint-enable @typescript-eslint/naming-convention */
+  players: string[];
+}
+
+/** A managed server, as listed by `list_servers`. */
+export interface ServerSummary {
+  id: string;
+  /* eslint-disable @typescript-eslint/naming-convention */
+  display_name: string;
+  /* eslint-enable @typescript-eslint/naming-convention */
+  state: ServerState;
+  failure?: string;
+  stale: boolean;
+}
+
+/**
+ * Sent in place of the response to a request whose session token is missing,
+ * expired, or lacks the role the request requires, and to a login with the
+ * wrong password.
+ */
+export interface Unauthorized {
+  reason: string;
+}
+
+export type Authed<T> = T | Unauthorized;
+
+export function isUnauthorized(value: unknown): value is Unauthorized {
+  return (
+    value !== null &&
+    typeof value === 'object' &&
+    'reason' in value &&
+    typeof value.reason === 'string'
+  );
+}
+
+/**
+ * Narrows the status of an authenticated call, calling `onUnauthorized` and
+ * returning `undefined` if the call was rejected for its session.
+ */
+export function authorized<T>(
+  status: Status<Authed<T>>,
+  onUnauthorized: () => void
+): Status<T> | undefined {
+  if (isOk(status) && isUnauthorized(status.value)) {
+    onUnauthorized();
+    return undefined;
+  }
+  return status as Status<T>;
+}
+
 interface ServerToClient {
   /* eslint-disable @typescript-eslint/naming-convention */
-  boot_server_res: (res: Status<Empty>) => void;
-  shutdown_server_res: (res: Status<Empty>) => void;
-  mc_server_status_res: (res: Status<{ state: ServerState }>) => void;
+  login_res: (res: Status<Authed<{ token: string }>>) => void;
+  logout_res: (res: Status<Empty>) => void;
+  list_servers_res: (
+    res: Status<Authed<{ servers: ServerSummary[] }>>
+  ) => void;
+  boot_server_res: (res: Status<Authed<Empty>>) => void;
+  shutdown_server_res: (res: Status<Authed<Empty>>) => void;
+  restart_server_res: (res: Status<Authed<Empty>>) => void;
+  cancel_operation_res: (res: Status<Authed<Empty>>) => void;
+  reset_server_res: (res: Status<Authed<Empty>>) => void;
+  mc_server_status_res: (
+    res: Status<
+      Authed<{
+        state: ServerState;
+        failure?: string;
+        info?: ServerInfo;
+        stale: boolean;
+      }>
+    >
+  ) => void;
+  mc_server_info_res: (res: Status<Authed<{ info?: ServerInfo }>>) => void;
+  server_state_changed: (serverId: string, state: ServerState) => void;
+  idle_shutdown_warning: (serverId: string, shutdownInSecs: number) => void;
+  auto_shutdown: (serverId: string, reason: string) => void;
   /* eslint-enable @typescript-eslint/naming-convention */
 }
 
 interface ClientToServer {
   /* eslint-disable @typescript-eslint/naming-convention */
-  boot_server_req: () => void;
-  shutdown_server_req: () => void;
-  mc_server_status_req: () => void;
+  login_req: (username: string, password: string) => void;
+  logout_req: (token: string) => void;
+  list_servers_req: (token: string) => void;
+  boot_server_req: (token: string, serverId: string) => void;
+  shutdown_server_req: (token: string, serverId: string) => void;
+  restart_server_req: (token: string, serverId: string) => void;
+  cancel_operation_req: (token: string, serverId: string) => void;
+  reset_server_req: (token: string, serverId: string) => void;
+  mc_server_status_req: (token: string, serverId: string) => void;
+  mc_server_info_req: (token: string, serverId: string) => void;
   /* eslint-enable @typescript-eslint/naming-convention */
 }
 
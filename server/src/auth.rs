@@ -1,30 +1,208 @@
-use std::collections::hash_map;
+use std::{
+  collections::{hash_map, HashMap},
+  fmt::Write,
+  time::Duration,
+};
 
+use argon2::{Algorithm, Argon2, Params, Version};
 use prost::Message;
+use rand::{rngs::OsRng, RngCore};
 use serde::{de::Visitor, Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::time::Instant;
 
 use crate::{
+  checkpoint_codec::ProtobufCodec,
+  checkpoint_stream::{CheckpointNotifier, IncrementalUpdate},
   error::{McError, McResult},
-  proto::{User, UserMap},
+  proto::{PasswordHash, PasswordHashParams, Role, User, UserChange, UserMap, UserMapDelta},
 };
 
+const SALT_LEN: usize = argon2::RECOMMENDED_SALT_LEN;
+const HASH_LEN: usize = Params::DEFAULT_OUTPUT_LEN;
+
+const SESSION_TOKEN_LEN: usize = 32;
+const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The Argon2id parameters new password hashes are derived with, unless
+/// overridden with `UserStore::with_hash_params`.
+pub fn default_hash_params() -> PasswordHashParams {
+  PasswordHashParams {
+    memory_cost_kib: Some(Params::DEFAULT_M_COST),
+    iterations: Some(Params::DEFAULT_T_COST),
+    parallelism: Some(Params::DEFAULT_P_COST),
+  }
+}
+
+fn derive_hash(
+  password: &str,
+  params: &PasswordHashParams,
+  salt: &[u8],
+  hash_len: usize,
+) -> McResult<Vec<u8>> {
+  let params = Params::new(
+    params.memory_cost_kib(),
+    params.iterations(),
+    params.parallelism(),
+    Some(hash_len),
+  )
+  .map_err(|err| McError::HashError(err.to_string()))?;
+
+  let mut hash = vec![0; hash_len];
+  Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    .hash_password_into(password.as_bytes(), salt, &mut hash)
+    .map_err(|err| McError::HashError(err.to_string()))?;
+  Ok(hash)
+}
+
+/// Hashes `password` with a freshly generated salt.
+fn hash_password(password: &str, params: &PasswordHashParams) -> McResult<PasswordHash> {
+  let mut salt = vec![0; SALT_LEN];
+  OsRng.fill_bytes(&mut salt);
+  let hash = derive_hash(password, params, &salt, HASH_LEN)?;
+  Ok(PasswordHash {
+    params: Some(params.clone()),
+    salt: Some(salt),
+    hash: Some(hash),
+  })
+}
+
+/// Returns true if `candidate` hashes to `password_hash`. The hashes are
+/// compared in constant time.
+fn matches(password_hash: &PasswordHash, candidate: &str) -> McResult<bool> {
+  let (Some(params), Some(salt), Some(hash)) = (
+    password_hash.params.as_ref(),
+    password_hash.salt.as_ref(),
+    password_hash.hash.as_ref(),
+  ) else {
+    return Err(McError::HashError("Incomplete password hash".to_owned()));
+  };
+
+  let candidate_hash = derive_hash(candidate, params, salt, hash.len())?;
+  Ok(candidate_hash.ct_eq(hash).into())
+}
+
+/// Derives a throwaway hash of `candidate`, so checking the password of a
+/// user who doesn't exist takes as long as checking a real user's, and doesn't
+/// reveal which usernames exist.
+fn dummy_hash(candidate: &str, params: &PasswordHashParams) -> McResult<()> {
+  derive_hash(candidate, params, &[0; SALT_LEN], HASH_LEN)?;
+  Ok(())
+}
+
+/// A user's password hash, taken from a `UserStore` so a password can be
+/// checked against it without holding the store, since hashing is slow.
+pub struct PasswordCheck {
+  username: String,
+  password_hash: Option<PasswordHash>,
+  hash_params: PasswordHashParams,
+}
+
+/// A password which passed a `PasswordCheck`, to be completed with
+/// `UserStore::complete_password_check`.
+pub struct VerifiedPassword {
+  username: String,
+  /// The hash the password was checked against.
+  checked_hash: PasswordHash,
+  /// The password hashed with the store's current parameters, if the checked
+  /// hash was derived with different ones.
+  rehashed: Option<PasswordHash>,
+}
+
+impl PasswordCheck {
+  /// Returns `Some` if `candidate` is the user's password, and `None` if it
+  /// isn't or the user doesn't exist. `candidate` is hashed either way, so
+  /// both failures take as long.
+  pub fn verify(self, candidate: &str) -> McResult<Option<VerifiedPassword>> {
+    let Some(password_hash) = self.password_hash else {
+      dummy_hash(candidate, &self.hash_params)?;
+      return Ok(None);
+    };
+    if !matches(&password_hash, candidate)? {
+      return Ok(None);
+    }
+
+    let rehashed = if password_hash.params.as_ref() != Some(&self.hash_params) {
+      Some(hash_password(candidate, &self.hash_params)?)
+    } else {
+      None
+    };
+    Ok(Some(VerifiedPassword {
+      username: self.username,
+      checked_hash: password_hash,
+      rehashed,
+    }))
+  }
+}
+
+/// The parameters a `UserStore` hashes passwords with, taken from the store
+/// so a new password can be hashed without holding the store.
+pub struct PasswordHasher {
+  hash_params: PasswordHashParams,
+}
+
+/// A password hashed by a `PasswordHasher`, to be stored with
+/// `UserStore::add_hashed_user`.
+pub struct HashedPassword(PasswordHash);
+
+impl PasswordHasher {
+  pub fn hash(&self, password: &str) -> McResult<HashedPassword> {
+    Ok(HashedPassword(hash_password(password, &self.hash_params)?))
+  }
+}
+
 pub struct UserStore {
   usermap: UserMap,
+  hash_params: PasswordHashParams,
+  /// Changes to `usermap` which have not been committed yet.
+  pending: HashMap<String, UserChange>,
+  /// Notified whenever a change is recorded.
+  checkpoint_notifier: Option<CheckpointNotifier>,
 }
 
 impl UserStore {
   pub fn new() -> Self {
+    Self::with_hash_params(default_hash_params())
+  }
+
+  pub fn with_hash_params(hash_params: PasswordHashParams) -> Self {
     Self {
       usermap: UserMap::default(),
+      hash_params,
+      pending: HashMap::new(),
+      checkpoint_notifier: None,
     }
   }
 
+  /// Notifies `checkpoint_notifier` of every future change to the store.
+  pub fn set_checkpoint_notifier(&mut self, checkpoint_notifier: CheckpointNotifier) {
+    self.checkpoint_notifier = Some(checkpoint_notifier);
+  }
+
   #[cfg(test)]
   pub fn num_users(&self) -> usize {
     self.usermap.users.len()
   }
 
-  pub fn add_user(&mut self, username: String, password: String) -> McResult<()> {
+  pub fn add_user(&mut self, username: String, password: String, role: Role) -> McResult<()> {
+    let password = self.password_hasher().hash(&password)?;
+    self.add_hashed_user(username, password, role)
+  }
+
+  /// Takes what's needed to hash a new password, like `add_user` but without
+  /// holding the store while hashing.
+  pub fn password_hasher(&self) -> PasswordHasher {
+    PasswordHasher {
+      hash_params: self.hash_params.clone(),
+    }
+  }
+
+  pub fn add_hashed_user(
+    &mut self,
+    username: String,
+    password: HashedPassword,
+    role: Role,
+  ) -> McResult<()> {
     if username.is_empty() {
       return Err(McError::InvalidOp("Cannot have empty username".to_owned()));
     }
@@ -35,16 +213,115 @@ impl UserStore {
       ))),
       hash_map::Entry::Vacant(entry) => {
         entry.insert(User {
-          password: Some(password),
+          password: None,
+          password_hash: Some(password.0),
+          role: Some(role.into()),
         });
+        self.record_change(&username);
         Ok(())
       }
     }
   }
 
+  pub fn remove_user(&mut self, username: &str) -> McResult<()> {
+    match self.usermap.users.remove(username) {
+      Some(_) => {
+        self.record_change(username);
+        Ok(())
+      }
+      None => Err(McError::InvalidOp(format!(
+        "User {username} does not exist"
+      ))),
+    }
+  }
+
+  pub fn set_role(&mut self, username: &str, role: Role) -> McResult<()> {
+    match self.usermap.users.get_mut(username) {
+      Some(user) => {
+        user.set_role(role);
+        self.record_change(username);
+        Ok(())
+      }
+      None => Err(McError::InvalidOp(format!(
+        "User {username} does not exist"
+      ))),
+    }
+  }
+
   pub fn find_user(&self, username: &str) -> Option<&User> {
     self.usermap.users.get(username)
   }
+
+  /// Returns true if `candidate` is the password of `username`, and false if
+  /// it isn't or the user doesn't exist. `candidate` is hashed either way, so
+  /// both failures take as long. If the user's password was hashed with
+  /// different parameters than this store's, it is rehashed with the current
+  /// parameters on success.
+  pub fn verify_password(&mut self, username: &str, candidate: &str) -> McResult<bool> {
+    let Some(verified) = self.password_check(username).verify(candidate)? else {
+      return Ok(false);
+    };
+    Ok(self.complete_password_check(verified))
+  }
+
+  /// Takes what's needed to check a password of `username`, like
+  /// `verify_password` but without holding the store while hashing.
+  pub fn password_check(&self, username: &str) -> PasswordCheck {
+    PasswordCheck {
+      username: username.to_owned(),
+      password_hash: self
+        .find_user(username)
+        .and_then(|user| user.password_hash.clone()),
+      hash_params: self.hash_params.clone(),
+    }
+  }
+
+  /// Stores the rehash of a verified password, if any. Returns false if the
+  /// user was removed or their password changed since it was checked.
+  pub fn complete_password_check(&mut self, verified: VerifiedPassword) -> bool {
+    let Some(user) = self.usermap.users.get_mut(&verified.username) else {
+      return false;
+    };
+    if user.password_hash.as_ref() != Some(&verified.checked_hash) {
+      return false;
+    }
+    if let Some(rehashed) = verified.rehashed {
+      user.password_hash = Some(rehashed);
+      self.record_change(&verified.username);
+    }
+    true
+  }
+
+  /// Replaces any plaintext passwords left over from before passwords were
+  /// hashed with their hashes.
+  fn migrate_plaintext_passwords(&mut self) -> McResult<()> {
+    let mut migrated = Vec::new();
+    for (username, user) in self.usermap.users.iter_mut() {
+      if let Some(password) = user.password.take() {
+        if user.password_hash.is_none() {
+          user.password_hash = Some(hash_password(&password, &self.hash_params)?);
+        }
+        migrated.push(username.clone());
+      }
+    }
+
+    for username in migrated {
+      self.record_change(&username);
+    }
+    Ok(())
+  }
+
+  /// Records the current state of `username` as a pending change, to be
+  /// emitted on the next commit.
+  fn record_change(&mut self, username: &str) {
+    let change = UserChange {
+      user: self.usermap.users.get(username).cloned(),
+    };
+    self.pending.insert(username.to_owned(), change);
+    if let Some(checkpoint_notifier) = &self.checkpoint_notifier {
+      checkpoint_notifier.notify();
+    }
+  }
 }
 
 impl Default for UserStore {
@@ -84,23 +361,141 @@ impl<'de> Deserialize<'de> for UserStore {
   where
     D: serde::Deserializer<'de>,
   {
-    Ok(Self {
+    let mut store = Self {
       usermap: deserializer.deserialize_bytes(UserMapDecoder)?,
-    })
+      ..Self::new()
+    };
+    store
+      .migrate_plaintext_passwords()
+      .map_err(serde::de::Error::custom)?;
+    Ok(store)
+  }
+}
+
+impl IncrementalUpdate for UserStore {
+  type Increment = UserMapDelta;
+  type Codec = ProtobufCodec;
+
+  fn has_update(&self) -> bool {
+    !self.pending.is_empty()
+  }
+
+  fn commit(&mut self) -> UserMapDelta {
+    UserMapDelta {
+      changes: std::mem::take(&mut self.pending),
+    }
+  }
+
+  fn apply(&mut self, delta: UserMapDelta) {
+    for (username, change) in delta.changes {
+      match change.user {
+        Some(user) => {
+          self.usermap.users.insert(username, user);
+        }
+        None => {
+          self.usermap.users.remove(&username);
+        }
+      }
+    }
+  }
+}
+
+struct Session {
+  username: String,
+  expires_at: Instant,
+}
+
+/// Tracks the sessions of logged-in users. Sessions are identified by opaque,
+/// randomly generated tokens, and expire a fixed amount of time after login.
+pub struct SessionStore {
+  sessions: HashMap<String, Session>,
+  ttl: Duration,
+}
+
+impl SessionStore {
+  pub fn new() -> Self {
+    Self::with_ttl(DEFAULT_SESSION_TTL)
+  }
+
+  pub fn with_ttl(ttl: Duration) -> Self {
+    Self {
+      sessions: HashMap::new(),
+      ttl,
+    }
+  }
+
+  /// Starts a new session for `username`, returning the session's token.
+  pub fn create_session(&mut self, username: String) -> String {
+    let mut token_bytes = [0; SESSION_TOKEN_LEN];
+    OsRng.fill_bytes(&mut token_bytes);
+    let token = token_bytes.iter().fold(String::new(), |mut token, byte| {
+      let _ = write!(token, "{byte:02x}");
+      token
+    });
+
+    self.sessions.insert(
+      token.clone(),
+      Session {
+        username,
+        expires_at: Instant::now() + self.ttl,
+      },
+    );
+    token
+  }
+
+  /// Returns the username of the session `token` belongs to, or `None` if
+  /// there is no such session or it has expired.
+  pub fn authenticate(&mut self, token: &str) -> Option<&str> {
+    let now = Instant::now();
+    self.sessions.retain(|_, session| session.expires_at > now);
+    self
+      .sessions
+      .get(token)
+      .map(|session| session.username.as_str())
+  }
+
+  /// Ends the session `token` belongs to. Returns false if there was no such
+  /// session.
+  pub fn end_session(&mut self, token: &str) -> bool {
+    self.sessions.remove(token).is_some()
+  }
+}
+
+impl Default for SessionStore {
+  fn default() -> Self {
+    Self::new()
   }
 }
 
 #[cfg(test)]
 mod test {
+  use std::{sync::Arc, time::Duration};
+
+  use prost::Message;
+  use tokio::{sync::Mutex, time};
   use tokio_util::bytes::Buf;
 
-  use super::UserStore;
+  use crate::{
+    checkpoint_codec::{Codec, ProtobufCodec},
+    checkpoint_stream::{CheckpointStreamOptions, CheckpointTrigger, IncrementalUpdate},
+    proto::{PasswordHashParams, Role, User, UserMap, UserMapDelta},
+  };
+
+  use super::{SessionStore, UserStore};
 
   fn ser_de(store: &UserStore) -> UserStore {
     let encoding = bincode::serialize(store).unwrap();
     bincode::deserialize(encoding.as_slice()).unwrap()
   }
 
+  fn cheap_hash_params() -> PasswordHashParams {
+    PasswordHashParams {
+      memory_cost_kib: Some(8),
+      iterations: Some(1),
+      parallelism: Some(1),
+    }
+  }
+
   #[test]
   fn test_empty() {
     let store = UserStore::new();
@@ -118,50 +513,164 @@ mod test {
   fn test_one_user() {
     let mut store = UserStore::new();
     store
-      .add_user("bob".to_owned(), "bob's password".to_owned())
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
       .unwrap();
     assert_eq!(store.num_users(), 1);
-    assert!(store.find_user("bob").is_some_and(|user| user
-      .password
-      .as_ref()
-      .is_some_and(|password| password == "bob's password")));
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+  }
+
+  #[test]
+  fn test_add_hashed_user() {
+    let mut store = UserStore::new();
+    let password = store.password_hasher().hash("bob's password").unwrap();
+    store
+      .add_hashed_user("bob".to_owned(), password, Role::Viewer)
+      .unwrap();
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+
+    let password = store.password_hasher().hash("other password").unwrap();
+    assert!(store
+      .add_hashed_user("bob".to_owned(), password, Role::Admin)
+      .is_err());
+  }
+
+  #[test]
+  fn test_password_not_stored_in_plaintext() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.password.is_none()
+        && user
+          .password_hash
+          .as_ref()
+          .is_some_and(|password_hash| password_hash.hash() != b"bob's password")));
+  }
+
+  #[test]
+  fn test_wrong_password() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    assert!(!store.verify_password("bob", "Bob's password").unwrap());
+    assert!(!store.verify_password("bob", "").unwrap());
+  }
+
+  #[test]
+  fn test_unknown_user() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    assert!(!store.verify_password("joe", "bob's password").unwrap());
+  }
+
+  #[test]
+  fn test_passwords_are_salted() {
+    let mut store = UserStore::new();
+    store
+      .add_user("a".to_owned(), "password".to_owned(), Role::Viewer)
+      .unwrap();
+    store
+      .add_user("b".to_owned(), "password".to_owned(), Role::Viewer)
+      .unwrap();
+    let a_hash = store.find_user("a").unwrap().password_hash.clone().unwrap();
+    let b_hash = store.find_user("b").unwrap().password_hash.clone().unwrap();
+    assert_ne!(a_hash.salt(), b_hash.salt());
+    assert_ne!(a_hash.hash(), b_hash.hash());
+  }
+
+  #[test]
+  fn test_rehash_on_param_change() {
+    let mut store = UserStore::with_hash_params(cheap_hash_params());
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+
+    let mut store = UserStore {
+      usermap: store.usermap,
+      ..UserStore::new()
+    };
+    assert!(!store.verify_password("bob", "wrong password").unwrap());
+    assert_eq!(
+      store
+        .find_user("bob")
+        .unwrap()
+        .password_hash
+        .as_ref()
+        .unwrap()
+        .params,
+      Some(cheap_hash_params())
+    );
+
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+    assert_eq!(
+      store
+        .find_user("bob")
+        .unwrap()
+        .password_hash
+        .as_ref()
+        .unwrap()
+        .params,
+      Some(super::default_hash_params())
+    );
+    assert!(store.verify_password("bob", "bob's password").unwrap());
   }
 
   #[test]
   fn test_serde_one_user() {
     let mut store = UserStore::new();
     store
-      .add_user("bob".to_owned(), "bob's password".to_owned())
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
       .unwrap();
-    let store = ser_de(&store);
+    let mut store = ser_de(&store);
     assert_eq!(store.num_users(), 1);
-    assert!(store.find_user("bob").is_some_and(|user| user
-      .password
-      .as_ref()
-      .is_some_and(|password| password == "bob's password")));
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+  }
+
+  #[test]
+  fn test_migrate_plaintext_passwords() {
+    let usermap = UserMap {
+      users: [(
+        "bob".to_owned(),
+        User {
+          password: Some("bob's password".to_owned()),
+          password_hash: None,
+          role: None,
+        },
+      )]
+      .into(),
+    };
+    let encoding = bincode::serialize(&usermap.encode_to_vec()).unwrap();
+
+    let mut store: UserStore = bincode::deserialize(encoding.as_slice()).unwrap();
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.password.is_none() && user.password_hash.is_some()));
+    assert!(store.verify_password("bob", "bob's password").unwrap());
   }
 
   #[test]
   fn test_no_repeat_usernames() {
     let mut store = UserStore::new();
     store
-      .add_user("bob".to_owned(), "bob's password".to_owned())
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
       .unwrap();
     store
-      .add_user("bob".to_owned(), "new password".to_owned())
+      .add_user("bob".to_owned(), "new password".to_owned(), Role::Viewer)
       .expect_err("Should not be allowed to add existing user");
     assert_eq!(store.num_users(), 1);
-    assert!(store.find_user("bob").is_some_and(|user| user
-      .password
-      .as_ref()
-      .is_some_and(|password| password == "bob's password")));
+    assert!(store.verify_password("bob", "bob's password").unwrap());
   }
 
   #[test]
   fn test_no_empty_username() {
     let mut store = UserStore::new();
     store
-      .add_user("".to_owned(), "password".to_owned())
+      .add_user("".to_owned(), "password".to_owned(), Role::Viewer)
       .expect_err("Can't add a user with an empty username");
     assert_eq!(store.num_users(), 0);
   }
@@ -173,33 +682,33 @@ mod test {
       .add_user(
         "bob vance vance refrigeration".to_owned(),
         "bob's cold".to_owned(),
+        Role::Viewer,
       )
       .unwrap();
     store
-      .add_user("joe".to_owned(), "bad password".to_owned())
+      .add_user("joe".to_owned(), "bad password".to_owned(), Role::Viewer)
       .unwrap();
     assert_eq!(store.num_users(), 2);
     assert!(store
-      .find_user("bob vance vance refrigeration")
-      .is_some_and(|user| user
-        .password
-        .as_ref()
-        .is_some_and(|password| password == "bob's cold")));
-    assert!(store.find_user("joe").is_some_and(|user| user
-      .password
-      .as_ref()
-      .is_some_and(|password| password == "bad password")));
+      .verify_password("bob vance vance refrigeration", "bob's cold")
+      .unwrap());
+    assert!(store.verify_password("joe", "bad password").unwrap());
+    assert!(!store.verify_password("joe", "bob's cold").unwrap());
   }
 
   #[test]
   fn test_adjacent_serializations() {
     let mut store1 = UserStore::new();
     store1
-      .add_user("joe".to_owned(), "bad password".to_owned())
+      .add_user("joe".to_owned(), "bad password".to_owned(), Role::Viewer)
       .unwrap();
     let mut store2 = UserStore::new();
-    store2.add_user("a".to_owned(), "a".to_owned()).unwrap();
-    store2.add_user("b".to_owned(), "b".to_owned()).unwrap();
+    store2
+      .add_user("a".to_owned(), "a".to_owned(), Role::Viewer)
+      .unwrap();
+    store2
+      .add_user("b".to_owned(), "b".to_owned(), Role::Viewer)
+      .unwrap();
     let mut encoding = bincode::serialize(&store1).unwrap();
     encoding.extend(bincode::serialize(&store2).unwrap());
 
@@ -209,4 +718,345 @@ mod test {
     assert_eq!(store1.num_users(), 1);
     assert_eq!(store2.num_users(), 2);
   }
+
+  #[test]
+  fn test_role() {
+    let mut store = UserStore::new();
+    store
+      .add_user(
+        "bob".to_owned(),
+        "bob's password".to_owned(),
+        Role::Operator,
+      )
+      .unwrap();
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.role() == Role::Operator));
+  }
+
+  #[test]
+  fn test_serde_role() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Admin)
+      .unwrap();
+    let store = ser_de(&store);
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.role() == Role::Admin));
+  }
+
+  #[test]
+  fn test_roles_ordered_by_privilege() {
+    assert!(Role::Viewer < Role::Operator);
+    assert!(Role::Operator < Role::Admin);
+  }
+
+  #[test]
+  fn test_set_role() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    store.set_role("bob", Role::Admin).unwrap();
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.role() == Role::Admin));
+    store
+      .set_role("joe", Role::Admin)
+      .expect_err("Can't set the role of a nonexistent user");
+  }
+
+  #[test]
+  fn test_remove_user() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    store
+      .add_user("joe".to_owned(), "bad password".to_owned(), Role::Viewer)
+      .unwrap();
+    store.remove_user("bob").unwrap();
+    assert_eq!(store.num_users(), 1);
+    assert!(store.find_user("bob").is_none());
+    assert!(!store.verify_password("bob", "bob's password").unwrap());
+    store
+      .remove_user("bob")
+      .expect_err("Can't remove a user twice");
+  }
+
+  #[test]
+  fn test_password_check_outlived_by_user() {
+    let mut store = UserStore::with_hash_params(cheap_hash_params());
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    let check = store.password_check("bob");
+    store.remove_user("bob").unwrap();
+    store
+      .add_user("bob".to_owned(), "new password".to_owned(), Role::Viewer)
+      .unwrap();
+
+    let verified = check.verify("bob's password").unwrap().unwrap();
+    assert!(!store.complete_password_check(verified));
+    assert!(store.verify_password("bob", "new password").unwrap());
+  }
+
+  fn recover_from(increments: impl IntoIterator<Item = UserMapDelta>) -> UserStore {
+    let mut store = UserStore::new();
+    for increment in increments {
+      store.apply(increment);
+    }
+    store
+  }
+
+  #[test]
+  fn test_no_update_when_empty() {
+    let store = UserStore::new();
+    assert!(!store.has_update());
+  }
+
+  #[test]
+  fn test_add_user_is_update() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    assert!(store.has_update());
+    store.commit();
+    assert!(!store.has_update());
+  }
+
+  #[tokio::test]
+  async fn test_changes_notify_checkpoint_stream() {
+    time::pause();
+    let store = Arc::new(Mutex::new(UserStore::with_hash_params(cheap_hash_params())));
+    let stream = CheckpointStreamOptions {
+      trigger: CheckpointTrigger::Notify {
+        debounce: None,
+        max_latency: None,
+      },
+      ..CheckpointStreamOptions::default()
+    }
+    .build(Vec::new(), store.clone())
+    .await
+    .unwrap();
+    store
+      .lock()
+      .await
+      .set_checkpoint_notifier(stream.notifier());
+    stream.start();
+
+    store
+      .lock()
+      .await
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    time::sleep(Duration::from_millis(1)).await;
+    assert!(!store.lock().await.has_update());
+  }
+
+  #[test]
+  fn test_failed_ops_are_not_updates() {
+    let mut store = UserStore::new();
+    store
+      .add_user("".to_owned(), "password".to_owned(), Role::Viewer)
+      .unwrap_err();
+    store.remove_user("bob").unwrap_err();
+    store.set_role("bob", Role::Admin).unwrap_err();
+    assert!(!store.verify_password("bob", "password").unwrap());
+    assert!(!store.has_update());
+  }
+
+  #[test]
+  fn test_verify_password_is_not_update() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    store.commit();
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+    assert!(!store.has_update());
+  }
+
+  #[test]
+  fn test_rehash_is_update() {
+    let mut store = UserStore::with_hash_params(cheap_hash_params());
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    let increment = store.commit();
+
+    let mut store = recover_from([increment.clone()]);
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+    assert!(store.has_update());
+
+    let store = recover_from([increment, store.commit()]);
+    assert_eq!(
+      store
+        .find_user("bob")
+        .unwrap()
+        .password_hash
+        .as_ref()
+        .unwrap()
+        .params,
+      Some(super::default_hash_params())
+    );
+  }
+
+  #[test]
+  fn test_recover_added_user() {
+    let mut store = UserStore::new();
+    store
+      .add_user(
+        "bob".to_owned(),
+        "bob's password".to_owned(),
+        Role::Operator,
+      )
+      .unwrap();
+
+    let mut store = recover_from([store.commit()]);
+    assert_eq!(store.num_users(), 1);
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.role() == Role::Operator));
+    assert!(!store.has_update());
+  }
+
+  #[test]
+  fn test_recover_sequence() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    store
+      .add_user("joe".to_owned(), "bad password".to_owned(), Role::Viewer)
+      .unwrap();
+    let increment1 = store.commit();
+
+    store.set_role("bob", Role::Admin).unwrap();
+    store.remove_user("joe").unwrap();
+    let increment2 = store.commit();
+
+    let store = recover_from([increment1.clone()]);
+    assert_eq!(store.num_users(), 2);
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.role() == Role::Viewer));
+
+    let store = recover_from([increment1, increment2]);
+    assert_eq!(store.num_users(), 1);
+    assert!(store.find_user("joe").is_none());
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.role() == Role::Admin));
+  }
+
+  #[test]
+  fn test_remove_then_add_in_one_increment() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "old password".to_owned(), Role::Viewer)
+      .unwrap();
+    let increment1 = store.commit();
+
+    store.remove_user("bob").unwrap();
+    store
+      .add_user("bob".to_owned(), "new password".to_owned(), Role::Viewer)
+      .unwrap();
+    let increment2 = store.commit();
+
+    let mut store = recover_from([increment1, increment2]);
+    assert_eq!(store.num_users(), 1);
+    assert!(store.verify_password("bob", "new password").unwrap());
+    assert!(!store.verify_password("bob", "old password").unwrap());
+  }
+
+  #[test]
+  fn test_concatenated_increments_merge() {
+    let mut store = UserStore::new();
+    store
+      .add_user("bob".to_owned(), "bob's password".to_owned(), Role::Viewer)
+      .unwrap();
+    store
+      .add_user("joe".to_owned(), "bad password".to_owned(), Role::Viewer)
+      .unwrap();
+    let mut encoding = ProtobufCodec::encode(&store.commit()).unwrap();
+    store.remove_user("joe").unwrap();
+    encoding.extend(ProtobufCodec::encode(&store.commit()).unwrap());
+
+    let store = recover_from([ProtobufCodec::decode(&encoding).unwrap()]);
+    assert_eq!(store.num_users(), 1);
+    assert!(store.find_user("bob").is_some());
+  }
+
+  #[test]
+  fn test_migration_is_update() {
+    let usermap = UserMap {
+      users: [(
+        "bob".to_owned(),
+        User {
+          password: Some("bob's password".to_owned()),
+          password_hash: None,
+          role: None,
+        },
+      )]
+      .into(),
+    };
+    let encoding = bincode::serialize(&usermap.encode_to_vec()).unwrap();
+
+    let mut store: UserStore = bincode::deserialize(encoding.as_slice()).unwrap();
+    assert!(store.has_update());
+    let mut store = recover_from([store.commit()]);
+    assert!(store
+      .find_user("bob")
+      .is_some_and(|user| user.password.is_none()));
+    assert!(store.verify_password("bob", "bob's password").unwrap());
+  }
+
+  #[test]
+  fn test_session_authenticates() {
+    let mut sessions = SessionStore::new();
+    let token = sessions.create_session("bob".to_owned());
+    assert_eq!(sessions.authenticate(&token), Some("bob"));
+  }
+
+  #[test]
+  fn test_session_tokens_unique() {
+    let mut sessions = SessionStore::new();
+    let token1 = sessions.create_session("bob".to_owned());
+    let token2 = sessions.create_session("bob".to_owned());
+    assert_ne!(token1, token2);
+    assert_eq!(sessions.authenticate(&token1), Some("bob"));
+    assert_eq!(sessions.authenticate(&token2), Some("bob"));
+  }
+
+  #[test]
+  fn test_unknown_session() {
+    let mut sessions = SessionStore::new();
+    sessions.create_session("bob".to_owned());
+    assert_eq!(sessions.authenticate("not a token"), None);
+    assert_eq!(sessions.authenticate(""), None);
+  }
+
+  #[test]
+  fn test_end_session() {
+    let mut sessions = SessionStore::new();
+    let token = sessions.create_session("bob".to_owned());
+    assert!(sessions.end_session(&token));
+    assert_eq!(sessions.authenticate(&token), None);
+    assert!(!sessions.end_session(&token));
+  }
+
+  #[tokio::test]
+  async fn test_session_expires() {
+    time::pause();
+    let mut sessions = SessionStore::with_ttl(Duration::from_secs(60));
+    let token = sessions.create_session("bob".to_owned());
+    time::sleep(Duration::from_secs(59)).await;
+    assert_eq!(sessions.authenticate(&token), Some("bob"));
+    time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(sessions.authenticate(&token), None);
+  }
 }
@@ -0,0 +1,631 @@
+//! Inspects and repairs checkpoint files.
+
+use std::{
+  collections::BTreeSet,
+  fmt::Write,
+  path::{Path, PathBuf},
+  process::ExitCode,
+  sync::Arc,
+};
+
+use clap::{Parser, Subcommand};
+use pc_landing_page::{
+  auth::UserStore,
+  checkpoint_codec::{BincodeCodec, Codec, ProtobufCodec},
+  checkpoint_crypto::{CheckpointKey, CryptoError, Keyring, RecordChain},
+  checkpoint_multiplex::{untag_record, CheckpointSources},
+  checkpoint_record::{RecordError, RecordReader, RECORD_HEADER_LEN},
+  checkpoint_stream::{CheckpointStreamOptions, IncrementalUpdate, RecordKind},
+  error::{McError, ThreadSafeError},
+  proto::{User, UserMap, UserMapDelta},
+};
+use serde_json::{json, Map, Value};
+use tokio::{
+  fs::{File, OpenOptions},
+  io::BufReader,
+  sync::Mutex,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+  #[command(subcommand)]
+  command: Command,
+
+  /// The key file the checkpoint is encrypted with.
+  #[arg(long, global = true)]
+  key_file: Option<PathBuf>,
+
+  /// Key files older records may be encrypted with.
+  #[arg(long, global = true)]
+  old_key_file: Vec<PathBuf>,
+
+  /// Accepts plaintext records although key files are given, e.g. to compact
+  /// a checkpoint written before encryption was enabled into an encrypted
+  /// snapshot.
+  #[arg(long, global = true)]
+  migrate_plaintext: bool,
+
+  /// The source users records are tagged with in a multiplexed checkpoint.
+  /// Records of other sources are skipped by `dump` and `verify`, and
+  /// `compact` refuses checkpoints holding any.
+  #[arg(long, global = true, default_value = "users")]
+  users_source: String,
+}
+
+impl Args {
+  async fn keyring(&self) -> Result<Keyring, Box<dyn ThreadSafeError>> {
+    let mut keyring = Keyring::new();
+    for path in self.key_file.iter().chain(&self.old_key_file) {
+      keyring.add(CheckpointKey::from_file(path).await?);
+    }
+    if self.migrate_plaintext {
+      keyring.accept_plaintext();
+    }
+    Ok(keyring)
+  }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Lists every record with its offset, size and payload checksum.
+  List { file: PathBuf },
+  /// Prints each record of a users checkpoint as a line of JSON.
+  Dump { file: PathBuf },
+  /// Checks that every record of a users checkpoint is intact and decodes.
+  Verify { file: PathBuf },
+  /// Truncates the file after its last valid record.
+  Truncate { file: PathBuf },
+  /// Replaces a users checkpoint with a single snapshot of its users,
+  /// encrypted with `--key-file` if given. A multiplexed checkpoint stays
+  /// multiplexed.
+  Compact { file: PathBuf },
+}
+
+/// A record read intact from a checkpoint file.
+struct ScannedRecord {
+  offset: u64,
+  payload: Vec<u8>,
+}
+
+impl ScannedRecord {
+  fn len(&self) -> usize {
+    RECORD_HEADER_LEN + self.payload.len()
+  }
+}
+
+/// The records of a checkpoint file, up to the first invalid one.
+struct Scan {
+  records: Vec<ScannedRecord>,
+  /// The error which stopped the scan, if it didn't reach the end of the file.
+  error: Option<RecordError>,
+  /// The offset just past the last valid record.
+  valid_len: u64,
+  file_len: u64,
+}
+
+async fn scan(path: &Path) -> Result<Scan, Box<dyn ThreadSafeError>> {
+  let file = File::open(path).await?;
+  let file_len = file.metadata().await?.len();
+  let mut reader = RecordReader::new(BufReader::new(file));
+
+  let mut records = Vec::new();
+  let error = loop {
+    let offset = reader.offset();
+    match reader.read_record().await {
+      Ok(Some(payload)) => records.push(ScannedRecord { offset, payload }),
+      Ok(None) => break None,
+      Err(RecordError::Io(err)) => return Err(err.into()),
+      Err(err) => break Some(err),
+    }
+  };
+
+  Ok(Scan {
+    records,
+    error,
+    valid_len: reader.offset(),
+    file_len,
+  })
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().fold(String::new(), |mut hex, byte| {
+    let _ = write!(hex, "{byte:02x}");
+    hex
+  })
+}
+
+fn user_json(user: &User) -> Value {
+  let mut json = Map::new();
+  json.insert("role".to_owned(), json!(format!("{:?}", user.role())));
+  if user.password.is_some() {
+    json.insert("plaintext_password".to_owned(), json!(true));
+  }
+  if let Some(hash) = &user.password_hash {
+    let params = hash.params.clone().unwrap_or_default();
+    json.insert(
+      "password_hash".to_owned(),
+      json!({
+        "memory_cost_kib": params.memory_cost_kib,
+        "iterations": params.iterations,
+        "parallelism": params.parallelism,
+        "salt": hex(hash.salt()),
+        "hash": hex(hash.hash()),
+      }),
+    );
+  }
+  Value::Object(json)
+}
+
+/// Decodes a record of a users checkpoint, decrypting it with `keyring` at
+/// `chain`, untagging it if the checkpoint is multiplexed, and migrating it to
+/// the current schema version first. Returns `None` if the record is tagged
+/// with a source other than `users_source`. Removed users in increments are
+/// `null`.
+fn users_record_json(
+  keyring: &Keyring,
+  chain: &mut RecordChain,
+  users_source: &str,
+  payload: Vec<u8>,
+) -> Result<Option<Value>, Box<dyn ThreadSafeError>> {
+  let (payload, _) = keyring.decrypt(payload, chain)?;
+  let (source, payload) = untag_record(payload)?;
+  if source.is_some_and(|source| source != users_source) {
+    return Ok(None);
+  }
+  let (kind, schema_version, body) = RecordKind::decode(payload)?;
+  let body = UserStore::migrations().migrate(kind, schema_version, body)?;
+  let json = match kind {
+    RecordKind::Snapshot => {
+      // `UserStore` serializes as the bytes of its `UserMap`.
+      let usermap_encoding: Vec<u8> = BincodeCodec::decode(&body)?;
+      let usermap: UserMap = ProtobufCodec::decode(&usermap_encoding)?;
+      let users: Map<_, _> = usermap
+        .users
+        .iter()
+        .map(|(username, user)| (username.clone(), user_json(user)))
+        .collect();
+      json!({ "snapshot": users })
+    }
+    RecordKind::Increment => {
+      let delta: UserMapDelta = ProtobufCodec::decode(&body)?;
+      let changes: Map<_, _> = delta
+        .changes
+        .iter()
+        .map(|(username, change)| {
+          let user = change.user.as_ref().map_or(Value::Null, user_json);
+          (username.clone(), user)
+        })
+        .collect();
+      json!({ "increment": changes })
+    }
+  };
+  Ok(Some(json))
+}
+
+fn print_scan_error(scan: &Scan) {
+  if let Some(err) = &scan.error {
+    println!(
+      "{err}; {} bytes after offset {} are unreadable",
+      scan.file_len - scan.valid_len,
+      scan.valid_len
+    );
+  }
+}
+
+/// Describes a record's kind, along with the source it's tagged with and the
+/// key it's encrypted with, and its schema version. The record is decrypted
+/// at `chain`.
+fn describe_record(
+  keyring: &Keyring,
+  chain: &mut RecordChain,
+  payload: Vec<u8>,
+) -> (String, String) {
+  let (payload, key_id) = match keyring.decrypt(payload, chain) {
+    Ok(decrypted) => decrypted,
+    Err(err @ CryptoError::Unencrypted) => return (format!("plaintext ({err})"), "?".to_owned()),
+    Err(err) => return (format!("encrypted ({err})"), "?".to_owned()),
+  };
+  let (source, payload) = match untag_record(payload) {
+    Ok(untagged) => untagged,
+    Err(err) => return (format!("unknown ({err})"), "?".to_owned()),
+  };
+  let (kind, schema_version) = match RecordKind::decode(payload) {
+    Ok((RecordKind::Snapshot, schema_version, _)) => ("snapshot", schema_version.to_string()),
+    Ok((RecordKind::Increment, schema_version, _)) => ("increment", schema_version.to_string()),
+    Err(_) => ("unknown", "?".to_owned()),
+  };
+
+  let mut description = match source {
+    Some(source) => format!("{source}: {kind}"),
+    None => kind.to_owned(),
+  };
+  if let Some(key_id) = key_id {
+    let _ = write!(description, " (key {key_id})");
+  }
+  (description, schema_version)
+}
+
+async fn list(path: &Path, keyring: &Keyring) -> Result<ExitCode, Box<dyn ThreadSafeError>> {
+  let scan = scan(path).await?;
+  println!(
+    "{:>10} {:>10} {:>8} {:>7}  kind",
+    "offset", "size", "crc32c", "version"
+  );
+  let mut chain = RecordChain::new();
+  for record in &scan.records {
+    let (kind, schema_version) = describe_record(keyring, &mut chain, record.payload.clone());
+    println!(
+      "{:>10} {:>10} {:08x} {schema_version:>7}  {kind}",
+      record.offset,
+      record.len(),
+      crc32c::crc32c(&record.payload)
+    );
+  }
+  print_scan_error(&scan);
+  Ok(ExitCode::SUCCESS)
+}
+
+async fn dump(
+  path: &Path,
+  keyring: &Keyring,
+  users_source: &str,
+) -> Result<ExitCode, Box<dyn ThreadSafeError>> {
+  let scan = scan(path).await?;
+  let mut chain = RecordChain::new();
+  for record in &scan.records {
+    let json = match users_record_json(keyring, &mut chain, users_source, record.payload.clone()) {
+      Ok(Some(json)) => json,
+      Ok(None) => continue,
+      Err(err) => json!({ "error": err.to_string() }),
+    };
+    println!("{}", json!({ "offset": record.offset, "record": json }));
+  }
+  print_scan_error(&scan);
+  Ok(ExitCode::SUCCESS)
+}
+
+async fn verify(
+  path: &Path,
+  keyring: &Keyring,
+  users_source: &str,
+) -> Result<ExitCode, Box<dyn ThreadSafeError>> {
+  let scan = scan(path).await?;
+  let mut ok = scan.error.is_none();
+  let mut chain = RecordChain::new();
+  for record in &scan.records {
+    if let Err(err) = users_record_json(keyring, &mut chain, users_source, record.payload.clone()) {
+      println!("Record at offset {} doesn't decode: {err}", record.offset);
+      ok = false;
+    }
+  }
+  print_scan_error(&scan);
+
+  if ok {
+    println!(
+      "{} records, {} bytes, all valid",
+      scan.records.len(),
+      scan.file_len
+    );
+    Ok(ExitCode::SUCCESS)
+  } else {
+    Ok(ExitCode::FAILURE)
+  }
+}
+
+async fn truncate(path: &Path) -> Result<ExitCode, Box<dyn ThreadSafeError>> {
+  let scan = scan(path).await?;
+  if scan.valid_len == scan.file_len {
+    println!("Nothing to truncate");
+    return Ok(ExitCode::SUCCESS);
+  }
+
+  print_scan_error(&scan);
+  let file = OpenOptions::new().write(true).open(path).await?;
+  file.set_len(scan.valid_len).await?;
+  file.sync_all().await?;
+  println!(
+    "Truncated {} bytes, keeping {} records",
+    scan.file_len - scan.valid_len,
+    scan.records.len()
+  );
+  Ok(ExitCode::SUCCESS)
+}
+
+/// The sources the records of a checkpoint are tagged with, which is empty
+/// unless the checkpoint is multiplexed.
+async fn record_sources(
+  path: &Path,
+  keyring: &Keyring,
+) -> Result<BTreeSet<String>, Box<dyn ThreadSafeError>> {
+  let mut sources = BTreeSet::new();
+  let mut chain = RecordChain::new();
+  for record in scan(path).await?.records {
+    let (payload, _) = keyring.decrypt(record.payload, &mut chain)?;
+    if let (Some(source), _) = untag_record(payload)? {
+      sources.insert(source);
+    }
+  }
+  Ok(sources)
+}
+
+async fn compact(
+  path: &Path,
+  keyring: &Keyring,
+  users_source: &str,
+  options: CheckpointStreamOptions,
+) -> Result<ExitCode, Box<dyn ThreadSafeError>> {
+  let users = Arc::new(Mutex::new(UserStore::new()));
+  let sources = record_sources(path, keyring).await?;
+  let mut recovery = if sources.is_empty() {
+    options.recover(path, users).await?
+  } else {
+    // Only users can be decoded, so compacting would lose any other source.
+    if let Some(source) = sources.iter().find(|source| *source != users_source) {
+      return Err(
+        McError::InvalidOp(format!(
+          "Can't compact {}, which holds records of source {source} besides {users_source}",
+          path.display()
+        ))
+        .into(),
+      );
+    }
+    let sources = CheckpointSources::new().with_source(users_source, users, |_, _| {});
+    options.recover_multiplexed(path, sources).await?
+  };
+  recovery.stream.compact().await?;
+
+  let file_len = File::open(path).await?.metadata().await?.len();
+  println!(
+    "Compacted {} records into a {file_len} byte snapshot",
+    recovery.records_applied
+  );
+  Ok(ExitCode::SUCCESS)
+}
+
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn ThreadSafeError>> {
+  let args = Args::parse();
+  let keyring = args.keyring().await?;
+  match args.command {
+    Command::List { file } => list(&file, &keyring).await,
+    Command::Dump { file } => dump(&file, &keyring, &args.users_source).await,
+    Command::Verify { file } => verify(&file, &keyring, &args.users_source).await,
+    Command::Truncate { file } => truncate(&file).await,
+    Command::Compact { file } => {
+      let options = CheckpointStreamOptions {
+        key_file: args.key_file,
+        old_key_files: args.old_key_file,
+        migrate_plaintext: args.migrate_plaintext,
+        ..CheckpointStreamOptions::default()
+      };
+      compact(&file, &keyring, &args.users_source, options).await
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use pc_landing_page::{
+    checkpoint_codec::{Codec, ProtobufCodec},
+    checkpoint_crypto::{CheckpointKey, Keyring, RecordChain},
+    checkpoint_multiplex::tag_record,
+    checkpoint_record::{encode_record, RECORD_HEADER_LEN},
+    checkpoint_stream::{CheckpointStreamOptions, RecordKind},
+    proto::{User, UserChange, UserMapDelta},
+  };
+  use serde_json::json;
+  use tempfile::NamedTempFile;
+
+  use super::{compact, describe_record, scan, users_record_json};
+
+  fn increment_record(changes: &[(&str, Option<User>)]) -> Vec<u8> {
+    let delta = UserMapDelta {
+      changes: changes
+        .iter()
+        .map(|(username, user)| (username.to_string(), UserChange { user: user.clone() }))
+        .collect(),
+    };
+    encode_record(&RecordKind::Increment.encode(1, &ProtobufCodec::encode(&delta).unwrap()))
+  }
+
+  #[tokio::test]
+  async fn test_scan_stops_at_torn_record() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = increment_record(&[("bob", Some(User::default()))]);
+    let valid_len = checkpoint.len() as u64;
+    checkpoint.extend(&increment_record(&[("joe", None)])[..5]);
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    let scan = scan(file.path()).await.unwrap();
+    assert_eq!(scan.records.len(), 1);
+    assert_eq!(scan.records[0].offset, 0);
+    assert!(scan.error.is_some());
+    assert_eq!(scan.valid_len, valid_len);
+    assert_eq!(scan.file_len, valid_len + 5);
+  }
+
+  #[test]
+  fn test_increment_json() {
+    let record = increment_record(&[("bob", Some(User::default())), ("joe", None)]);
+    let payload = record[RECORD_HEADER_LEN..].to_vec();
+    assert_eq!(
+      users_record_json(&Keyring::new(), &mut RecordChain::new(), "users", payload).unwrap(),
+      Some(json!({ "increment": { "bob": { "role": "Viewer" }, "joe": null } }))
+    );
+  }
+
+  #[test]
+  fn test_malformed_increment() {
+    let payload = RecordKind::Increment.encode(1, &[0xff]);
+    assert!(users_record_json(&Keyring::new(), &mut RecordChain::new(), "users", payload).is_err());
+  }
+
+  #[test]
+  fn test_describe_record() {
+    let key = CheckpointKey::new(1, &[7; 32]);
+    let mut keyring = Keyring::new();
+    keyring.add(key.clone());
+
+    let payload = RecordKind::Snapshot.encode(2, &[]);
+    let (kind, schema_version) =
+      describe_record(&keyring, &mut RecordChain::new(), payload.clone());
+    assert!(kind.starts_with("plaintext"), "{kind}");
+    assert_eq!(schema_version, "?");
+    assert_eq!(
+      describe_record(&Keyring::new(), &mut RecordChain::new(), payload.clone()),
+      ("snapshot".to_owned(), "2".to_owned())
+    );
+    assert_eq!(
+      describe_record(
+        &keyring,
+        &mut RecordChain::new(),
+        key.encrypt(&tag_record("users", &payload), &mut RecordChain::new())
+      ),
+      ("users: snapshot (key 1)".to_owned(), "2".to_owned())
+    );
+    assert_eq!(
+      describe_record(
+        &Keyring::new(),
+        &mut RecordChain::new(),
+        key.encrypt(&payload, &mut RecordChain::new())
+      )
+      .1,
+      "?"
+    );
+  }
+
+  #[test]
+  fn test_encrypted_increment_json() {
+    let key = CheckpointKey::new(1, &[7; 32]);
+    let record = increment_record(&[("joe", None)]);
+    let payload = key.encrypt(&record[RECORD_HEADER_LEN..], &mut RecordChain::new());
+    assert!(users_record_json(
+      &Keyring::new(),
+      &mut RecordChain::new(),
+      "users",
+      payload.clone()
+    )
+    .is_err());
+
+    let mut keyring = Keyring::new();
+    keyring.add(key);
+    assert_eq!(
+      users_record_json(&keyring, &mut RecordChain::new(), "users", payload).unwrap(),
+      Some(json!({ "increment": { "joe": null } }))
+    );
+  }
+
+  #[tokio::test]
+  async fn test_multiplexed_users_json() {
+    let key = CheckpointKey::new(1, &[7; 32]);
+    let mut keyring = Keyring::new();
+    keyring.add(key.clone());
+    let delta = UserMapDelta {
+      changes: [("bob".to_owned(), UserChange { user: None })].into(),
+    };
+    let users_payload = RecordKind::Increment.encode(1, &ProtobufCodec::encode(&delta).unwrap());
+    let file = NamedTempFile::new().unwrap();
+    let mut chain = RecordChain::new();
+    std::fs::write(
+      file.path(),
+      [
+        encode_record(&key.encrypt(&tag_record("users", &users_payload), &mut chain)),
+        encode_record(&key.encrypt(&tag_record("servers", &[0xff]), &mut chain)),
+      ]
+      .concat(),
+    )
+    .unwrap();
+
+    let scan = scan(file.path()).await.unwrap();
+    let mut chain = RecordChain::new();
+    let records: Vec<_> = scan
+      .records
+      .into_iter()
+      .map(|record| users_record_json(&keyring, &mut chain, "users", record.payload).unwrap())
+      .collect();
+    assert_eq!(
+      records,
+      vec![Some(json!({ "increment": { "bob": null } })), None]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_compact_multiplexed() {
+    let delta = UserMapDelta {
+      changes: [(
+        "bob".to_owned(),
+        UserChange {
+          user: Some(User::default()),
+        },
+      )]
+      .into(),
+    };
+    let users_payload = RecordKind::Increment.encode(1, &ProtobufCodec::encode(&delta).unwrap());
+    let file = NamedTempFile::new().unwrap();
+    std::fs::write(
+      file.path(),
+      [
+        encode_record(&tag_record("users", &users_payload)),
+        encode_record(&tag_record("users", &users_payload)),
+      ]
+      .concat(),
+    )
+    .unwrap();
+
+    let keyring = Keyring::new();
+    compact(
+      file.path(),
+      &keyring,
+      "users",
+      CheckpointStreamOptions::default(),
+    )
+    .await
+    .unwrap();
+    let records = scan(file.path()).await.unwrap().records;
+    assert_eq!(records.len(), 1);
+    assert_eq!(
+      users_record_json(
+        &keyring,
+        &mut RecordChain::new(),
+        "users",
+        records[0].payload.clone()
+      )
+      .unwrap(),
+      Some(json!({ "snapshot": { "bob": { "role": "Viewer" } } }))
+    );
+    assert_eq!(
+      describe_record(
+        &keyring,
+        &mut RecordChain::new(),
+        records[0].payload.clone()
+      )
+      .0,
+      "users: snapshot"
+    );
+  }
+
+  #[tokio::test]
+  async fn test_compact_refuses_other_sources() {
+    let users_payload =
+      RecordKind::Increment.encode(1, &ProtobufCodec::encode(&UserMapDelta::default()).unwrap());
+    let checkpoint = [
+      encode_record(&tag_record("users", &users_payload)),
+      encode_record(&tag_record("servers", &[0xff])),
+    ]
+    .concat();
+    let file = NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    let keyring = Keyring::new();
+    assert!(compact(
+      file.path(),
+      &keyring,
+      "users",
+      CheckpointStreamOptions::default()
+    )
+    .await
+    .is_err());
+    assert_eq!(std::fs::read(file.path()).unwrap(), checkpoint);
+  }
+}
@@ -0,0 +1,95 @@
+use std::{error, fmt::Display};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(Debug)]
+pub enum CodecError {
+  Bincode(bincode::Error),
+  Protobuf(prost::DecodeError),
+}
+
+impl Display for CodecError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CodecError::Bincode(err) => write!(f, "Malformed bincode encoding: {err}"),
+      CodecError::Protobuf(err) => write!(f, "Malformed protobuf encoding: {err}"),
+    }
+  }
+}
+
+impl error::Error for CodecError {}
+
+/// Encodes values of type `T` into checkpoint records.
+pub trait Codec<T> {
+  fn encode(value: &T) -> Result<Vec<u8>, CodecError>;
+
+  fn decode(encoding: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Encodes any serde type with bincode.
+pub struct BincodeCodec;
+
+impl<T> Codec<T> for BincodeCodec
+where
+  T: Serialize + DeserializeOwned,
+{
+  fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+    bincode::serialize(value).map_err(CodecError::Bincode)
+  }
+
+  fn decode(encoding: &[u8]) -> Result<T, CodecError> {
+    bincode::deserialize(encoding).map_err(CodecError::Bincode)
+  }
+}
+
+/// Encodes proto messages in their wire format.
+pub struct ProtobufCodec;
+
+impl<T> Codec<T> for ProtobufCodec
+where
+  T: prost::Message + Default,
+{
+  fn encode(value: &T) -> Result<Vec<u8>, CodecError> {
+    Ok(value.encode_to_vec())
+  }
+
+  fn decode(encoding: &[u8]) -> Result<T, CodecError> {
+    T::decode(encoding).map_err(CodecError::Protobuf)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::proto::{UserChange, UserMapDelta};
+
+  use super::{BincodeCodec, Codec, CodecError, ProtobufCodec};
+
+  #[test]
+  fn test_bincode_round_trip() {
+    let value = vec!["a".to_owned(), "b".to_owned()];
+    let encoding = BincodeCodec::encode(&value).unwrap();
+    let decoded: Vec<String> = BincodeCodec::decode(&encoding).unwrap();
+    assert_eq!(decoded, value);
+  }
+
+  #[test]
+  fn test_protobuf_round_trip() {
+    let delta = UserMapDelta {
+      changes: [("bob".to_owned(), UserChange { user: None })].into(),
+    };
+    let encoding = ProtobufCodec::encode(&delta).unwrap();
+    assert_eq!(ProtobufCodec::decode(&encoding).ok(), Some(delta));
+  }
+
+  #[test]
+  fn test_malformed_bincode() {
+    let result: Result<Vec<String>, _> = BincodeCodec::decode(&[1, 2]);
+    assert!(matches!(result, Err(CodecError::Bincode(_))));
+  }
+
+  #[test]
+  fn test_malformed_protobuf() {
+    let result: Result<UserMapDelta, _> = ProtobufCodec::decode(&[0xff]);
+    assert!(matches!(result, Err(CodecError::Protobuf(_))));
+  }
+}
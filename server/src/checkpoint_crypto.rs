@@ -0,0 +1,440 @@
+//! Encryption of checkpoint records at rest.
+//!
+//! An encrypted record's payload is `ENCRYPTED_RECORD_TAG`, the little-endian
+//! `u32` ID of the key it was encrypted with, a random 24-byte nonce, and the
+//! XChaCha20-Poly1305 encryption of the plaintext payload. The tag and key ID
+//! are authenticated along with the ciphertext, as is the authentication tag
+//! of the encrypted record before it, if any. Chaining records this way binds
+//! each to its place in the checkpoint: a record that's dropped, reordered, or
+//! replayed from an earlier checkpoint makes the record after it fail to
+//! decrypt. What can't be detected is losing the end of a checkpoint, i.e.
+//! dropping its last records or replacing the whole file with an older one.
+//!
+//! A key file holds a key ID and a 256-bit key in hex, separated by
+//! whitespace, e.g. `3 0123...cdef`. Keys are rotated by giving the stream a
+//! key file with a new ID, along with the old key file so existing records
+//! can still be read.
+//!
+//! Once keys are given, plaintext records are rejected, since they aren't
+//! authenticated and anyone who can write the checkpoint file could inject
+//! them. A checkpoint written before encryption was enabled is migrated by
+//! explicitly accepting plaintext records once.
+
+use std::{collections::HashMap, error, fmt::Display, path::Path};
+
+use chacha20poly1305::{
+  aead::{Aead, Payload},
+  KeyInit, XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+
+/// The first byte of an encrypted record's payload. This doesn't collide
+/// with any `RecordKind`, so encrypted and plaintext records can be told
+/// apart.
+pub const ENCRYPTED_RECORD_TAG: u8 = 0xec;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+/// The length of the authentication tag ending an encrypted payload.
+const TAG_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum CryptoError {
+  /// The key file at the path couldn't be parsed.
+  InvalidKeyFile(String),
+  /// A record is encrypted with a key which wasn't given.
+  MissingKey { key_id: u32 },
+  /// A record doesn't decrypt with the key that has its ID, so either the key
+  /// is wrong or the record was tampered with.
+  WrongKey { key_id: u32 },
+  /// An encrypted record is too short to hold its header.
+  Truncated,
+  /// A record isn't encrypted, though keys were given and plaintext records
+  /// weren't accepted.
+  Unencrypted,
+}
+
+impl Display for CryptoError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CryptoError::InvalidKeyFile(msg) => write!(f, "Invalid checkpoint key file: {msg}"),
+      CryptoError::MissingKey { key_id } => write!(
+        f,
+        "Checkpoint record is encrypted with key {key_id}, but no key file for it was given"
+      ),
+      CryptoError::WrongKey { key_id } => write!(
+        f,
+        "Checkpoint record failed to decrypt with key {key_id}; the key file is wrong or the \
+         record was tampered with"
+      ),
+      CryptoError::Truncated => write!(f, "Encrypted checkpoint record is truncated"),
+      CryptoError::Unencrypted => write!(
+        f,
+        "Checkpoint record isn't encrypted, though a key file was given; it may have been \
+         injected, or the checkpoint predates encryption and needs migrating"
+      ),
+    }
+  }
+}
+
+impl error::Error for CryptoError {}
+
+/// A position in a checkpoint, which the next encrypted record is bound to.
+/// A chain starts at the beginning of the checkpoint, and is advanced past
+/// each record in order as they're encrypted or decrypted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecordChain {
+  /// The authentication tag of the previous record, unless there's none or
+  /// it wasn't encrypted.
+  prev_tag: Option<[u8; TAG_LEN]>,
+}
+
+impl RecordChain {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Moves past the record whose (possibly encrypted) payload is `payload`.
+  fn advance(&mut self, payload: &[u8]) {
+    self.prev_tag =
+      if payload.first() == Some(&ENCRYPTED_RECORD_TAG) && payload.len() >= HEADER_LEN + TAG_LEN {
+        Some(payload[payload.len() - TAG_LEN..].try_into().unwrap())
+      } else {
+        None
+      };
+  }
+
+  /// The data authenticated along with a record whose payload starts with
+  /// `header`.
+  fn aad(&self, header: &[u8]) -> Vec<u8> {
+    let mut aad = header[..5].to_vec();
+    aad.extend(self.prev_tag.iter().flatten());
+    aad
+  }
+}
+
+/// A key records are encrypted with.
+#[derive(Clone)]
+pub struct CheckpointKey {
+  id: u32,
+  cipher: XChaCha20Poly1305,
+}
+
+impl CheckpointKey {
+  pub fn new(id: u32, key: &[u8; KEY_LEN]) -> Self {
+    Self {
+      id,
+      cipher: XChaCha20Poly1305::new(key.into()),
+    }
+  }
+
+  /// Parses the contents of a key file.
+  pub fn parse(contents: &str) -> Result<Self, CryptoError> {
+    Self::parse_key(contents).map_err(|msg| CryptoError::InvalidKeyFile(msg.to_owned()))
+  }
+
+  pub async fn from_file(path: &Path) -> Result<Self, CryptoError> {
+    let invalid =
+      |msg: &dyn Display| CryptoError::InvalidKeyFile(format!("{}: {msg}", path.display()));
+    let contents = tokio::fs::read_to_string(path)
+      .await
+      .map_err(|err| invalid(&err))?;
+    Self::parse_key(&contents).map_err(|msg| invalid(&msg))
+  }
+
+  fn parse_key(contents: &str) -> Result<Self, &'static str> {
+    let mut fields = contents.split_whitespace();
+    let id = fields
+      .next()
+      .ok_or("Missing key ID")?
+      .parse()
+      .map_err(|_| "Key ID is not a number")?;
+    let hex = fields.next().ok_or("Missing key")?;
+    if fields.next().is_some() {
+      return Err("Unexpected text after the key");
+    }
+    if hex.len() != 2 * KEY_LEN || !hex.is_ascii() {
+      return Err("Key is not 64 hex digits");
+    }
+
+    let mut key = [0; KEY_LEN];
+    for (i, byte) in key.iter_mut().enumerate() {
+      *byte =
+        u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| "Key is not 64 hex digits")?;
+    }
+    Ok(Self::new(id, &key))
+  }
+
+  pub fn id(&self) -> u32 {
+    self.id
+  }
+
+  /// Encrypts the payload of the record at `chain`, advancing it past the
+  /// record.
+  pub fn encrypt(&self, payload: &[u8], chain: &mut RecordChain) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + payload.len() + 16);
+    sealed.push(ENCRYPTED_RECORD_TAG);
+    sealed.extend(self.id.to_le_bytes());
+    sealed.extend(nonce);
+    let ciphertext = self
+      .cipher
+      .encrypt(
+        XNonce::from_slice(&nonce),
+        Payload {
+          msg: payload,
+          aad: &chain.aad(&sealed),
+        },
+      )
+      .expect("XChaCha20-Poly1305 encryption is infallible for record-sized payloads");
+    sealed.extend(ciphertext);
+    chain.advance(&sealed);
+    sealed
+  }
+
+  fn decrypt(&self, sealed: &[u8], chain: &RecordChain) -> Result<Vec<u8>, CryptoError> {
+    self
+      .cipher
+      .decrypt(
+        XNonce::from_slice(&sealed[5..HEADER_LEN]),
+        Payload {
+          msg: &sealed[HEADER_LEN..],
+          aad: &chain.aad(sealed),
+        },
+      )
+      .map_err(|_| CryptoError::WrongKey { key_id: self.id })
+  }
+}
+
+/// The keys records may be encrypted with.
+#[derive(Clone, Default)]
+pub struct Keyring {
+  keys: HashMap<u32, CheckpointKey>,
+  /// Whether plaintext records are accepted even though keys were given.
+  accept_plaintext: bool,
+}
+
+impl Keyring {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn add(&mut self, key: CheckpointKey) {
+    self.keys.insert(key.id, key);
+  }
+
+  /// Accepts plaintext payloads even if the keyring holds keys, e.g. to
+  /// migrate a checkpoint written before encryption was enabled.
+  pub fn accept_plaintext(&mut self) {
+    self.accept_plaintext = true;
+  }
+
+  /// Decrypts `payload`, of the record at `chain`, if it is encrypted,
+  /// returning the plaintext payload and the ID of the key it was encrypted
+  /// with. Plaintext payloads are returned unchanged if the keyring is empty
+  /// or accepts plaintext, and rejected otherwise. `chain` is advanced past
+  /// the record even if it fails to decrypt.
+  pub fn decrypt(
+    &self,
+    payload: Vec<u8>,
+    chain: &mut RecordChain,
+  ) -> Result<(Vec<u8>, Option<u32>), CryptoError> {
+    let record_chain = chain.clone();
+    chain.advance(&payload);
+    if payload.first() != Some(&ENCRYPTED_RECORD_TAG) {
+      if !self.keys.is_empty() && !self.accept_plaintext {
+        return Err(CryptoError::Unencrypted);
+      }
+      return Ok((payload, None));
+    }
+    if payload.len() < HEADER_LEN {
+      return Err(CryptoError::Truncated);
+    }
+
+    let key_id = u32::from_le_bytes(payload[1..5].try_into().unwrap());
+    let key = self
+      .keys
+      .get(&key_id)
+      .ok_or(CryptoError::MissingKey { key_id })?;
+    Ok((key.decrypt(&payload, &record_chain)?, Some(key_id)))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{CheckpointKey, CryptoError, Keyring, RecordChain, ENCRYPTED_RECORD_TAG};
+
+  const KEY_FILE: &str = "7 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n";
+
+  fn keyring(keys: &[&CheckpointKey]) -> Keyring {
+    let mut keyring = Keyring::new();
+    for key in keys {
+      keyring.add((*key).clone());
+    }
+    keyring
+  }
+
+  #[test]
+  fn test_parse_key_file() {
+    let key = CheckpointKey::parse(KEY_FILE).unwrap();
+    assert_eq!(key.id(), 7);
+    let expected = CheckpointKey::new(7, &std::array::from_fn(|i| i as u8));
+    let sealed = expected.encrypt(b"abc", &mut RecordChain::new());
+    assert_eq!(
+      keyring(&[&key])
+        .decrypt(sealed, &mut RecordChain::new())
+        .unwrap(),
+      (b"abc".to_vec(), Some(7))
+    );
+  }
+
+  #[test]
+  fn test_invalid_key_files() {
+    for contents in [
+      "",
+      "7",
+      "x 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+      "7 0001",
+      "7 zz0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+      "7 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f 8",
+    ] {
+      assert!(
+        matches!(
+          CheckpointKey::parse(contents),
+          Err(CryptoError::InvalidKeyFile(_))
+        ),
+        "{contents:?} should be invalid"
+      );
+    }
+  }
+
+  #[test]
+  fn test_round_trip() {
+    let key = CheckpointKey::new(1, &[3; 32]);
+    let sealed = key.encrypt(b"secret", &mut RecordChain::new());
+    assert_eq!(sealed[0], ENCRYPTED_RECORD_TAG);
+    assert!(!sealed.windows(6).any(|window| window == b"secret"));
+    assert_eq!(
+      keyring(&[&key])
+        .decrypt(sealed, &mut RecordChain::new())
+        .unwrap(),
+      (b"secret".to_vec(), Some(1))
+    );
+  }
+
+  #[test]
+  fn test_nonces_differ() {
+    let key = CheckpointKey::new(1, &[3; 32]);
+    assert_ne!(
+      key.encrypt(b"secret", &mut RecordChain::new()),
+      key.encrypt(b"secret", &mut RecordChain::new())
+    );
+  }
+
+  #[test]
+  fn test_plaintext_passes_through() {
+    assert_eq!(
+      Keyring::new()
+        .decrypt(vec![1, 2, 3], &mut RecordChain::new())
+        .unwrap(),
+      (vec![1, 2, 3], None)
+    );
+  }
+
+  #[test]
+  fn test_plaintext_rejected_with_keys() {
+    let mut keyring = keyring(&[&CheckpointKey::new(1, &[3; 32])]);
+    assert!(matches!(
+      keyring.decrypt(vec![1, 2, 3], &mut RecordChain::new()),
+      Err(CryptoError::Unencrypted)
+    ));
+
+    keyring.accept_plaintext();
+    assert_eq!(
+      keyring
+        .decrypt(vec![1, 2, 3], &mut RecordChain::new())
+        .unwrap(),
+      (vec![1, 2, 3], None)
+    );
+  }
+
+  #[test]
+  fn test_missing_key() {
+    let sealed = CheckpointKey::new(1, &[3; 32]).encrypt(b"secret", &mut RecordChain::new());
+    let keyring = keyring(&[&CheckpointKey::new(2, &[3; 32])]);
+    assert!(matches!(
+      keyring.decrypt(sealed, &mut RecordChain::new()),
+      Err(CryptoError::MissingKey { key_id: 1 })
+    ));
+  }
+
+  #[test]
+  fn test_wrong_key() {
+    let sealed = CheckpointKey::new(1, &[3; 32]).encrypt(b"secret", &mut RecordChain::new());
+    let keyring = keyring(&[&CheckpointKey::new(1, &[4; 32])]);
+    assert!(matches!(
+      keyring.decrypt(sealed, &mut RecordChain::new()),
+      Err(CryptoError::WrongKey { key_id: 1 })
+    ));
+  }
+
+  #[test]
+  fn test_tampering_detected() {
+    let key = CheckpointKey::new(1, &[3; 32]);
+    let sealed = key.encrypt(b"secret", &mut RecordChain::new());
+    for i in 1..sealed.len() {
+      let mut tampered = sealed.clone();
+      tampered[i] ^= 1;
+      assert!(keyring(&[&key])
+        .decrypt(tampered, &mut RecordChain::new())
+        .is_err());
+    }
+  }
+
+  #[test]
+  fn test_truncated() {
+    assert!(matches!(
+      Keyring::new().decrypt(
+        vec![ENCRYPTED_RECORD_TAG, 1, 0, 0, 0],
+        &mut RecordChain::new()
+      ),
+      Err(CryptoError::Truncated)
+    ));
+  }
+
+  #[test]
+  fn test_chained_records() {
+    let key = CheckpointKey::new(1, &[3; 32]);
+    let mut chain = RecordChain::new();
+    let records: Vec<_> = [b"a", b"b", b"c"]
+      .iter()
+      .map(|payload| key.encrypt(*payload, &mut chain))
+      .collect();
+
+    let keyring = keyring(&[&key]);
+    let decrypt_all = |records: &[&Vec<u8>]| {
+      let mut chain = RecordChain::new();
+      records
+        .iter()
+        .map(|record| {
+          keyring
+            .decrypt((*record).clone(), &mut chain)
+            .map(|(payload, _)| payload)
+        })
+        .collect::<Result<Vec<_>, _>>()
+    };
+    assert_eq!(
+      decrypt_all(&[&records[0], &records[1], &records[2]]).unwrap(),
+      vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+    );
+    // Dropping or reordering records breaks the chain, though dropping the
+    // last record can't be noticed.
+    assert!(decrypt_all(&[&records[0], &records[2]]).is_err());
+    assert!(decrypt_all(&[&records[1], &records[0], &records[2]]).is_err());
+    assert!(decrypt_all(&[&records[0], &records[1], &records[1]]).is_err());
+    assert!(decrypt_all(&[&records[0], &records[1]]).is_ok());
+  }
+}
@@ -0,0 +1,195 @@
+use std::{
+  io,
+  path::{Path, PathBuf},
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use tokio::{
+  fs::{self, File, OpenOptions},
+  io::{AsyncWrite, AsyncWriteExt},
+};
+
+/// A destination for checkpoint records.
+#[async_trait]
+pub trait CheckpointWriter: AsyncWrite + Unpin + Send {
+  /// Atomically replaces everything written so far with `contents`. Later
+  /// writes are appended to `contents`.
+  async fn replace_contents(&mut self, contents: &[u8]) -> io::Result<()>;
+
+  /// Makes everything written so far durable, so it survives power loss.
+  async fn sync(&mut self) -> io::Result<()>;
+
+  /// Discards everything written after the first `len` bytes.
+  async fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+#[async_trait]
+impl CheckpointWriter for Vec<u8> {
+  async fn replace_contents(&mut self, contents: &[u8]) -> io::Result<()> {
+    self.clear();
+    self.extend(contents);
+    Ok(())
+  }
+
+  async fn sync(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+
+  async fn truncate(&mut self, len: u64) -> io::Result<()> {
+    Vec::truncate(self, len as usize);
+    Ok(())
+  }
+}
+
+/// A checkpoint file, opened for appending.
+pub struct CheckpointFile {
+  path: PathBuf,
+  file: File,
+}
+
+impl CheckpointFile {
+  /// Opens the checkpoint file at `path` for reading and appending, creating
+  /// it if it doesn't exist.
+  pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+    let path = path.as_ref().to_owned();
+    let file = Self::open_file(&path).await?;
+    Ok(Self { path, file })
+  }
+
+  async fn open_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+      .read(true)
+      .append(true)
+      .create(true)
+      .open(path)
+      .await
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn file(&self) -> &File {
+    &self.file
+  }
+
+  pub fn file_mut(&mut self) -> &mut File {
+    &mut self.file
+  }
+
+  /// The path new contents are staged at before replacing the checkpoint.
+  fn staging_path(&self) -> PathBuf {
+    let mut staging_path = self.path.clone().into_os_string();
+    staging_path.push(".tmp");
+    staging_path.into()
+  }
+}
+
+#[async_trait]
+impl CheckpointWriter for CheckpointFile {
+  /// Writes `contents` to a staging file, fsyncs it, and renames it over the
+  /// checkpoint. The parent directory is fsynced so the rename itself is
+  /// durable. A crash at any point leaves either the old or the new contents
+  /// at `path`.
+  async fn replace_contents(&mut self, contents: &[u8]) -> io::Result<()> {
+    let staging_path = self.staging_path();
+    let mut staging_file = File::create(&staging_path).await?;
+    staging_file.write_all(contents).await?;
+    staging_file.sync_all().await?;
+    drop(staging_file);
+
+    fs::rename(&staging_path, &self.path).await?;
+    let parent = match self.path.parent() {
+      Some(parent) if !parent.as_os_str().is_empty() => parent,
+      _ => Path::new("."),
+    };
+    File::open(parent).await?.sync_all().await?;
+
+    self.file = Self::open_file(&self.path).await?;
+    Ok(())
+  }
+
+  async fn sync(&mut self) -> io::Result<()> {
+    self.file.sync_data().await
+  }
+
+  async fn truncate(&mut self, len: u64) -> io::Result<()> {
+    self.file.set_len(len).await
+  }
+}
+
+impl AsyncWrite for CheckpointFile {
+  fn poll_write(
+    mut self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+  ) -> Poll<io::Result<usize>> {
+    Pin::new(&mut self.file).poll_write(cx, buf)
+  }
+
+  fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.file).poll_flush(cx)
+  }
+
+  fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+    Pin::new(&mut self.file).poll_shutdown(cx)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use tokio::io::AsyncWriteExt;
+
+  use super::{CheckpointFile, CheckpointWriter};
+
+  #[tokio::test]
+  async fn test_appends() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint");
+    std::fs::write(&path, b"abc").unwrap();
+
+    let mut file = CheckpointFile::open(&path).await.unwrap();
+    file.write_all(b"def").await.unwrap();
+    file.flush().await.unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"abcdef");
+  }
+
+  #[tokio::test]
+  async fn test_replace_contents() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint");
+
+    let mut file = CheckpointFile::open(&path).await.unwrap();
+    file.write_all(b"abc").await.unwrap();
+    file.replace_contents(b"xy").await.unwrap();
+    file.write_all(b"z").await.unwrap();
+    file.flush().await.unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), b"xyz");
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+  }
+
+  #[tokio::test]
+  async fn test_truncate() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint");
+
+    let mut file = CheckpointFile::open(&path).await.unwrap();
+    file.write_all(b"abcdef").await.unwrap();
+    file.truncate(3).await.unwrap();
+    file.write_all(b"x").await.unwrap();
+    file.flush().await.unwrap();
+
+    assert_eq!(std::fs::read(&path).unwrap(), b"abcx");
+  }
+
+  #[tokio::test]
+  async fn test_replace_vec_contents() {
+    let mut buffer = b"abc".to_vec();
+    buffer.replace_contents(b"xy").await.unwrap();
+    buffer.write_all(b"z").await.unwrap();
+    assert_eq!(buffer, b"xyz");
+  }
+}
@@ -0,0 +1,162 @@
+use crate::{
+  checkpoint_stream::RecordKind,
+  error::{McError, ThreadSafeError},
+};
+
+/// Rewrites the encoding of a snapshot or increment from one schema version
+/// to the next.
+pub type MigrationFn = fn(Vec<u8>) -> Result<Vec<u8>, Box<dyn ThreadSafeError>>;
+
+/// Upgrades records written at `from_version` to `from_version + 1`.
+#[derive(Clone, Copy)]
+pub struct Migration {
+  pub from_version: u32,
+  pub snapshot: MigrationFn,
+  pub increment: MigrationFn,
+}
+
+/// The migrations between every schema version of some checkpointed state.
+/// Schema versions start at 1, and the current version is the one after the
+/// last migration.
+#[derive(Clone, Default)]
+pub struct MigrationRegistry {
+  migrations: Vec<Migration>,
+}
+
+impl MigrationRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds the migration from the current version to the next. Migrations must
+  /// be added in order.
+  pub fn with_migration(mut self, migration: Migration) -> Self {
+    assert_eq!(
+      migration.from_version,
+      self.current_version(),
+      "Migrations must be registered in order"
+    );
+    self.migrations.push(migration);
+    self
+  }
+
+  /// The schema version new records are written at.
+  pub fn current_version(&self) -> u32 {
+    self.migrations.len() as u32 + 1
+  }
+
+  /// Migrates `body`, the body of a record of `kind` written at `version`, to
+  /// the current version.
+  pub fn migrate(
+    &self,
+    kind: RecordKind,
+    version: u32,
+    mut body: Vec<u8>,
+  ) -> Result<Vec<u8>, Box<dyn ThreadSafeError>> {
+    if version == 0 || version > self.current_version() {
+      return Err(McError::UnsupportedSchemaVersion(version).into());
+    }
+
+    for migration in &self.migrations[version as usize - 1..] {
+      body = match kind {
+        RecordKind::Snapshot => (migration.snapshot)(body)?,
+        RecordKind::Increment => (migration.increment)(body)?,
+      };
+    }
+    Ok(body)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{checkpoint_stream::RecordKind, error::ThreadSafeError};
+
+  use super::{Migration, MigrationRegistry};
+
+  fn snapshot_v2(mut body: Vec<u8>) -> Result<Vec<u8>, Box<dyn ThreadSafeError>> {
+    body.push(b's');
+    Ok(body)
+  }
+
+  fn increment_v2(mut body: Vec<u8>) -> Result<Vec<u8>, Box<dyn ThreadSafeError>> {
+    body.push(b'i');
+    Ok(body)
+  }
+
+  fn snapshot_v3(mut body: Vec<u8>) -> Result<Vec<u8>, Box<dyn ThreadSafeError>> {
+    body.push(b'S');
+    Ok(body)
+  }
+
+  fn increment_v3(mut body: Vec<u8>) -> Result<Vec<u8>, Box<dyn ThreadSafeError>> {
+    body.push(b'I');
+    Ok(body)
+  }
+
+  fn registry() -> MigrationRegistry {
+    MigrationRegistry::new()
+      .with_migration(Migration {
+        from_version: 1,
+        snapshot: snapshot_v2,
+        increment: increment_v2,
+      })
+      .with_migration(Migration {
+        from_version: 2,
+        snapshot: snapshot_v3,
+        increment: increment_v3,
+      })
+  }
+
+  #[test]
+  fn test_empty_registry() {
+    let registry = MigrationRegistry::new();
+    assert_eq!(registry.current_version(), 1);
+    assert_eq!(
+      registry
+        .migrate(RecordKind::Increment, 1, b"a".to_vec())
+        .unwrap(),
+      b"a"
+    );
+  }
+
+  #[test]
+  fn test_migrates_in_order() {
+    let registry = registry();
+    assert_eq!(registry.current_version(), 3);
+    assert_eq!(
+      registry
+        .migrate(RecordKind::Snapshot, 1, b"a".to_vec())
+        .unwrap(),
+      b"asS"
+    );
+    assert_eq!(
+      registry
+        .migrate(RecordKind::Increment, 2, b"a".to_vec())
+        .unwrap(),
+      b"aI"
+    );
+    assert_eq!(
+      registry
+        .migrate(RecordKind::Increment, 3, b"a".to_vec())
+        .unwrap(),
+      b"a"
+    );
+  }
+
+  #[test]
+  fn test_unsupported_versions() {
+    let registry = registry();
+    assert!(registry.migrate(RecordKind::Snapshot, 0, vec![]).is_err());
+    assert!(registry.migrate(RecordKind::Snapshot, 4, vec![]).is_err());
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_migrations_registered_out_of_order() {
+    MigrationRegistry::new().with_migration(Migration {
+      from_version: 2,
+      snapshot: snapshot_v3,
+      increment: increment_v3,
+    });
+  }
+}
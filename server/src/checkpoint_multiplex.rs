@@ -0,0 +1,587 @@
+//! Checkpointing several states into one stream.
+//!
+//! Each state registered with a multiplexed stream is a named source. Its
+//! records are tagged with its name, so they can be routed back to it during
+//! recovery. A tagged record's payload is `SOURCE_RECORD_TAG`, the length of
+//! the source's name as a byte, the name, and the record's envelope. A stream
+//! over a single state writes untagged records.
+
+use std::{any::Any, sync::Arc};
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+  checkpoint_codec::{BincodeCodec, Codec, CodecError},
+  checkpoint_migration::MigrationRegistry,
+  checkpoint_stream::{IncrementalUpdate, RecordKind},
+  error::{McError, ThreadSafeError},
+};
+
+/// The first byte of a record tagged with the name of its source. This doesn't
+/// collide with any `RecordKind` or `ENCRYPTED_RECORD_TAG`.
+pub const SOURCE_RECORD_TAG: u8 = 0x5e;
+
+/// Tags a record's payload with the name of its source.
+pub fn tag_record(source: &str, payload: &[u8]) -> Vec<u8> {
+  let mut tagged = Vec::with_capacity(2 + source.len() + payload.len());
+  tagged.push(SOURCE_RECORD_TAG);
+  tagged.push(source.len() as u8);
+  tagged.extend(source.as_bytes());
+  tagged.extend(payload);
+  tagged
+}
+
+/// Splits a record's payload into the name of its source, if it is tagged,
+/// and the untagged payload.
+pub fn untag_record(mut payload: Vec<u8>) -> Result<(Option<String>, Vec<u8>), McError> {
+  if payload.first() != Some(&SOURCE_RECORD_TAG) {
+    return Ok((None, payload));
+  }
+
+  let name_len = match payload.get(1) {
+    Some(&name_len) if payload.len() >= 2 + name_len as usize => name_len as usize,
+    _ => {
+      return Err(McError::CorruptCheckpoint(
+        "Record too short for its source tag".to_owned(),
+      ))
+    }
+  };
+  let rest = payload.split_off(2 + name_len);
+  let name = String::from_utf8(payload.split_off(2))
+    .map_err(|_| McError::CorruptCheckpoint("Source name is not UTF-8".to_owned()))?;
+  Ok((Some(name), rest))
+}
+
+/// Summarizes the recovery of one source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceRecovery {
+  /// The number of records replayed into the source, including any snapshot.
+  pub records_applied: usize,
+}
+
+type RecoveryCallback = Box<dyn FnOnce(&mut dyn Any, SourceRecovery) + Send>;
+
+/// The states checkpointed by a multiplexed stream.
+#[derive(Default)]
+pub struct CheckpointSources {
+  sources: Vec<Source>,
+  callbacks: Vec<RecoveryCallback>,
+}
+
+impl CheckpointSources {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// A single untagged source, for streams over one state.
+  pub(crate) fn single<S>(state: Arc<Mutex<S>>) -> Self
+  where
+    S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+  {
+    let mut sources = Self::new();
+    sources.add(None, state, |_, _| {});
+    sources
+  }
+
+  /// Registers `state` as the source `name`. After recovering a stream,
+  /// `on_recover` is called with the source's recovered state and how many
+  /// records were replayed into it. Names must be unique and at most 255
+  /// bytes long.
+  pub fn with_source<S, F>(
+    mut self,
+    name: impl Into<String>,
+    state: Arc<Mutex<S>>,
+    on_recover: F,
+  ) -> Self
+  where
+    S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+    F: FnOnce(&mut S, SourceRecovery) + Send + 'static,
+  {
+    let name = name.into();
+    assert!(
+      name.len() <= u8::MAX as usize,
+      "Source name {name} is too long"
+    );
+    assert!(
+      self
+        .sources
+        .iter()
+        .all(|source| source.name.as_ref() != Some(&name)),
+      "Source {name} is already registered"
+    );
+    self.add(Some(name), state, on_recover);
+    self
+  }
+
+  fn add<S, F>(&mut self, name: Option<String>, state: Arc<Mutex<S>>, on_recover: F)
+  where
+    S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+    F: FnOnce(&mut S, SourceRecovery) + Send + 'static,
+  {
+    self.sources.push(Source {
+      name,
+      migrations: S::migrations(),
+      state,
+    });
+    self.callbacks.push(Box::new(move |state, recovery| {
+      on_recover(state.downcast_mut().unwrap(), recovery)
+    }));
+  }
+
+  pub(crate) fn into_parts(self) -> (Vec<Source>, Vec<RecoveryCallback>) {
+    (self.sources, self.callbacks)
+  }
+}
+
+/// A state registered with a stream.
+pub(crate) struct Source {
+  /// The name records are tagged with, or `None` if they aren't tagged.
+  name: Option<String>,
+  migrations: MigrationRegistry,
+  state: Arc<dyn ErasedState>,
+}
+
+impl Source {
+  /// Tags `payload` with the source's name, if it has one.
+  fn tag(&self, payload: Vec<u8>) -> Vec<u8> {
+    match &self.name {
+      Some(name) => tag_record(name, &payload),
+      None => payload,
+    }
+  }
+}
+
+#[async_trait]
+trait ErasedState: Send + Sync {
+  async fn lock(&self) -> Box<dyn LockedState + '_>;
+}
+
+#[async_trait]
+impl<S> ErasedState for Mutex<S>
+where
+  S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+{
+  async fn lock(&self) -> Box<dyn LockedState + '_> {
+    Box::new(Mutex::lock(self).await)
+  }
+}
+
+/// A locked state, with its type erased.
+trait LockedState: Send + Sync {
+  fn has_update(&self) -> bool;
+
+  /// Commits and encodes the pending increment.
+  fn commit(&mut self) -> Result<Vec<u8>, CodecError>;
+
+  fn snapshot(&self) -> Result<Vec<u8>, bincode::Error>;
+
+  /// Replaces the state with a snapshot or applies an increment.
+  fn restore(&mut self, kind: RecordKind, body: &[u8]) -> Result<(), CodecError>;
+
+  fn as_any(&mut self) -> &mut dyn Any;
+}
+
+impl<S> LockedState for MutexGuard<'_, S>
+where
+  S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+{
+  fn has_update(&self) -> bool {
+    S::has_update(self)
+  }
+
+  fn commit(&mut self) -> Result<Vec<u8>, CodecError> {
+    S::Codec::encode(&S::commit(self))
+  }
+
+  fn snapshot(&self) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(&**self)
+  }
+
+  fn restore(&mut self, kind: RecordKind, body: &[u8]) -> Result<(), CodecError> {
+    match kind {
+      RecordKind::Snapshot => **self = BincodeCodec::decode(body)?,
+      RecordKind::Increment => self.apply(S::Codec::decode(body)?),
+    }
+    Ok(())
+  }
+
+  fn as_any(&mut self) -> &mut dyn Any {
+    &mut **self
+  }
+}
+
+/// Every source of a stream, locked in the order they were registered.
+pub(crate) struct LockedSources<'a> {
+  sources: &'a [Source],
+  states: Vec<Box<dyn LockedState + 'a>>,
+}
+
+impl<'a> LockedSources<'a> {
+  pub async fn lock(sources: &'a [Source]) -> LockedSources<'a> {
+    let mut states = Vec::with_capacity(sources.len());
+    for source in sources {
+      states.push(source.state.lock().await);
+    }
+    Self { sources, states }
+  }
+
+  /// Commits the pending increment of every source with one, returning the
+  /// payloads of their increment records.
+  pub fn commit(&mut self) -> Result<Vec<Vec<u8>>, Box<dyn ThreadSafeError>> {
+    let mut payloads = vec![];
+    for (source, state) in self.sources.iter().zip(&mut self.states) {
+      if state.has_update() {
+        let increment = state.commit()?;
+        payloads.push(
+          source.tag(RecordKind::Increment.encode(source.migrations.current_version(), &increment)),
+        );
+      }
+    }
+    Ok(payloads)
+  }
+
+  /// Discards the pending increment of every source.
+  pub fn discard_updates(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.commit()?;
+    Ok(())
+  }
+
+  /// Returns the payloads of snapshot records of every source.
+  pub fn snapshot(&self) -> Result<Vec<Vec<u8>>, Box<dyn ThreadSafeError>> {
+    let mut payloads = vec![];
+    for (source, state) in self.sources.iter().zip(&self.states) {
+      let snapshot = state.snapshot()?;
+      payloads.push(
+        source.tag(RecordKind::Snapshot.encode(source.migrations.current_version(), &snapshot)),
+      );
+    }
+    Ok(payloads)
+  }
+
+  /// Routes the (decrypted) payload of a record to its source, migrating it to
+  /// the source's current schema version and applying it. Returns the index
+  /// of the source and the kind of the record.
+  pub fn restore(
+    &mut self,
+    payload: Vec<u8>,
+  ) -> Result<(usize, RecordKind), Box<dyn ThreadSafeError>> {
+    let (name, payload) = untag_record(payload)?;
+    let index = self
+      .sources
+      .iter()
+      .position(|source| source.name == name)
+      .ok_or_else(|| {
+        McError::CorruptCheckpoint(match &name {
+          Some(name) => format!("Record for unregistered source {name}"),
+          None => "Untagged record in a multiplexed checkpoint".to_owned(),
+        })
+      })?;
+
+    let (kind, schema_version, body) = RecordKind::decode(payload)?;
+    let body = self.sources[index]
+      .migrations
+      .migrate(kind, schema_version, body)?;
+    self.states[index].restore(kind, &body)?;
+    Ok((index, kind))
+  }
+
+  /// Calls each source's recovery callback, given the number of records
+  /// replayed into each.
+  pub fn recovered(&mut self, callbacks: Vec<RecoveryCallback>, records_applied: &[usize]) {
+    for ((state, callback), &records_applied) in
+      self.states.iter_mut().zip(callbacks).zip(records_applied)
+    {
+      callback(state.as_any(), SourceRecovery { records_applied });
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::{path::Path, sync::Arc};
+
+  use serde::{Deserialize, Serialize};
+  use tempfile::NamedTempFile;
+  use tokio::sync::Mutex;
+
+  use crate::{
+    checkpoint_codec::BincodeCodec,
+    checkpoint_stream::{CheckpointStreamOptions, CompactionPolicy, IncrementalUpdate, Recovery},
+    error::{McError, ThreadSafeError},
+  };
+
+  use super::{tag_record, untag_record, CheckpointSources, SourceRecovery, SOURCE_RECORD_TAG};
+
+  /// A counter, whose increments are the amount added since the last commit.
+  #[derive(Default, Serialize, Deserialize)]
+  struct Counter {
+    count: u64,
+    #[serde(skip)]
+    pending: u64,
+  }
+
+  impl Counter {
+    fn add(&mut self, amount: u64) {
+      self.count += amount;
+      self.pending += amount;
+    }
+  }
+
+  impl IncrementalUpdate for Counter {
+    type Increment = u64;
+    type Codec = BincodeCodec;
+
+    fn has_update(&self) -> bool {
+      self.pending != 0
+    }
+
+    fn commit(&mut self) -> u64 {
+      std::mem::take(&mut self.pending)
+    }
+
+    fn apply(&mut self, increment: u64) {
+      self.count += increment;
+    }
+  }
+
+  /// A set of names, whose increments are the names added since the last
+  /// commit.
+  #[derive(Default, Serialize, Deserialize)]
+  struct Names {
+    names: Vec<String>,
+    #[serde(skip)]
+    pending: Vec<String>,
+  }
+
+  impl Names {
+    fn add(&mut self, name: &str) {
+      self.names.push(name.to_owned());
+      self.pending.push(name.to_owned());
+    }
+  }
+
+  impl IncrementalUpdate for Names {
+    type Increment = Vec<String>;
+    type Codec = BincodeCodec;
+
+    fn has_update(&self) -> bool {
+      !self.pending.is_empty()
+    }
+
+    fn commit(&mut self) -> Vec<String> {
+      std::mem::take(&mut self.pending)
+    }
+
+    fn apply(&mut self, increment: Vec<String>) {
+      self.names.extend(increment);
+    }
+  }
+
+  struct Recovered {
+    counter: Arc<Mutex<Counter>>,
+    names: Arc<Mutex<Names>>,
+    recovery: Recovery,
+    /// The summaries passed to the counter's and names' recovery callbacks.
+    summaries: Arc<std::sync::Mutex<Vec<(&'static str, SourceRecovery)>>>,
+  }
+
+  async fn recover(
+    options: CheckpointStreamOptions,
+    path: &Path,
+  ) -> Result<Recovered, Box<dyn ThreadSafeError>> {
+    let counter = Arc::new(Mutex::new(Counter::default()));
+    let names = Arc::new(Mutex::new(Names::default()));
+    let summaries = Arc::new(std::sync::Mutex::new(vec![]));
+    let sources = CheckpointSources::new()
+      .with_source("counter", counter.clone(), {
+        let summaries = summaries.clone();
+        move |_, recovery| summaries.lock().unwrap().push(("counter", recovery))
+      })
+      .with_source("names", names.clone(), {
+        let summaries = summaries.clone();
+        move |_, recovery| summaries.lock().unwrap().push(("names", recovery))
+      });
+    let recovery = options.recover_multiplexed(path, sources).await?;
+    Ok(Recovered {
+      counter,
+      names,
+      recovery,
+      summaries,
+    })
+  }
+
+  #[test]
+  fn test_tag_round_trip() {
+    let tagged = tag_record("users", &[1, 2, 3]);
+    assert_eq!(tagged[..2], [SOURCE_RECORD_TAG, 5]);
+    assert_eq!(
+      untag_record(tagged).unwrap(),
+      (Some("users".to_owned()), vec![1, 2, 3])
+    );
+  }
+
+  #[test]
+  fn test_untagged_record() {
+    assert_eq!(untag_record(vec![0, 1]).unwrap(), (None, vec![0, 1]));
+  }
+
+  #[test]
+  fn test_truncated_tag() {
+    assert!(matches!(
+      untag_record(vec![SOURCE_RECORD_TAG, 5, b'u']),
+      Err(McError::CorruptCheckpoint(_))
+    ));
+    assert!(matches!(
+      untag_record(vec![SOURCE_RECORD_TAG]),
+      Err(McError::CorruptCheckpoint(_))
+    ));
+  }
+
+  #[test]
+  #[should_panic]
+  fn test_duplicate_source() {
+    CheckpointSources::new()
+      .with_source("a", Arc::new(Mutex::new(Counter::default())), |_, _| {})
+      .with_source("a", Arc::new(Mutex::new(Names::default())), |_, _| {});
+  }
+
+  #[tokio::test]
+  async fn test_routes_records_to_sources() {
+    let file = NamedTempFile::new().unwrap();
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    let handle = recovered.recovery.stream.start();
+    recovered.counter.lock().await.add(2);
+    recovered.names.lock().await.add("bob");
+    handle.stop().await.unwrap();
+
+    let mut recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    recovered.counter.lock().await.add(3);
+    recovered.recovery.stream.compact().await.unwrap();
+    assert_eq!(recovered.recovery.records_applied, 2);
+
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    assert_eq!(recovered.counter.lock().await.count, 5);
+    assert_eq!(recovered.names.lock().await.names, vec!["bob"]);
+  }
+
+  #[tokio::test]
+  async fn test_recovery_callbacks() {
+    let file = NamedTempFile::new().unwrap();
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    let handle = recovered.recovery.stream.start();
+    recovered.counter.lock().await.add(1);
+    recovered.names.lock().await.add("bob");
+    handle.stop().await.unwrap();
+
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    let handle = recovered.recovery.stream.start();
+    recovered.counter.lock().await.add(1);
+    handle.stop().await.unwrap();
+
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    assert_eq!(
+      *recovered.summaries.lock().unwrap(),
+      vec![
+        ("counter", SourceRecovery { records_applied: 2 }),
+        ("names", SourceRecovery { records_applied: 1 }),
+      ]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_compaction_snapshots_every_source() {
+    let file = NamedTempFile::new().unwrap();
+    let options = || CheckpointStreamOptions {
+      compaction: CompactionPolicy {
+        max_increments: Some(2),
+        max_bytes: None,
+      },
+      ..CheckpointStreamOptions::default()
+    };
+
+    for i in 0..5 {
+      let recovered = recover(options(), file.path()).await.unwrap();
+      let handle = recovered.recovery.stream.start();
+      recovered.counter.lock().await.add(1);
+      recovered.names.lock().await.add(&i.to_string());
+      handle.stop().await.unwrap();
+    }
+
+    let recovered = recover(options(), file.path()).await.unwrap();
+    assert_eq!(recovered.counter.lock().await.count, 5);
+    assert_eq!(
+      recovered.names.lock().await.names,
+      vec!["0", "1", "2", "3", "4"]
+    );
+    assert!(recovered.recovery.records_applied <= 4);
+  }
+
+  #[tokio::test]
+  async fn test_one_sync_for_every_source() {
+    let counter = Arc::new(Mutex::new(Counter::default()));
+    let names = Arc::new(Mutex::new(Names::default()));
+    let sources = CheckpointSources::new()
+      .with_source("counter", counter.clone(), |_, _| {})
+      .with_source("names", names.clone(), |_, _| {});
+    let stream = CheckpointStreamOptions::default()
+      .build_multiplexed(Vec::new(), sources)
+      .await
+      .unwrap();
+    let sync_stats = stream.sync_stats();
+    let handle = stream.start();
+
+    counter.lock().await.add(1);
+    names.lock().await.add("bob");
+    handle.stop().await.unwrap();
+    assert_eq!(sync_stats.lock().await.syncs, 1);
+  }
+
+  #[tokio::test]
+  async fn test_unregistered_source_fails() {
+    let file = NamedTempFile::new().unwrap();
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    let handle = recovered.recovery.stream.start();
+    recovered.names.lock().await.add("bob");
+    handle.stop().await.unwrap();
+
+    let counter = Arc::new(Mutex::new(Counter::default()));
+    let sources = CheckpointSources::new().with_source("counter", counter, |_, _| {});
+    assert!(CheckpointStreamOptions::default()
+      .recover_multiplexed(file.path(), sources)
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_single_state_recovery_rejects_tagged_records() {
+    let file = NamedTempFile::new().unwrap();
+    let recovered = recover(CheckpointStreamOptions::default(), file.path())
+      .await
+      .unwrap();
+    let handle = recovered.recovery.stream.start();
+    recovered.counter.lock().await.add(1);
+    handle.stop().await.unwrap();
+
+    let counter = Arc::new(Mutex::new(Counter::default()));
+    assert!(CheckpointStreamOptions::default()
+      .recover(file.path(), counter)
+      .await
+      .is_err());
+  }
+}
@@ -0,0 +1,334 @@
+//! The on-disk format of checkpoint records.
+//!
+//! Every record is a fixed-size header followed by the record's payload. All
+//! integers are little-endian.
+//!
+//! | offset | size | field                                        |
+//! |--------|------|----------------------------------------------|
+//! | 0      | 4    | magic number, `RECORD_MAGIC`                 |
+//! | 4      | 1    | format version, `RECORD_VERSION`             |
+//! | 5      | 4    | payload length                               |
+//! | 9      | 4    | CRC32C of the payload                        |
+//! | 13     | 4    | CRC32C of the preceding 13 bytes of header   |
+//!
+//! The header has its own checksum so a corrupted length is detected before
+//! it is used to find the payload.
+
+use std::{error, fmt::Display, io};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const RECORD_MAGIC: [u8; 4] = *b"MCCK";
+pub const RECORD_VERSION: u8 = 1;
+pub const RECORD_HEADER_LEN: usize = 17;
+
+const CHECKSUMMED_HEADER_LEN: usize = 13;
+
+#[derive(Debug)]
+pub enum RecordError {
+  Io(io::Error),
+  /// The stream ended partway through the record at `offset`.
+  Truncated {
+    offset: u64,
+  },
+  /// The record at `offset` doesn't start with `RECORD_MAGIC`.
+  BadMagic {
+    offset: u64,
+  },
+  /// The header of the record at `offset` doesn't match its checksum.
+  CorruptHeader {
+    offset: u64,
+  },
+  /// The record at `offset` was written in a format version this reader
+  /// doesn't understand.
+  UnsupportedVersion {
+    offset: u64,
+    version: u8,
+  },
+  /// The payload of the record at `offset` doesn't match its checksum.
+  CorruptPayload {
+    offset: u64,
+  },
+}
+
+impl Display for RecordError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      RecordError::Io(err) => write!(f, "I/O error reading checkpoint record: {err}"),
+      RecordError::Truncated { offset } => {
+        write!(f, "Checkpoint record at offset {offset} is truncated")
+      }
+      RecordError::BadMagic { offset } => {
+        write!(f, "No checkpoint record magic number at offset {offset}")
+      }
+      RecordError::CorruptHeader { offset } => {
+        write!(f, "Checkpoint record header at offset {offset} is corrupt")
+      }
+      RecordError::UnsupportedVersion { offset, version } => write!(
+        f,
+        "Checkpoint record at offset {offset} has unsupported version {version}"
+      ),
+      RecordError::CorruptPayload { offset } => {
+        write!(f, "Checkpoint record payload at offset {offset} is corrupt")
+      }
+    }
+  }
+}
+
+impl error::Error for RecordError {}
+
+impl From<io::Error> for RecordError {
+  fn from(value: io::Error) -> Self {
+    Self::Io(value)
+  }
+}
+
+/// Encodes `payload` as a complete record.
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+  let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+  record.extend(RECORD_MAGIC);
+  record.push(RECORD_VERSION);
+  record.extend((payload.len() as u32).to_le_bytes());
+  record.extend(crc32c::crc32c(payload).to_le_bytes());
+  record.extend(crc32c::crc32c(&record).to_le_bytes());
+  record.extend(payload);
+  record
+}
+
+/// Writes framed, checksummed records to an underlying writer.
+pub struct RecordWriter<W> {
+  writer: W,
+}
+
+impl<W> RecordWriter<W>
+where
+  W: AsyncWrite + Unpin,
+{
+  pub fn new(writer: W) -> Self {
+    Self { writer }
+  }
+
+  /// Writes `payload` as a single record. The record is written with one
+  /// call to `write_all`, so it is not interleaved with other writes.
+  pub async fn write_record(&mut self, payload: &[u8]) -> io::Result<()> {
+    self.writer.write_all(&encode_record(payload)).await
+  }
+
+  pub async fn flush(&mut self) -> io::Result<()> {
+    self.writer.flush().await
+  }
+
+  pub fn get_ref(&self) -> &W {
+    &self.writer
+  }
+
+  pub fn get_mut(&mut self) -> &mut W {
+    &mut self.writer
+  }
+
+  pub fn into_inner(self) -> W {
+    self.writer
+  }
+}
+
+/// Reads records written by a `RecordWriter`, verifying each one.
+pub struct RecordReader<R> {
+  reader: R,
+  offset: u64,
+}
+
+impl<R> RecordReader<R>
+where
+  R: AsyncRead + Unpin,
+{
+  pub fn new(reader: R) -> Self {
+    Self { reader, offset: 0 }
+  }
+
+  /// The offset just past the last record successfully read.
+  pub fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  /// Reads into `buf` until it is full or the reader is exhausted, returning
+  /// the number of bytes read.
+  async fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+      match self.reader.read(&mut buf[filled..]).await? {
+        0 => break,
+        n => filled += n,
+      }
+    }
+    Ok(filled)
+  }
+
+  /// Reads the payload of the next record. Returns `Ok(None)` if the reader
+  /// is exhausted exactly at a record boundary.
+  pub async fn read_record(&mut self) -> Result<Option<Vec<u8>>, RecordError> {
+    let offset = self.offset;
+
+    let mut header = [0; RECORD_HEADER_LEN];
+    match self.read_fully(&mut header).await? {
+      0 => return Ok(None),
+      RECORD_HEADER_LEN => {}
+      _ => return Err(RecordError::Truncated { offset }),
+    }
+
+    if header[0..4] != RECORD_MAGIC {
+      return Err(RecordError::BadMagic { offset });
+    }
+    let header_crc = u32::from_le_bytes(header[13..17].try_into().unwrap());
+    if crc32c::crc32c(&header[..CHECKSUMMED_HEADER_LEN]) != header_crc {
+      return Err(RecordError::CorruptHeader { offset });
+    }
+    let version = header[4];
+    if version != RECORD_VERSION {
+      return Err(RecordError::UnsupportedVersion { offset, version });
+    }
+    let len = u32::from_le_bytes(header[5..9].try_into().unwrap()) as usize;
+    let payload_crc = u32::from_le_bytes(header[9..13].try_into().unwrap());
+
+    let mut payload = vec![0; len];
+    if self.read_fully(&mut payload).await? != len {
+      return Err(RecordError::Truncated { offset });
+    }
+    if crc32c::crc32c(&payload) != payload_crc {
+      return Err(RecordError::CorruptPayload { offset });
+    }
+
+    self.offset += (RECORD_HEADER_LEN + len) as u64;
+    Ok(Some(payload))
+  }
+
+  pub fn into_inner(self) -> R {
+    self.reader
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::{
+    encode_record, RecordError, RecordReader, RecordWriter, RECORD_HEADER_LEN, RECORD_VERSION,
+  };
+
+  async fn write_records(payloads: &[&[u8]]) -> Vec<u8> {
+    let mut writer = RecordWriter::new(Vec::new());
+    for payload in payloads {
+      writer.write_record(payload).await.unwrap();
+    }
+    writer.into_inner()
+  }
+
+  async fn read_records(encoding: &[u8]) -> Result<Vec<Vec<u8>>, RecordError> {
+    let mut reader = RecordReader::new(encoding);
+    let mut payloads = Vec::new();
+    while let Some(payload) = reader.read_record().await? {
+      payloads.push(payload);
+    }
+    Ok(payloads)
+  }
+
+  #[tokio::test]
+  async fn test_empty() {
+    assert!(read_records(&[]).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_round_trip() {
+    let encoding = write_records(&[b"abc", b"", b"defgh"]).await;
+    assert_eq!(
+      read_records(&encoding).await.unwrap(),
+      vec![b"abc".to_vec(), vec![], b"defgh".to_vec()]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_offsets() {
+    let encoding = write_records(&[b"abc", b"defgh"]).await;
+    let mut reader = RecordReader::new(encoding.as_slice());
+    assert_eq!(reader.offset(), 0);
+    reader.read_record().await.unwrap();
+    assert_eq!(reader.offset(), (RECORD_HEADER_LEN + 3) as u64);
+    reader.read_record().await.unwrap();
+    assert_eq!(reader.offset(), encoding.len() as u64);
+    assert!(reader.read_record().await.unwrap().is_none());
+    assert_eq!(reader.offset(), encoding.len() as u64);
+  }
+
+  #[tokio::test]
+  async fn test_concatenated_streams() {
+    let mut encoding = write_records(&[b"a", b"b"]).await;
+    encoding.extend(write_records(&[b"c"]).await);
+    encoding.extend(encode_record(b"d"));
+    assert_eq!(
+      read_records(&encoding).await.unwrap(),
+      vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_every_truncation_detected() {
+    let first_len = RECORD_HEADER_LEN + 3;
+    let encoding = write_records(&[b"abc", b"defgh"]).await;
+    for len in 1..encoding.len() {
+      let result = read_records(&encoding[..len]).await;
+      if len == first_len {
+        assert_eq!(result.unwrap(), vec![b"abc".to_vec()]);
+      } else {
+        let expected_offset = if len < first_len { 0 } else { first_len as u64 };
+        assert!(
+          matches!(result, Err(RecordError::Truncated { offset }) if offset == expected_offset),
+          "Truncation to {len} bytes not detected: {result:?}"
+        );
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_every_bit_flip_detected() {
+    let encoding = write_records(&[b"abc", b"defgh"]).await;
+    for bit in 0..encoding.len() * 8 {
+      let mut corrupted = encoding.clone();
+      corrupted[bit / 8] ^= 1 << (bit % 8);
+      let result = read_records(&corrupted).await;
+      assert!(
+        matches!(
+          result,
+          Err(
+            RecordError::BadMagic { .. }
+              | RecordError::CorruptHeader { .. }
+              | RecordError::CorruptPayload { .. }
+          )
+        ),
+        "Flip of bit {bit} not detected: {result:?}"
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn test_records_before_corruption_readable() {
+    let mut encoding = write_records(&[b"abc", b"defgh"]).await;
+    let last = encoding.len() - 1;
+    encoding[last] ^= 1;
+
+    let mut reader = RecordReader::new(encoding.as_slice());
+    assert_eq!(reader.read_record().await.unwrap(), Some(b"abc".to_vec()));
+    assert!(matches!(
+      reader.read_record().await,
+      Err(RecordError::CorruptPayload { offset }) if offset == (RECORD_HEADER_LEN + 3) as u64
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_unsupported_version() {
+    let mut encoding = encode_record(b"abc");
+    encoding[4] = RECORD_VERSION + 1;
+    let header_crc = crc32c::crc32c(&encoding[..13]);
+    encoding[13..17].copy_from_slice(&header_crc.to_le_bytes());
+    assert!(matches!(
+      read_records(&encoding).await,
+      Err(RecordError::UnsupportedVersion { offset: 0, version }) if version == RECORD_VERSION + 1
+    ));
+  }
+}
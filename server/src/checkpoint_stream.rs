@@ -1,101 +1,1898 @@
-use std::{ops::DerefMut, time::Duration};
+use std::{
+  mem,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
 
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
-  io::{AsyncWrite, AsyncWriteExt},
-  sync::Mutex,
+  io::{AsyncWriteExt, BufReader},
+  sync::{Mutex, Notify},
   task::JoinHandle,
-  time::{interval, MissedTickBehavior},
+  time::{interval, sleep, sleep_until, Instant, MissedTickBehavior},
 };
+use tokio_util::sync::CancellationToken;
 
-use crate::error::ThreadSafeError;
+use crate::{
+  checkpoint_codec::Codec,
+  checkpoint_crypto::{CheckpointKey, Keyring, RecordChain},
+  checkpoint_file::{CheckpointFile, CheckpointWriter},
+  checkpoint_migration::MigrationRegistry,
+  checkpoint_multiplex::{CheckpointSources, LockedSources, Source},
+  checkpoint_record::{encode_record, RecordError, RecordReader, RecordWriter, RECORD_HEADER_LEN},
+  error::{McError, ThreadSafeError},
+};
 
+/// State which can be checkpointed as a series of increments. The full state
+/// is serialized for snapshots.
 pub trait IncrementalUpdate: Serialize {
+  /// The changes made to the state between two checkpoints.
+  type Increment;
+
+  /// How increments are encoded in checkpoint records.
+  type Codec: Codec<Self::Increment>;
+
   /// Should return true when there is new uncommitted incremental state to be
   /// checkpointed.
   fn has_update(&self) -> bool;
 
-  /// Called after the incremental state has been checkpointed. This should
-  /// commit all incremental state to the full state.
-  fn commit(&mut self) -> Result<Vec<u8>, Box<dyn ThreadSafeError>>;
+  /// Called when the incremental state is about to be checkpointed. This
+  /// should commit all incremental state to the full state, returning the
+  /// changes since the last commit.
+  fn commit(&mut self) -> Self::Increment;
+
+  /// Applies an increment recovered from a checkpoint. Increments are applied
+  /// in the order they were committed.
+  fn apply(&mut self, increment: Self::Increment);
+
+  /// Migrations from older schema versions of the state's snapshots and
+  /// increments. Records are written at the registry's current version, and
+  /// older records are migrated to it when recovered.
+  fn migrations() -> MigrationRegistry {
+    MigrationRegistry::new()
+  }
+}
+
+/// What a checkpoint record holds. A record's payload is an envelope of its
+/// kind in the first byte, then the little-endian `u32` schema version its
+/// body was written at, then the body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordKind {
+  /// A bincode encoding of the full state, which replaces any prior state.
+  Snapshot = 0,
+  /// An increment returned by `IncrementalUpdate::commit`.
+  Increment = 1,
+}
+
+const ENVELOPE_LEN: usize = 5;
+
+impl RecordKind {
+  /// Wraps `body`, written at `schema_version`, in a record payload.
+  pub fn encode(self, schema_version: u32, body: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(ENVELOPE_LEN + body.len());
+    payload.push(self as u8);
+    payload.extend(schema_version.to_le_bytes());
+    payload.extend(body);
+    payload
+  }
+
+  /// Splits a record's payload into its kind, schema version and body.
+  pub fn decode(mut payload: Vec<u8>) -> Result<(Self, u32, Vec<u8>), McError> {
+    if payload.len() < ENVELOPE_LEN {
+      return Err(McError::CorruptCheckpoint(
+        "Record too short for its envelope".to_owned(),
+      ));
+    }
+    let kind = match payload[0] {
+      0 => Self::Snapshot,
+      1 => Self::Increment,
+      kind => {
+        return Err(McError::CorruptCheckpoint(format!(
+          "Unknown record kind {kind}"
+        )))
+      }
+    };
+    let schema_version = u32::from_le_bytes(payload[1..ENVELOPE_LEN].try_into().unwrap());
+    payload.drain(..ENVELOPE_LEN);
+    Ok((kind, schema_version, payload))
+  }
+}
+
+/// When to replace the checkpoint with a snapshot of the full state. The
+/// checkpoint is compacted as soon as either limit is exceeded, and never if
+/// neither is set.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompactionPolicy {
+  /// The number of increments to allow after the last snapshot.
+  pub max_increments: Option<usize>,
+  /// The size to allow the checkpoint to grow to, in bytes. This should be
+  /// comfortably larger than a snapshot, or every checkpoint will compact.
+  pub max_bytes: Option<u64>,
+}
+
+impl CompactionPolicy {
+  fn should_compact(&self, increments: usize, bytes: u64) -> bool {
+    self.max_increments.is_some_and(|max| increments > max)
+      || self.max_bytes.is_some_and(|max| bytes > max)
+  }
+}
+
+/// When to fsync checkpoints, trading throughput for how many checkpoints a
+/// power loss can drop. Compaction always syncs the new snapshot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+  /// Never sync, leaving it to the OS to write checkpoints back.
+  None,
+  /// Sync after every checkpoint.
+  EveryCommit,
+  /// Sync at most once per `interval`. Checkpoints written since the last
+  /// sync are synced by the first tick after `interval` elapses, so up to
+  /// `interval` plus a poll period of checkpoints may be lost.
+  Grouped { interval: Duration },
+}
+
+impl Durability {
+  fn should_sync(&self, last_sync: Option<Instant>, now: Instant) -> bool {
+    match self {
+      Durability::None => false,
+      Durability::EveryCommit => true,
+      Durability::Grouped { interval } => match last_sync {
+        Some(last_sync) => now >= last_sync + *interval,
+        None => true,
+      },
+    }
+  }
+}
+
+/// How long a stream has spent syncing checkpoints.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncStats {
+  /// The number of syncs performed.
+  pub syncs: u64,
+  /// The total time spent syncing.
+  pub total_time: Duration,
+  /// The time taken by the slowest sync.
+  pub max_time: Duration,
+}
+
+impl SyncStats {
+  fn record(&mut self, time: Duration) {
+    self.syncs += 1;
+    self.total_time += time;
+    self.max_time = self.max_time.max(time);
+  }
+
+  /// The average time taken per sync.
+  pub fn mean_time(&self) -> Duration {
+    if self.syncs == 0 {
+      Duration::ZERO
+    } else {
+      self.total_time / self.syncs as u32
+    }
+  }
+}
+
+/// What wakes a stream to check for updates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckpointTrigger {
+  /// Check for updates every `period`.
+  Poll { period: Duration },
+  /// Check for updates when notified through the stream's
+  /// `CheckpointNotifier`. If `debounce` is set, the stream waits until no
+  /// notification has arrived for `debounce` before checkpointing, but no
+  /// longer than `max_latency` after the first notification.
+  Notify {
+    debounce: Option<Duration>,
+    max_latency: Option<Duration>,
+  },
+}
+
+/// Wakes a stream using `CheckpointTrigger::Notify` to checkpoint its state.
+#[derive(Clone, Debug, Default)]
+pub struct CheckpointNotifier {
+  notify: Arc<Notify>,
+}
+
+impl CheckpointNotifier {
+  /// Signals that the state has changed. Notifications sent while the stream
+  /// is busy are coalesced into one checkpoint after it finishes.
+  pub fn notify(&self) {
+    self.notify.notify_one();
+  }
+
+  async fn notified(&self) {
+    self.notify.notified().await
+  }
+}
+
+/// How to retry checkpoints which fail, e.g. because the disk is full. Each
+/// retry waits twice as long as the last, up to `max_backoff`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+  /// The number of times to retry a failed checkpoint before stopping the
+  /// stream.
+  pub max_retries: u32,
+  /// The time to wait before the first retry.
+  pub initial_backoff: Duration,
+  /// The longest time to wait between retries.
+  pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+  /// The time to wait before retry number `retry`, counting from 0, or `None`
+  /// if no retries are left.
+  fn backoff(&self, retry: u32) -> Option<Duration> {
+    (retry < self.max_retries).then(|| {
+      self
+        .initial_backoff
+        .saturating_mul(1 << retry.min(31))
+        .min(self.max_backoff)
+    })
+  }
+}
 
-  /// Don't love this API, think of better way (want to ser/de here, not in
-  /// impls).
-  fn recover(&mut self, increment_encoding: Vec<u8>) -> Result<(), Box<dyn ThreadSafeError>>;
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_retries: 8,
+      initial_backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(10),
+    }
+  }
 }
 
 pub struct CheckpointStreamOptions {
-  /// The amount of time to wait between successive incremental checkpoints.
-  pub poll_period: Duration,
+  /// What wakes the stream to checkpoint.
+  pub trigger: CheckpointTrigger,
+  /// When to compact the checkpoint.
+  pub compaction: CompactionPolicy,
+  /// When to sync checkpoints to disk.
+  pub durability: Durability,
+  /// How to retry failed checkpoints.
+  pub retry: RetryPolicy,
+  /// The file holding the key records are encrypted with, or `None` to write
+  /// records in plaintext.
+  pub key_file: Option<PathBuf>,
+  /// Files holding keys which records were previously encrypted with. These
+  /// are only used to read old records during recovery.
+  pub old_key_files: Vec<PathBuf>,
+  /// Accepts plaintext records during recovery even though `key_file` is
+  /// given, to encrypt a checkpoint written before encryption was enabled.
+  /// Otherwise they're rejected, since anyone who can write the checkpoint
+  /// could inject them. Only set for the one recovery that migrates.
+  pub migrate_plaintext: bool,
 }
 
 impl CheckpointStreamOptions {
-  pub fn build<W, S>(self, checkpoint_writer: W, state: Mutex<S>) -> CheckpointStream<W, S>
+  /// Builds a stream writing to `checkpoint_writer`, which is assumed to be
+  /// empty. Fails if `key_file` can't be read.
+  pub async fn build<W, S>(
+    self,
+    checkpoint_writer: W,
+    state: Arc<Mutex<S>>,
+  ) -> Result<CheckpointStream<W>, Box<dyn ThreadSafeError>>
+  where
+    W: CheckpointWriter + 'static,
+    S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+  {
+    self
+      .build_multiplexed(checkpoint_writer, CheckpointSources::single(state))
+      .await
+  }
+
+  /// Builds a stream checkpointing every state in `sources` to
+  /// `checkpoint_writer`, which is assumed to be empty.
+  pub async fn build_multiplexed<W>(
+    self,
+    checkpoint_writer: W,
+    sources: CheckpointSources,
+  ) -> Result<CheckpointStream<W>, Box<dyn ThreadSafeError>>
+  where
+    W: CheckpointWriter + 'static,
+  {
+    let (key, _) = self.load_keys().await?;
+    let (sources, _) = sources.into_parts();
+    Ok(CheckpointStream::from_options(
+      checkpoint_writer,
+      sources,
+      self,
+      key,
+    ))
+  }
+
+  /// Loads the key to encrypt records with, and a keyring of every key which
+  /// records may have been encrypted with.
+  async fn load_keys(&self) -> Result<(Option<CheckpointKey>, Keyring), Box<dyn ThreadSafeError>> {
+    let mut keyring = Keyring::new();
+    for path in &self.old_key_files {
+      keyring.add(CheckpointKey::from_file(path).await?);
+    }
+    let key = match &self.key_file {
+      Some(path) => {
+        let key = CheckpointKey::from_file(path).await?;
+        keyring.add(key.clone());
+        Some(key)
+      }
+      None => None,
+    };
+    // Without a key, records are written in plaintext, so plaintext records
+    // are expected.
+    if key.is_none() || self.migrate_plaintext {
+      keyring.accept_plaintext();
+    }
+    Ok((key, keyring))
+  }
+
+  /// Opens the checkpoint file at `path`, creating it if it doesn't exist, and
+  /// replays each of its records into `state` in order: the latest snapshot,
+  /// if the file has been compacted, followed by every increment since.
+  /// `state` should be freshly constructed.
+  ///
+  /// A partially written final record, as left by a crash mid-checkpoint, is
+  /// discarded and truncated from the file. Any other corruption is an error,
+  /// as is a record encrypted with a key that isn't in `key_file` or
+  /// `old_key_files`, or a plaintext record when `key_file` is given unless
+  /// `migrate_plaintext` is set. If any record isn't encrypted with the key
+  /// in `key_file`, including migrated plaintext records or encrypted ones
+  /// when no key is given, the checkpoint is compacted to re-snapshot it
+  /// under the current key. The returned stream appends to the same file.
+  pub async fn recover<S>(
+    self,
+    path: impl AsRef<Path>,
+    state: Arc<Mutex<S>>,
+  ) -> Result<Recovery, Box<dyn ThreadSafeError>>
   where
-    W: AsyncWrite + Unpin + Send + 'static,
-    S: IncrementalUpdate + Send + Sync + 'static,
+    S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
   {
-    CheckpointStream::from_options(checkpoint_writer, state, self)
+    self
+      .recover_multiplexed(path, CheckpointSources::single(state))
+      .await
+  }
+
+  /// Like `recover`, but routes each record to the source in `sources` it is
+  /// tagged with. A record for a source which isn't registered is an error.
+  /// Once every record is replayed, each source's recovery callback is
+  /// called.
+  pub async fn recover_multiplexed(
+    self,
+    path: impl AsRef<Path>,
+    sources: CheckpointSources,
+  ) -> Result<Recovery, Box<dyn ThreadSafeError>> {
+    let (sources, callbacks) = sources.into_parts();
+    let (key, keyring) = self.load_keys().await?;
+    let key_id = key.as_ref().map(CheckpointKey::id);
+    let mut checkpoint_file = CheckpointFile::open(path).await?;
+    let file_len = checkpoint_file.file().metadata().await?.len();
+
+    let mut records_applied = 0;
+    let mut increments_since_snapshot = 0;
+    let mut rekey = false;
+    let mut chain = RecordChain::new();
+    let valid_len = {
+      let mut states = LockedSources::lock(&sources).await;
+      let mut source_records_applied = vec![0; sources.len()];
+      let mut reader = RecordReader::new(BufReader::new(checkpoint_file.file_mut()));
+      let valid_len = loop {
+        match reader.read_record().await {
+          Ok(Some(payload)) => {
+            let (payload, record_key_id) = keyring.decrypt(payload, &mut chain)?;
+            rekey |= record_key_id != key_id;
+            let (source, kind) = states.restore(payload)?;
+            match kind {
+              RecordKind::Snapshot => increments_since_snapshot = 0,
+              RecordKind::Increment => increments_since_snapshot += 1,
+            }
+            source_records_applied[source] += 1;
+            records_applied += 1;
+          }
+          Ok(None) => break reader.offset(),
+          Err(RecordError::Truncated { offset }) => break offset,
+          Err(err) => return Err(err.into()),
+        }
+      };
+      states.recovered(callbacks, &source_records_applied);
+      valid_len
+    };
+
+    let discarded_bytes = (file_len - valid_len) as usize;
+    if discarded_bytes != 0 {
+      checkpoint_file.file_mut().set_len(valid_len).await?;
+    }
+
+    let mut stream = CheckpointStream::from_options(checkpoint_file, sources, self, key);
+    stream.increments_since_snapshot = increments_since_snapshot;
+    stream.checkpoint_len = valid_len;
+    stream.chain = chain;
+    if rekey {
+      stream.compact().await?;
+    }
+    Ok(Recovery {
+      stream,
+      records_applied,
+      discarded_bytes,
+      rekeyed: rekey,
+    })
   }
 }
 
+/// The result of recovering state from a checkpoint file.
+pub struct Recovery {
+  /// A stream which appends to the recovered checkpoint file.
+  pub stream: CheckpointStream<CheckpointFile>,
+  /// The number of records replayed into the state, including any snapshots.
+  pub records_applied: usize,
+  /// The length of the incomplete record truncated from the end of the file,
+  /// or 0 if the file ended with a complete record.
+  pub discarded_bytes: usize,
+  /// True if the checkpoint was re-snapshotted under a new key.
+  pub rekeyed: bool,
+}
+
 impl Default for CheckpointStreamOptions {
   fn default() -> Self {
     Self {
-      poll_period: Duration::from_secs(30),
+      trigger: CheckpointTrigger::Poll {
+        period: Duration::from_secs(30),
+      },
+      compaction: CompactionPolicy::default(),
+      durability: Durability::EveryCommit,
+      retry: RetryPolicy::default(),
+      key_file: None,
+      old_key_files: vec![],
+      migrate_plaintext: false,
     }
   }
 }
 
-pub struct CheckpointStream<W, S> {
-  checkpoint_writer: W,
-  state: Mutex<S>,
+/// Stops a started `CheckpointStream`. Dropping the handle leaves the stream
+/// running.
+pub struct CheckpointStreamHandle {
+  stop: CancellationToken,
+  task: JoinHandle<Result<(), Box<dyn ThreadSafeError>>>,
+}
+
+impl CheckpointStreamHandle {
+  /// Stops the stream, waiting for it to checkpoint any pending changes and
+  /// close its writer. Returns the error which stopped the stream, if it
+  /// already failed.
+  pub async fn stop(self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.stop.cancel();
+    self.task.await?
+  }
+}
+
+pub struct CheckpointStream<W> {
+  checkpoint_writer: RecordWriter<W>,
+  sources: Arc<[Source]>,
   options: CheckpointStreamOptions,
+  /// The number of increments written since the last snapshot.
+  increments_since_snapshot: usize,
+  /// The number of bytes written to `checkpoint_writer`.
+  checkpoint_len: u64,
+  /// When `checkpoint_writer` was last synced, or `None` if it never was.
+  last_sync: Option<Instant>,
+  /// True if checkpoints have been written since the last sync.
+  unsynced: bool,
+  sync_stats: Arc<Mutex<SyncStats>>,
+  notifier: CheckpointNotifier,
+  /// The payloads of committed increments which failed to write, in order.
+  unwritten: Vec<Vec<u8>>,
+  /// The key records are encrypted with, if any.
+  key: Option<CheckpointKey>,
+  /// The end of the checkpoint, which the next record is chained to.
+  chain: RecordChain,
 }
 
-impl<W, S> CheckpointStream<W, S>
+impl<W> CheckpointStream<W>
 where
-  W: AsyncWriteExt + Unpin + Send + 'static,
-  S: IncrementalUpdate + Send + Sync + 'static,
+  W: CheckpointWriter + 'static,
 {
-  pub fn new(checkpoint_writer: W, state: Mutex<S>) -> Self {
-    Self::from_options(checkpoint_writer, state, CheckpointStreamOptions::default())
+  pub fn new<S>(checkpoint_writer: W, state: Arc<Mutex<S>>) -> Self
+  where
+    S: IncrementalUpdate + DeserializeOwned + Send + Sync + 'static,
+  {
+    let (sources, _) = CheckpointSources::single(state).into_parts();
+    Self::from_options(
+      checkpoint_writer,
+      sources,
+      CheckpointStreamOptions::default(),
+      None,
+    )
   }
 
-  fn from_options(checkpoint_writer: W, state: Mutex<S>, options: CheckpointStreamOptions) -> Self {
+  fn from_options(
+    checkpoint_writer: W,
+    sources: Vec<Source>,
+    options: CheckpointStreamOptions,
+    key: Option<CheckpointKey>,
+  ) -> Self {
     Self {
-      checkpoint_writer,
-      state,
+      checkpoint_writer: RecordWriter::new(checkpoint_writer),
+      sources: sources.into(),
       options,
+      increments_since_snapshot: 0,
+      checkpoint_len: 0,
+      last_sync: None,
+      unsynced: false,
+      sync_stats: Arc::default(),
+      notifier: CheckpointNotifier::default(),
+      unwritten: vec![],
+      key,
+      chain: RecordChain::new(),
     }
   }
 
+  /// A handle to wake the stream when `state` changes. Notifications are
+  /// ignored unless the stream was built with `CheckpointTrigger::Notify`.
+  pub fn notifier(&self) -> CheckpointNotifier {
+    self.notifier.clone()
+  }
+
+  /// Statistics on the time spent syncing checkpoints, which remain updated
+  /// after the stream is started.
+  pub fn sync_stats(&self) -> Arc<Mutex<SyncStats>> {
+    self.sync_stats.clone()
+  }
+
   /// Starts a separate thread to run the checkpoint stream in. This will
-  /// periodically poll `state`, and update the checkpointed state whenever
-  /// there is a change.
-  pub fn start(mut self) -> JoinHandle<Result<(), Box<dyn ThreadSafeError>>> {
-    tokio::spawn(async move {
-      let mut timer = interval(self.options.poll_period);
-      timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
-
-      loop {
-        timer.tick().await;
-        self.tick().await?;
+  /// check every source for changes whenever `options.trigger` fires, and update
+  /// the checkpointed state whenever there is a change. The stream runs until
+  /// stopped through the returned handle, or until a checkpoint fails more
+  /// times than `options.retry` allows.
+  pub fn start(mut self) -> CheckpointStreamHandle {
+    let stop = CancellationToken::new();
+    let task = tokio::spawn({
+      let stop = stop.clone();
+      async move {
+        match self.options.trigger {
+          CheckpointTrigger::Poll { period } => self.run_polling(period, &stop).await?,
+          CheckpointTrigger::Notify {
+            debounce,
+            max_latency,
+          } => self.run_notified(debounce, max_latency, &stop).await?,
+        }
+        self.finish().await
       }
-    })
+    });
+    CheckpointStreamHandle { stop, task }
+  }
+
+  async fn run_polling(
+    &mut self,
+    period: Duration,
+    stop: &CancellationToken,
+  ) -> Result<(), Box<dyn ThreadSafeError>> {
+    let mut timer = interval(period);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+      tokio::select! {
+        _ = timer.tick() => {}
+        _ = stop.cancelled() => return Ok(()),
+      }
+      self.tick_with_retries().await?;
+    }
+  }
+
+  async fn run_notified(
+    &mut self,
+    debounce: Option<Duration>,
+    max_latency: Option<Duration>,
+    stop: &CancellationToken,
+  ) -> Result<(), Box<dyn ThreadSafeError>> {
+    let notifier = self.notifier.clone();
+    loop {
+      // Checkpoints left unsynced by grouped durability still need to be
+      // synced if no further notifications arrive.
+      let sync_deadline = self.sync_deadline();
+      tokio::select! {
+        _ = notifier.notified() => {
+          if let Some(debounce) = debounce {
+            tokio::select! {
+              _ = Self::debounce(&notifier, debounce, max_latency) => {}
+              _ = stop.cancelled() => return Ok(()),
+            }
+          }
+        }
+        _ = sleep_until(sync_deadline.unwrap_or_else(Instant::now)), if sync_deadline.is_some() => {}
+        _ = stop.cancelled() => return Ok(()),
+      }
+      self.tick_with_retries().await?;
+    }
+  }
+
+  /// Waits until no notification has arrived for `debounce`, or until
+  /// `max_latency` has passed.
+  async fn debounce(
+    notifier: &CheckpointNotifier,
+    debounce: Duration,
+    max_latency: Option<Duration>,
+  ) {
+    let deadline = max_latency.map(|max_latency| Instant::now() + max_latency);
+    loop {
+      let quiet_deadline = Instant::now() + debounce;
+      let wake = match deadline {
+        Some(deadline) => deadline.min(quiet_deadline),
+        None => quiet_deadline,
+      };
+      tokio::select! {
+        _ = notifier.notified() => {}
+        _ = sleep_until(wake) => return,
+      }
+      if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return;
+      }
+    }
+  }
+
+  /// When written checkpoints will next need syncing, if any are unsynced.
+  fn sync_deadline(&self) -> Option<Instant> {
+    match self.options.durability {
+      Durability::Grouped { interval } if self.unsynced => Some(
+        self
+          .last_sync
+          .map_or_else(Instant::now, |last_sync| last_sync + interval),
+      ),
+      _ => None,
+    }
+  }
+
+  /// Checkpoints any pending changes, syncs them unless durability is
+  /// `Durability::None`, and shuts down the writer.
+  async fn finish(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.tick_with_retries().await?;
+    if self.unsynced && self.options.durability != Durability::None {
+      self.sync(Instant::now()).await?;
+    }
+    self.checkpoint_writer.get_mut().shutdown().await?;
+    Ok(())
+  }
+
+  /// Ticks, retrying with backoff according to `options.retry` on failure.
+  async fn tick_with_retries(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let mut retries = 0;
+    loop {
+      let err = match self.tick().await {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+      };
+      let Some(backoff) = self.options.retry.backoff(retries) else {
+        return Err(err);
+      };
+      println!("Checkpoint failed, retrying in {backoff:?}: {err}");
+      sleep(backoff).await;
+      retries += 1;
+    }
   }
 
   async fn tick(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
-    let mut guard = self.state.lock().await;
-    let state = guard.deref_mut();
+    let sources = self.sources.clone();
+    let mut states = LockedSources::lock(&sources).await;
+
+    // Increments which failed to write are retried before any newer ones, so
+    // increments are always written in order.
+    if self.unwritten.is_empty() {
+      self.unwritten = states.commit()?;
+    }
+
+    if !self.unwritten.is_empty() {
+      let mut unwritten = mem::take(&mut self.unwritten);
+      for i in 0..unwritten.len() {
+        if let Err(err) = self.write_increment(&unwritten[i]).await {
+          self.unwritten = unwritten.split_off(i);
+          // Drop whatever part of the record was written, so the retry
+          // doesn't follow a torn record.
+          self
+            .checkpoint_writer
+            .get_mut()
+            .truncate(self.checkpoint_len)
+            .await?;
+          return Err(err);
+        }
+      }
+
+      if self
+        .options
+        .compaction
+        .should_compact(self.increments_since_snapshot, self.checkpoint_len)
+      {
+        self.write_snapshot(&states).await?;
+      }
+    }
+
+    let now = Instant::now();
+    if self.unsynced && self.options.durability.should_sync(self.last_sync, now) {
+      self.sync(now).await?;
+    }
+
+    Ok(())
+  }
+
+  async fn write_increment(&mut self, payload: &[u8]) -> Result<(), Box<dyn ThreadSafeError>> {
+    // The chain only moves past the record once it's written, so a retry is
+    // chained to the same record.
+    let mut chain = self.chain.clone();
+    let payload = self.seal(payload, &mut chain);
+    self.checkpoint_writer.write_record(&payload).await?;
+    self.checkpoint_writer.flush().await?;
+    self.increments_since_snapshot += 1;
+    self.checkpoint_len += (RECORD_HEADER_LEN + payload.len()) as u64;
+    self.chain = chain;
+    self.unsynced = true;
+    Ok(())
+  }
+
+  async fn sync(&mut self, now: Instant) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.checkpoint_writer.get_mut().sync().await?;
+    self.sync_stats.lock().await.record(now.elapsed());
+    self.last_sync = Some(now);
+    self.unsynced = false;
+    Ok(())
+  }
+
+  /// Commits any pending increments and replaces the checkpoint with a
+  /// snapshot of the full state of every source.
+  pub async fn compact(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let sources = self.sources.clone();
+    let mut states = LockedSources::lock(&sources).await;
+    states.discard_updates()?;
+    // The snapshot includes any increments which failed to write.
+    self.unwritten.clear();
+    self.write_snapshot(&states).await
+  }
 
-    if state.has_update() {
-      // let mut encoding = bincode::serialize(&state)?;
-      let mut encoding = state.commit()?;
-      self.checkpoint_writer.write_all(&mut encoding).await?;
-      self.checkpoint_writer.flush().await?;
+  /// Encrypts the payload of the record at `chain` if the stream has a key.
+  fn seal(&self, payload: &[u8], chain: &mut RecordChain) -> Vec<u8> {
+    match &self.key {
+      Some(key) => key.encrypt(payload, chain),
+      None => payload.to_vec(),
     }
+  }
 
+  async fn write_snapshot(
+    &mut self,
+    states: &LockedSources<'_>,
+  ) -> Result<(), Box<dyn ThreadSafeError>> {
+    let mut chain = RecordChain::new();
+    let contents: Vec<u8> = states
+      .snapshot()?
+      .into_iter()
+      .flat_map(|payload| encode_record(&self.seal(&payload, &mut chain)))
+      .collect();
+    self
+      .checkpoint_writer
+      .get_mut()
+      .replace_contents(&contents)
+      .await?;
+    self.increments_since_snapshot = 0;
+    self.checkpoint_len = contents.len() as u64;
+    self.chain = chain;
+    self.unsynced = false;
     Ok(())
   }
 }
+
+#[cfg(test)]
+mod test {
+  use std::{
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+  };
+
+  use async_trait::async_trait;
+  use serde::{Deserialize, Serialize};
+  use tempfile::{NamedTempFile, TempDir};
+  use tokio::{io::AsyncWrite, sync::Mutex, task, time};
+
+  use crate::{
+    checkpoint_codec::BincodeCodec,
+    checkpoint_crypto::CryptoError,
+    checkpoint_file::CheckpointWriter,
+    checkpoint_migration::{Migration, MigrationRegistry},
+    checkpoint_record::encode_record,
+    error::ThreadSafeError,
+  };
+
+  use super::{
+    CheckpointStreamOptions, CheckpointTrigger, CompactionPolicy, Durability, IncrementalUpdate,
+    RecordKind, RetryPolicy,
+  };
+
+  /// A log of strings, whose increments are the strings appended since the
+  /// last commit.
+  #[derive(Default, Serialize, Deserialize)]
+  struct Log {
+    entries: Vec<String>,
+    #[serde(skip)]
+    pending: Vec<String>,
+  }
+
+  impl Log {
+    fn append(&mut self, entry: &str) {
+      self.entries.push(entry.to_owned());
+      self.pending.push(entry.to_owned());
+    }
+  }
+
+  impl IncrementalUpdate for Log {
+    type Increment = Vec<String>;
+    type Codec = BincodeCodec;
+
+    fn has_update(&self) -> bool {
+      !self.pending.is_empty()
+    }
+
+    fn commit(&mut self) -> Vec<String> {
+      std::mem::take(&mut self.pending)
+    }
+
+    fn apply(&mut self, increment: Vec<String>) {
+      self.entries.extend(increment);
+    }
+  }
+
+  /// A writer whose first `failures` writes each write half of their buffer
+  /// and then fail.
+  #[derive(Default)]
+  struct FlakyWriter {
+    contents: Arc<std::sync::Mutex<Vec<u8>>>,
+    failures: usize,
+    partially_written: bool,
+  }
+
+  impl AsyncWrite for FlakyWriter {
+    fn poll_write(
+      mut self: Pin<&mut Self>,
+      _cx: &mut Context<'_>,
+      buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+      if self.failures == 0 {
+        self.contents.lock().unwrap().extend(buf);
+        Poll::Ready(Ok(buf.len()))
+      } else if !self.partially_written {
+        self.partially_written = true;
+        self.contents.lock().unwrap().extend(&buf[..buf.len() / 2]);
+        Poll::Ready(Ok(buf.len() / 2))
+      } else {
+        self.partially_written = false;
+        self.failures -= 1;
+        Poll::Ready(Err(io::Error::other("disk full")))
+      }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  #[async_trait]
+  impl CheckpointWriter for FlakyWriter {
+    async fn replace_contents(&mut self, contents: &[u8]) -> io::Result<()> {
+      *self.contents.lock().unwrap() = contents.to_vec();
+      Ok(())
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+
+    async fn truncate(&mut self, len: u64) -> io::Result<()> {
+      self.contents.lock().unwrap().truncate(len as usize);
+      Ok(())
+    }
+  }
+
+  /// A `Log` whose second schema version upper-cases every entry written at
+  /// the first.
+  #[derive(Default, Serialize, Deserialize)]
+  #[serde(transparent)]
+  struct LoudLog(Log);
+
+  fn shout(body: Vec<u8>) -> Result<Vec<u8>, Box<dyn ThreadSafeError>> {
+    let entries: Vec<String> = bincode::deserialize(&body)?;
+    let entries: Vec<_> = entries.iter().map(|entry| entry.to_uppercase()).collect();
+    Ok(bincode::serialize(&entries)?)
+  }
+
+  impl IncrementalUpdate for LoudLog {
+    type Increment = Vec<String>;
+    type Codec = BincodeCodec;
+
+    fn has_update(&self) -> bool {
+      self.0.has_update()
+    }
+
+    fn commit(&mut self) -> Vec<String> {
+      self.0.commit()
+    }
+
+    fn apply(&mut self, increment: Vec<String>) {
+      self.0.apply(increment)
+    }
+
+    fn migrations() -> MigrationRegistry {
+      MigrationRegistry::new().with_migration(Migration {
+        from_version: 1,
+        snapshot: shout,
+        increment: shout,
+      })
+    }
+  }
+
+  fn increment_record(entries: &[&str]) -> Vec<u8> {
+    encode_record(&RecordKind::Increment.encode(1, &bincode::serialize(entries).unwrap()))
+  }
+
+  fn snapshot_record(entries: &[&str]) -> Vec<u8> {
+    let log = Log {
+      entries: entries.iter().map(|entry| entry.to_string()).collect(),
+      pending: vec![],
+    };
+    encode_record(&RecordKind::Snapshot.encode(1, &bincode::serialize(&log).unwrap()))
+  }
+
+  fn compacting_options(compaction: CompactionPolicy) -> CheckpointStreamOptions {
+    CheckpointStreamOptions {
+      compaction,
+      ..CheckpointStreamOptions::default()
+    }
+  }
+
+  async fn recover_log(file: &NamedTempFile) -> (Vec<String>, usize, usize) {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let recovery = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap();
+    let entries = log.lock().await.entries.clone();
+    (entries, recovery.records_applied, recovery.discarded_bytes)
+  }
+
+  #[tokio::test]
+  async fn test_recover_empty() {
+    let file = NamedTempFile::new().unwrap();
+    assert_eq!(recover_log(&file).await, (vec![], 0, 0));
+  }
+
+  #[tokio::test]
+  async fn test_recover_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("checkpoint");
+    let log = Arc::new(Mutex::new(Log::default()));
+    let recovery = CheckpointStreamOptions::default()
+      .recover(&path, log)
+      .await
+      .unwrap();
+    assert_eq!(recovery.records_applied, 0);
+    assert!(path.exists());
+  }
+
+  #[tokio::test]
+  async fn test_recover_in_order() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+
+    log.lock().await.append("a");
+    log.lock().await.append("b");
+    stream.tick().await.unwrap();
+    stream.tick().await.unwrap();
+    log.lock().await.append("c");
+    stream.tick().await.unwrap();
+
+    assert_eq!(
+      recover_log(&file).await,
+      (vec!["a".to_owned(), "b".to_owned(), "c".to_owned()], 2, 0)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recovered_stream_appends() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    log.lock().await.append("a");
+    stream.tick().await.unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    log.lock().await.append("b");
+    stream.tick().await.unwrap();
+
+    assert_eq!(
+      recover_log(&file).await,
+      (vec!["a".to_owned(), "b".to_owned()], 2, 0)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recover_truncated_record() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = increment_record(&["a"]);
+    let torn_record = increment_record(&["b"]);
+    checkpoint.extend(&torn_record[..torn_record.len() - 1]);
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    assert_eq!(
+      recover_log(&file).await,
+      (vec!["a".to_owned()], 1, torn_record.len() - 1)
+    );
+    // The torn record should have been truncated from the file.
+    assert_eq!(recover_log(&file).await, (vec!["a".to_owned()], 1, 0));
+  }
+
+  #[tokio::test]
+  async fn test_recover_truncated_header() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = increment_record(&["a"]);
+    checkpoint.extend([1, 0]);
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    assert_eq!(recover_log(&file).await, (vec!["a".to_owned()], 1, 2));
+  }
+
+  #[tokio::test]
+  async fn test_recover_corrupt_record_fails() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = increment_record(&["a"]);
+    let last = checkpoint.len() - 1;
+    checkpoint[last] ^= 1;
+    checkpoint.extend(increment_record(&["b"]));
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    assert!(CheckpointStreamOptions::default()
+      .recover(file.path(), log)
+      .await
+      .is_err());
+    // The file should be left untouched for inspection.
+    assert_eq!(std::fs::read(file.path()).unwrap(), checkpoint);
+  }
+
+  #[tokio::test]
+  async fn test_append_after_truncated_record() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = increment_record(&["a"]);
+    checkpoint.extend([1, 0]);
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    log.lock().await.append("b");
+    stream.tick().await.unwrap();
+
+    assert_eq!(
+      recover_log(&file).await,
+      (vec!["a".to_owned(), "b".to_owned()], 2, 0)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recover_snapshot_and_tail() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = snapshot_record(&["a", "b"]);
+    checkpoint.extend(increment_record(&["c"]));
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    assert_eq!(
+      recover_log(&file).await,
+      (vec!["a".to_owned(), "b".to_owned(), "c".to_owned()], 2, 0)
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recover_malformed_increment_fails() {
+    let file = NamedTempFile::new().unwrap();
+    std::fs::write(
+      file.path(),
+      encode_record(&RecordKind::Increment.encode(1, &[1, 2])),
+    )
+    .unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let err = CheckpointStreamOptions::default()
+      .recover(file.path(), log)
+      .await
+      .err()
+      .unwrap();
+    assert!(err.to_string().starts_with("Malformed bincode encoding"));
+  }
+
+  #[tokio::test]
+  async fn test_recover_migrates_old_records() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = snapshot_record(&["a"]);
+    checkpoint.extend(increment_record(&["b"]));
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    let log = Arc::new(Mutex::new(LoudLog::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    assert_eq!(log.lock().await.0.entries, vec!["A", "B"]);
+
+    // New records are written at the current version, so aren't migrated.
+    log.lock().await.0.append("c");
+    stream.tick().await.unwrap();
+    let log = Arc::new(Mutex::new(LoudLog::default()));
+    CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap();
+    assert_eq!(log.lock().await.0.entries, vec!["A", "B", "c"]);
+  }
+
+  #[tokio::test]
+  async fn test_recover_newer_schema_version_fails() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(LoudLog::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    log.lock().await.0.append("a");
+    stream.tick().await.unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    assert!(CheckpointStreamOptions::default()
+      .recover(file.path(), log)
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_recover_unknown_record_kind_fails() {
+    let file = NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), encode_record(&[7, 1, 0, 0, 0])).unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    assert!(CheckpointStreamOptions::default()
+      .recover(file.path(), log)
+      .await
+      .is_err());
+  }
+
+  /// Writes a key file for key `id`, whose bytes are all `byte`, to `dir`.
+  fn key_file(dir: &TempDir, id: u32, byte: u8) -> PathBuf {
+    let path = dir.path().join(format!("key-{id}-{byte}"));
+    std::fs::write(
+      &path,
+      format!("{id} {}\n", format!("{byte:02x}").repeat(32)),
+    )
+    .unwrap();
+    path
+  }
+
+  fn keyed_options(key_file: &Path, old_key_files: &[&Path]) -> CheckpointStreamOptions {
+    CheckpointStreamOptions {
+      key_file: Some(key_file.to_owned()),
+      old_key_files: old_key_files
+        .iter()
+        .map(|path| path.to_path_buf())
+        .collect(),
+      ..CheckpointStreamOptions::default()
+    }
+  }
+
+  /// Recovers a log from `path` with `options` and appends `entries`.
+  async fn append_log(
+    options: CheckpointStreamOptions,
+    path: &Path,
+    entries: &[&str],
+  ) -> Result<Vec<String>, Box<dyn ThreadSafeError>> {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = options.recover(path, log.clone()).await?.stream;
+    for entry in entries {
+      log.lock().await.append(entry);
+      stream.tick().await?;
+    }
+    let entries = log.lock().await.entries.clone();
+    Ok(entries)
+  }
+
+  #[tokio::test]
+  async fn test_encrypted_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let key = key_file(&dir, 1, 0xaa);
+    let path = dir.path().join("checkpoint");
+
+    append_log(keyed_options(&key, &[]), &path, &["secret"])
+      .await
+      .unwrap();
+    assert!(!String::from_utf8_lossy(&std::fs::read(&path).unwrap()).contains("secret"));
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let recovery = keyed_options(&key, &[])
+      .recover(&path, log.clone())
+      .await
+      .unwrap();
+    assert_eq!(log.lock().await.entries, vec!["secret"]);
+    assert_eq!(recovery.records_applied, 1);
+    assert!(!recovery.rekeyed);
+  }
+
+  #[tokio::test]
+  async fn test_encrypted_snapshot() {
+    let dir = TempDir::new().unwrap();
+    let key = key_file(&dir, 1, 0xaa);
+    let path = dir.path().join("checkpoint");
+    let options = CheckpointStreamOptions {
+      compaction: CompactionPolicy {
+        max_increments: Some(1),
+        max_bytes: None,
+      },
+      ..keyed_options(&key, &[])
+    };
+
+    append_log(options, &path, &["a", "b", "c"]).await.unwrap();
+    assert_eq!(
+      append_log(keyed_options(&key, &[]), &path, &[])
+        .await
+        .unwrap(),
+      vec!["a", "b", "c"]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_dropped_encrypted_record_detected() {
+    let dir = TempDir::new().unwrap();
+    let key = key_file(&dir, 1, 0xaa);
+    let path = dir.path().join("checkpoint");
+    append_log(keyed_options(&key, &[]), &path, &["a", "b"])
+      .await
+      .unwrap();
+    // A recovered stream carries on the chain.
+    assert_eq!(
+      append_log(keyed_options(&key, &[]), &path, &["c"])
+        .await
+        .unwrap(),
+      vec!["a", "b", "c"]
+    );
+
+    // Every record is the same length, so the middle one is easy to cut out.
+    let checkpoint = std::fs::read(&path).unwrap();
+    let record_len = checkpoint.len() / 3;
+    let dropped = [&checkpoint[..record_len], &checkpoint[2 * record_len..]].concat();
+    std::fs::write(&path, dropped).unwrap();
+    let err = append_log(keyed_options(&key, &[]), &path, &[])
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      CryptoError::WrongKey { key_id: 1 }.to_string()
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recover_with_wrong_key_fails() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("checkpoint");
+    append_log(keyed_options(&key_file(&dir, 1, 0xaa), &[]), &path, &["a"])
+      .await
+      .unwrap();
+    let checkpoint = std::fs::read(&path).unwrap();
+
+    let err = append_log(keyed_options(&key_file(&dir, 1, 0xbb), &[]), &path, &[])
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      CryptoError::WrongKey { key_id: 1 }.to_string()
+    );
+    // The checkpoint is left untouched.
+    assert_eq!(std::fs::read(&path).unwrap(), checkpoint);
+  }
+
+  #[tokio::test]
+  async fn test_recover_without_old_key_fails() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("checkpoint");
+    append_log(keyed_options(&key_file(&dir, 1, 0xaa), &[]), &path, &["a"])
+      .await
+      .unwrap();
+
+    let err = append_log(keyed_options(&key_file(&dir, 2, 0xbb), &[]), &path, &[])
+      .await
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      CryptoError::MissingKey { key_id: 1 }.to_string()
+    );
+    assert!(append_log(CheckpointStreamOptions::default(), &path, &[])
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn test_recover_rotates_key() {
+    let dir = TempDir::new().unwrap();
+    let old_key = key_file(&dir, 1, 0xaa);
+    let new_key = key_file(&dir, 2, 0xbb);
+    let path = dir.path().join("checkpoint");
+    append_log(keyed_options(&old_key, &[]), &path, &["a", "b"])
+      .await
+      .unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut recovery = keyed_options(&new_key, &[&old_key])
+      .recover(&path, log.clone())
+      .await
+      .unwrap();
+    assert!(recovery.rekeyed);
+    log.lock().await.append("c");
+    recovery.stream.tick().await.unwrap();
+
+    // Nothing is left encrypted with the old key.
+    assert_eq!(
+      append_log(keyed_options(&new_key, &[]), &path, &[])
+        .await
+        .unwrap(),
+      vec!["a", "b", "c"]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recover_encrypts_plaintext() {
+    let dir = TempDir::new().unwrap();
+    let key = key_file(&dir, 1, 0xaa);
+    let path = dir.path().join("checkpoint");
+    std::fs::write(
+      &path,
+      [increment_record(&["a"]), increment_record(&["b"])].concat(),
+    )
+    .unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let recovery = CheckpointStreamOptions {
+      migrate_plaintext: true,
+      ..keyed_options(&key, &[])
+    }
+    .recover(&path, log.clone())
+    .await
+    .unwrap();
+    assert!(recovery.rekeyed);
+    assert!(append_log(CheckpointStreamOptions::default(), &path, &[])
+      .await
+      .is_err());
+    assert_eq!(
+      append_log(keyed_options(&key, &[]), &path, &[])
+        .await
+        .unwrap(),
+      vec!["a", "b"]
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recover_rejects_plaintext_without_migration() {
+    let dir = TempDir::new().unwrap();
+    let key = key_file(&dir, 1, 0xaa);
+    let path = dir.path().join("checkpoint");
+    std::fs::write(&path, increment_record(&["a"])).unwrap();
+
+    let err = append_log(keyed_options(&key, &[]), &path, &[])
+      .await
+      .unwrap_err();
+    assert_eq!(err.to_string(), CryptoError::Unencrypted.to_string());
+  }
+
+  #[tokio::test]
+  async fn test_recover_rejects_injected_plaintext() {
+    let dir = TempDir::new().unwrap();
+    let key = key_file(&dir, 1, 0xaa);
+    let path = dir.path().join("checkpoint");
+    append_log(keyed_options(&key, &[]), &path, &["a"])
+      .await
+      .unwrap();
+    let mut checkpoint = std::fs::read(&path).unwrap();
+    checkpoint.extend(increment_record(&["injected"]));
+    std::fs::write(&path, &checkpoint).unwrap();
+
+    let err = append_log(keyed_options(&key, &[]), &path, &[])
+      .await
+      .unwrap_err();
+    assert_eq!(err.to_string(), CryptoError::Unencrypted.to_string());
+    // The checkpoint is left untouched.
+    assert_eq!(std::fs::read(&path).unwrap(), checkpoint);
+  }
+
+  #[tokio::test]
+  async fn test_invalid_key_file_fails() {
+    let dir = TempDir::new().unwrap();
+    let key = dir.path().join("key");
+    std::fs::write(&key, "1 abc").unwrap();
+    let path = dir.path().join("checkpoint");
+
+    let err = append_log(keyed_options(&key, &[]), &path, &[])
+      .await
+      .unwrap_err();
+    assert!(err.to_string().contains("Invalid checkpoint key file"));
+  }
+
+  #[tokio::test]
+  async fn test_compact_after_max_increments() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(Log::default()));
+    let options = compacting_options(CompactionPolicy {
+      max_increments: Some(2),
+      max_bytes: None,
+    });
+    let mut stream = options
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+
+    for entry in ["a", "b"] {
+      log.lock().await.append(entry);
+      stream.tick().await.unwrap();
+    }
+    assert_eq!(recover_log(&file).await.1, 2);
+
+    log.lock().await.append("c");
+    stream.tick().await.unwrap();
+    assert_eq!(
+      std::fs::read(file.path()).unwrap(),
+      snapshot_record(&["a", "b", "c"])
+    );
+
+    log.lock().await.append("d");
+    stream.tick().await.unwrap();
+    assert_eq!(
+      recover_log(&file).await,
+      (
+        vec![
+          "a".to_owned(),
+          "b".to_owned(),
+          "c".to_owned(),
+          "d".to_owned()
+        ],
+        2,
+        0
+      )
+    );
+  }
+
+  #[tokio::test]
+  async fn test_compact_after_max_bytes() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(Log::default()));
+    let max_bytes = increment_record(&["a"]).len() as u64 * 2;
+    let options = compacting_options(CompactionPolicy {
+      max_increments: None,
+      max_bytes: Some(max_bytes),
+    });
+    let mut stream = options
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+
+    for entry in ["a", "b"] {
+      log.lock().await.append(entry);
+      stream.tick().await.unwrap();
+    }
+    assert_eq!(recover_log(&file).await.1, 2);
+
+    log.lock().await.append("c");
+    stream.tick().await.unwrap();
+    assert_eq!(
+      std::fs::read(file.path()).unwrap(),
+      snapshot_record(&["a", "b", "c"])
+    );
+  }
+
+  #[tokio::test]
+  async fn test_recovered_stream_counts_tail() {
+    let file = NamedTempFile::new().unwrap();
+    let mut checkpoint = snapshot_record(&["a"]);
+    checkpoint.extend(increment_record(&["b"]));
+    std::fs::write(file.path(), &checkpoint).unwrap();
+
+    let log = Arc::new(Mutex::new(Log::default()));
+    let options = compacting_options(CompactionPolicy {
+      max_increments: Some(1),
+      max_bytes: None,
+    });
+    let mut stream = options
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    log.lock().await.append("c");
+    stream.tick().await.unwrap();
+
+    assert_eq!(
+      std::fs::read(file.path()).unwrap(),
+      snapshot_record(&["a", "b", "c"])
+    );
+  }
+
+  #[tokio::test]
+  async fn test_compact_commits_pending() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions::default()
+      .recover(file.path(), log.clone())
+      .await
+      .unwrap()
+      .stream;
+    log.lock().await.append("a");
+    stream.tick().await.unwrap();
+    log.lock().await.append("b");
+    stream.compact().await.unwrap();
+
+    assert!(!log.lock().await.has_update());
+    assert_eq!(
+      recover_log(&file).await,
+      (vec!["a".to_owned(), "b".to_owned()], 1, 0)
+    );
+  }
+
+  async fn sync_count(durability: Durability, ticks: &[(Duration, bool)]) -> u64 {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions {
+      durability,
+      ..CheckpointStreamOptions::default()
+    }
+    .build(Vec::new(), log.clone())
+    .await
+    .unwrap();
+
+    for &(delay, update) in ticks {
+      time::advance(delay).await;
+      if update {
+        log.lock().await.append("a");
+      }
+      stream.tick().await.unwrap();
+    }
+    let syncs = stream.sync_stats().lock().await.syncs;
+    syncs
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_no_durability_never_syncs() {
+    assert_eq!(
+      sync_count(Durability::None, &[(Duration::ZERO, true); 3]).await,
+      0
+    );
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_sync_every_commit() {
+    let ticks = [
+      (Duration::ZERO, true),
+      (Duration::ZERO, true),
+      (Duration::ZERO, false),
+    ];
+    assert_eq!(sync_count(Durability::EveryCommit, &ticks).await, 2);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_grouped_sync() {
+    let durability = Durability::Grouped {
+      interval: Duration::from_millis(100),
+    };
+    let ticks = [
+      (Duration::ZERO, true),
+      // Within the interval, so not synced yet.
+      (Duration::from_millis(10), true),
+      (Duration::from_millis(10), true),
+      // Nothing new, but the earlier checkpoints are now due.
+      (Duration::from_millis(80), false),
+      // Nothing written since the last sync.
+      (Duration::from_millis(200), false),
+    ];
+    assert_eq!(sync_count(durability, &ticks).await, 2);
+  }
+
+  #[tokio::test]
+  async fn test_compaction_is_not_synced_again() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let mut stream = CheckpointStreamOptions {
+      compaction: CompactionPolicy {
+        max_increments: Some(0),
+        max_bytes: None,
+      },
+      ..CheckpointStreamOptions::default()
+    }
+    .build(Vec::new(), log.clone())
+    .await
+    .unwrap();
+
+    log.lock().await.append("a");
+    stream.tick().await.unwrap();
+    assert_eq!(stream.sync_stats().lock().await.syncs, 0);
+  }
+
+  /// Lets spawned streams run, then advances paused time by `duration` and
+  /// lets them run again.
+  async fn advance(duration: Duration) {
+    for _ in 0..10 {
+      task::yield_now().await;
+    }
+    time::advance(duration).await;
+    for _ in 0..10 {
+      task::yield_now().await;
+    }
+  }
+
+  /// Starts a stream over a fresh log with `trigger`, returning the log and a
+  /// function which appends to it and notifies the stream.
+  async fn start_log(trigger: CheckpointTrigger) -> (Arc<Mutex<Log>>, impl Fn(&mut Log)) {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let stream = CheckpointStreamOptions {
+      trigger,
+      ..CheckpointStreamOptions::default()
+    }
+    .build(Vec::new(), log.clone())
+    .await
+    .unwrap();
+    let notifier = stream.notifier();
+    stream.start();
+    (log, move |log: &mut Log| {
+      log.append("a");
+      notifier.notify();
+    })
+  }
+
+  fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_poll() {
+    let (log, _) = start_log(CheckpointTrigger::Poll { period: ms(1000) }).await;
+    advance(ms(0)).await;
+
+    log.lock().await.append("a");
+    advance(ms(900)).await;
+    assert!(log.lock().await.has_update());
+    advance(ms(100)).await;
+    assert!(!log.lock().await.has_update());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_notify_checkpoints_immediately() {
+    let (log, append) = start_log(CheckpointTrigger::Notify {
+      debounce: None,
+      max_latency: None,
+    })
+    .await;
+
+    append(&mut *log.lock().await);
+    advance(ms(0)).await;
+    assert!(!log.lock().await.has_update());
+
+    append(&mut *log.lock().await);
+    advance(ms(0)).await;
+    assert!(!log.lock().await.has_update());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_notify_ignores_unnotified_changes() {
+    let (log, _) = start_log(CheckpointTrigger::Notify {
+      debounce: None,
+      max_latency: None,
+    })
+    .await;
+
+    log.lock().await.append("a");
+    advance(Duration::from_secs(3600)).await;
+    assert!(log.lock().await.has_update());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_notify_debounce() {
+    let (log, append) = start_log(CheckpointTrigger::Notify {
+      debounce: Some(ms(100)),
+      max_latency: None,
+    })
+    .await;
+
+    append(&mut *log.lock().await);
+    advance(ms(50)).await;
+    append(&mut *log.lock().await);
+    advance(ms(90)).await;
+    // Only 90ms have passed since the last notification.
+    assert!(log.lock().await.has_update());
+    advance(ms(20)).await;
+    assert!(!log.lock().await.has_update());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_notify_max_latency() {
+    let (log, append) = start_log(CheckpointTrigger::Notify {
+      debounce: Some(ms(100)),
+      max_latency: Some(ms(150)),
+    })
+    .await;
+
+    for _ in 0..2 {
+      append(&mut *log.lock().await);
+      advance(ms(50)).await;
+    }
+    assert!(log.lock().await.has_update());
+    append(&mut *log.lock().await);
+    advance(ms(50)).await;
+    // Notifications never stopped for 100ms, but the first was 150ms ago.
+    assert!(!log.lock().await.has_update());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_notify_syncs_grouped_checkpoints() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let stream = CheckpointStreamOptions {
+      trigger: CheckpointTrigger::Notify {
+        debounce: None,
+        max_latency: None,
+      },
+      durability: Durability::Grouped { interval: ms(100) },
+      ..CheckpointStreamOptions::default()
+    }
+    .build(Vec::new(), log.clone())
+    .await
+    .unwrap();
+    let notifier = stream.notifier();
+    let sync_stats = stream.sync_stats();
+    stream.start();
+
+    for _ in 0..2 {
+      log.lock().await.append("a");
+      notifier.notify();
+      advance(ms(10)).await;
+    }
+    assert_eq!(sync_stats.lock().await.syncs, 1);
+    advance(ms(100)).await;
+    assert_eq!(sync_stats.lock().await.syncs, 2);
+  }
+
+  #[tokio::test]
+  async fn test_stop_checkpoints_pending() {
+    let file = NamedTempFile::new().unwrap();
+    let log = Arc::new(Mutex::new(Log::default()));
+    let stream = CheckpointStreamOptions {
+      trigger: CheckpointTrigger::Poll {
+        period: Duration::from_secs(3600),
+      },
+      ..CheckpointStreamOptions::default()
+    }
+    .recover(file.path(), log.clone())
+    .await
+    .unwrap()
+    .stream;
+    let handle = stream.start();
+
+    log.lock().await.append("a");
+    handle.stop().await.unwrap();
+    assert!(!log.lock().await.has_update());
+    assert_eq!(recover_log(&file).await, (vec!["a".to_owned()], 1, 0));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_stop_while_debouncing() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let stream = CheckpointStreamOptions {
+      trigger: CheckpointTrigger::Notify {
+        debounce: Some(Duration::from_secs(3600)),
+        max_latency: None,
+      },
+      ..CheckpointStreamOptions::default()
+    }
+    .build(Vec::new(), log.clone())
+    .await
+    .unwrap();
+    let notifier = stream.notifier();
+    let handle = stream.start();
+
+    log.lock().await.append("a");
+    notifier.notify();
+    advance(ms(10)).await;
+    assert!(log.lock().await.has_update());
+    handle.stop().await.unwrap();
+    assert!(!log.lock().await.has_update());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_retry_after_partial_write() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let writer = FlakyWriter {
+      failures: 2,
+      ..FlakyWriter::default()
+    };
+    let contents = writer.contents.clone();
+    let mut stream = CheckpointStreamOptions::default()
+      .build(writer, log.clone())
+      .await
+      .unwrap();
+
+    log.lock().await.append("a");
+    stream.tick_with_retries().await.unwrap();
+    log.lock().await.append("b");
+    stream.tick_with_retries().await.unwrap();
+
+    let mut expected = increment_record(&["a"]);
+    expected.extend(increment_record(&["b"]));
+    assert_eq!(*contents.lock().unwrap(), expected);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_retry_backoff() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let writer = FlakyWriter {
+      failures: 3,
+      ..FlakyWriter::default()
+    };
+    let mut stream = CheckpointStreamOptions {
+      retry: RetryPolicy {
+        max_retries: 3,
+        initial_backoff: ms(100),
+        max_backoff: ms(300),
+      },
+      ..CheckpointStreamOptions::default()
+    }
+    .build(writer, log.clone())
+    .await
+    .unwrap();
+
+    log.lock().await.append("a");
+    let start = time::Instant::now();
+    stream.tick_with_retries().await.unwrap();
+    assert_eq!(start.elapsed(), ms(100 + 200 + 300));
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_gives_up_after_max_retries() {
+    let log = Arc::new(Mutex::new(Log::default()));
+    let writer = FlakyWriter {
+      failures: 2,
+      ..FlakyWriter::default()
+    };
+    let contents = writer.contents.clone();
+    let stream = CheckpointStreamOptions {
+      trigger: CheckpointTrigger::Poll { period: ms(1000) },
+      retry: RetryPolicy {
+        max_retries: 1,
+        ..RetryPolicy::default()
+      },
+      ..CheckpointStreamOptions::default()
+    }
+    .build(writer, log.clone())
+    .await
+    .unwrap();
+
+    log.lock().await.append("a");
+    let handle = stream.start();
+    advance(Duration::from_secs(10)).await;
+    assert!(handle.stop().await.is_err());
+    // The torn record should have been discarded.
+    assert!(contents.lock().unwrap().is_empty());
+  }
+}
@@ -1,32 +1,136 @@
 use crate::{
   error::{McError, ThreadSafeError},
   proto::ServerState,
-  systemctl::unit::Unit,
+  readiness::ReadinessProbe,
+  sleep_proxy::{Refresh, Wake},
+  slp::ServerInfo,
+  systemctl::unit::{AsyncResult, Unit},
 };
-use std::time::Duration;
+use futures_util::future::{join_all, BoxFuture};
+use std::{process::ExitStatus, sync::Arc, time::Duration};
 use tokio::{
-  sync::{Mutex, MutexGuard},
-  time::Instant,
+  sync::{broadcast, watch, Mutex, MutexGuard},
+  task::JoinHandle,
+  time::{interval, timeout, Instant, MissedTickBehavior},
 };
 
-const REFRESH_RATE: Duration = Duration::from_secs(5);
+/// How often the unit's state is refreshed by default.
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many events a slow subscriber may fall behind by before missing some.
+const EVENT_CAPACITY: usize = 16;
+
+/// Runs before a server is booted, e.g. to free resources its unit needs.
+pub type StartHook = Box<dyn Fn() -> BoxFuture<'static, ()> + Send + Sync>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ControllerOptions {
+  /// How often the unit's state is refreshed.
+  pub refresh_interval: Duration,
+  /// How long the server may take to become active after being started
+  /// before it's considered failed.
+  pub boot_deadline: Duration,
+  /// How long the unit may take to stop before the server is considered
+  /// failed.
+  pub shutdown_deadline: Duration,
+  /// How long the server may be on with no players before it's shut down
+  /// automatically, or `None` to leave idle servers on. Players are counted
+  /// by the readiness probe, so servers without one are never idle.
+  pub idle_timeout: Option<Duration>,
+  /// How long before an idle server is shut down to warn about it.
+  pub idle_warning: Duration,
+}
+
+impl Default for ControllerOptions {
+  fn default() -> Self {
+    Self {
+      refresh_interval: DEFAULT_REFRESH_INTERVAL,
+      boot_deadline: Duration::from_secs(300),
+      shutdown_deadline: Duration::from_secs(120),
+      idle_timeout: None,
+      idle_warning: Duration::from_secs(60),
+    }
+  }
+}
+
+/// An action the controller took on its own, which subscribers to
+/// `ServerController::events` are told about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ControllerEvent {
+  /// The server will be shut down after `shutdown_in` unless a player joins.
+  IdleShutdownWarning { shutdown_in: Duration },
+  /// The server is being shut down because of `reason`.
+  AutoShutdown { reason: String },
+}
+
+/// A shutdown the controller began on its own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AutoShutdown {
+  pub reason: String,
+  pub at: Instant,
+}
+
+enum IdleAction {
+  Warn { shutdown_in: Duration },
+  Shutdown { reason: String },
+}
+
+/// The server's state as of the latest refresh or transition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerSnapshot {
+  pub state: ServerState,
+  /// Why the server failed, if `state` is `ServerState::Failed`.
+  pub failure: Option<String>,
+  /// What the server reported to the readiness probe when it last answered,
+  /// if it's on.
+  pub info: Option<ServerInfo>,
+  /// When the unit's state was last refreshed successfully, or `None` if it
+  /// never was.
+  pub refreshed_at: Option<Instant>,
+  /// True if the latest refresh failed, so `state` may be out of date.
+  pub stale: bool,
+  /// The latest shutdown the controller began on its own, if any.
+  pub last_auto_shutdown: Option<AutoShutdown>,
+}
 
-#[derive(Debug, Clone)]
 pub struct ServerStatus<U> {
   unit: U,
-  last_updated: Instant,
   state: ServerState,
+  /// Counts operations begun on the server. An operation may only finish if
+  /// no other has begun, been cancelled, or failed since.
+  op: u64,
+  /// When the server must have finished booting by, if it's booting.
+  boot_deadline: Option<Instant>,
+  /// Since when the server has been on with no players, if it is.
+  idle_since: Option<Instant>,
+  /// Whether a shutdown warning has been sent since the server became idle.
+  idle_warned: bool,
+  options: ControllerOptions,
+  /// Publishes a snapshot of the status whenever it changes.
+  snapshot_tx: watch::Sender<ServerSnapshot>,
 }
 
 impl<U> ServerStatus<U>
 where
   U: Unit,
 {
-  fn new(unit: U) -> Self {
+  fn new(unit: U, options: ControllerOptions) -> Self {
     Self {
       unit,
-      last_updated: Instant::now(),
       state: ServerState::Unknown,
+      op: 0,
+      boot_deadline: None,
+      idle_since: None,
+      idle_warned: false,
+      options,
+      snapshot_tx: watch::Sender::new(ServerSnapshot {
+        state: ServerState::Unknown,
+        failure: None,
+        info: None,
+        refreshed_at: None,
+        stale: false,
+        last_auto_shutdown: None,
+      }),
     }
   }
 
@@ -42,120 +146,696 @@ where
     self.state
   }
 
-  fn begin_boot(&mut self) {
+  /// Publishes a change to the snapshot. Subscribers are only notified if the
+  /// state or staleness changed, not for every successful refresh.
+  fn publish(&self, update: impl FnOnce(&mut ServerSnapshot)) {
+    self.snapshot_tx.send_if_modified(|snapshot| {
+      let before = (snapshot.state, snapshot.stale);
+      update(snapshot);
+      before != (snapshot.state, snapshot.stale)
+    });
+  }
+
+  fn set_state(&mut self, state: ServerState) {
+    debug_assert_ne!(state, ServerState::Failed);
+    self.state = state;
+    self.publish(|snapshot| {
+      snapshot.state = state;
+      snapshot.failure = None;
+      if state != ServerState::On {
+        snapshot.info = None;
+      }
+    });
+  }
+
+  /// Begins a new operation, superseding any in progress.
+  fn next_op(&mut self) -> u64 {
+    self.op += 1;
+    self.op
+  }
+
+  fn fail(&mut self, reason: String) {
+    println!("Server {} failed: {reason}", self.unit.name());
+    self.next_op();
+    self.state = ServerState::Failed;
+    self.boot_deadline = None;
+    self.publish(|snapshot| {
+      snapshot.state = ServerState::Failed;
+      snapshot.failure = Some(reason);
+      snapshot.info = None;
+    });
+  }
+
+  /// Moves to the booting state, which the server must leave within the boot
+  /// deadline.
+  fn start_booting(&mut self) {
+    self.boot_deadline = Some(Instant::now() + self.options.boot_deadline);
+    self.set_state(ServerState::Booting);
+  }
+
+  fn begin_boot(&mut self) -> u64 {
     debug_assert_eq!(self.state, ServerState::Off);
-    self.state = ServerState::Booting;
+    self.start_booting();
+    self.next_op()
   }
 
   fn abort_boot(&mut self) {
     debug_assert_eq!(self.state, ServerState::Booting);
-    self.state = ServerState::Off;
+    self.set_state(ServerState::Off);
   }
 
-  fn begin_shutdown(&mut self) {
+  fn begin_shutdown(&mut self) -> u64 {
     debug_assert_eq!(self.state, ServerState::On);
-    self.state = ServerState::Shutdown;
+    self.set_state(ServerState::Shutdown);
+    self.next_op()
   }
 
   fn complete_shutdown(&mut self) {
     debug_assert_eq!(self.state, ServerState::Shutdown);
-    self.state = ServerState::Off;
+    self.set_state(ServerState::Off);
   }
 
   fn abort_shutdown(&mut self) {
     debug_assert_eq!(self.state, ServerState::Shutdown);
-    self.state = ServerState::On;
+    self.set_state(ServerState::On);
   }
 
-  async fn do_update(&mut self, now: Instant) -> Result<(), Box<dyn ThreadSafeError>> {
-    self.unit.refresh().await?;
-    self.last_updated = now;
+  fn begin_restart(&mut self) -> u64 {
+    debug_assert_eq!(self.state, ServerState::On);
+    self.set_state(ServerState::Restarting);
+    self.next_op()
+  }
 
-    self.state = match (self.state, self.unit.is_active()) {
-      (ServerState::Shutdown, _) => ServerState::Shutdown,
-      (ServerState::Booting, false) => ServerState::Booting,
-      (_, false) => ServerState::Off,
-      (_, true) => ServerState::On,
-    };
-    Ok(())
+  /// The unit has been stopped and started again, so the server is booting
+  /// until the unit is seen to be active.
+  fn complete_restart(&mut self) {
+    debug_assert_eq!(self.state, ServerState::Restarting);
+    self.start_booting();
+  }
+
+  /// The unit may or may not have been stopped, so assume it's off until the
+  /// next refresh says otherwise.
+  fn abort_restart(&mut self) {
+    debug_assert_eq!(self.state, ServerState::Restarting);
+    self.set_state(ServerState::Off);
   }
 
-  async fn maybe_update(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
+  /// Cancels booting by stopping the unit, which is tracked like a shutdown.
+  fn begin_cancel_boot(&mut self) -> u64 {
+    debug_assert_eq!(self.state, ServerState::Booting);
+    self.boot_deadline = None;
+    self.set_state(ServerState::Shutdown);
+    self.next_op()
+  }
+
+  /// The unit couldn't be stopped, so it's still booting.
+  fn abort_cancel_boot(&mut self) {
+    debug_assert_eq!(self.state, ServerState::Shutdown);
+    self.start_booting();
+  }
+
+  /// Cancels a shutdown by starting the unit again, which is tracked like a
+  /// boot.
+  fn begin_cancel_shutdown(&mut self) -> u64 {
+    debug_assert_eq!(self.state, ServerState::Shutdown);
+    self.start_booting();
+    self.next_op()
+  }
+
+  /// The unit couldn't be started again, so assume the shutdown went through
+  /// until the next refresh says otherwise.
+  fn abort_cancel_shutdown(&mut self) {
+    debug_assert_eq!(self.state, ServerState::Booting);
+    self.set_state(ServerState::Off);
+  }
+
+  /// Refreshes the unit, returning whether it's active.
+  async fn refresh_unit(&mut self) -> Result<bool, Box<dyn ThreadSafeError>> {
+    if let Err(err) = self.unit.refresh().await {
+      self.publish(|snapshot| snapshot.stale = true);
+      return Err(err);
+    }
+    Ok(self.unit.is_active())
+  }
+
+  /// Moves to the state implied by whether the unit is `active` and the
+  /// server is `ready` for players, which is what it reported as `info`.
+  fn apply_refresh(&mut self, active: bool, ready: bool, info: Option<ServerInfo>) {
     let now = Instant::now();
-    if self.state != ServerState::Unknown && now < self.last_updated + REFRESH_RATE {
-      return Ok(());
+    let state = match (self.state, active, ready) {
+      (ServerState::Failed, _, _) => ServerState::Failed,
+      (ServerState::Shutdown, _, _) => ServerState::Shutdown,
+      (ServerState::Restarting, _, _) => ServerState::Restarting,
+      (ServerState::Booting, _, true) => ServerState::On,
+      (ServerState::Booting, _, false)
+        if self.boot_deadline.is_some_and(|deadline| now >= deadline) =>
+      {
+        self.fail(format!(
+          "Server didn't finish booting within {}s",
+          self.options.boot_deadline.as_secs()
+        ));
+        ServerState::Failed
+      }
+      (ServerState::Booting, _, false) => ServerState::Booting,
+      // Once on, the server stays on while its unit is active, even if it
+      // stops answering the probe.
+      (ServerState::On, true, _) => ServerState::On,
+      (_, true, true) => ServerState::On,
+      (_, true, false) => ServerState::Booting,
+      (_, false, _) => ServerState::Off,
+    };
+    if state != ServerState::Booting {
+      self.boot_deadline = None;
+    } else if self.boot_deadline.is_none() {
+      // The unit was found booting, e.g. after being started outside the
+      // controller, so it gets as long to finish as a boot begun here.
+      self.boot_deadline = Some(now + self.options.boot_deadline);
+    }
+    let players_online = info.as_ref().map(|info| info.players_online);
+    if state == ServerState::On && players_online == Some(0) {
+      self.idle_since.get_or_insert(now);
+    } else {
+      self.idle_since = None;
+      self.idle_warned = false;
     }
-    self.do_update(now).await
+    self.state = state;
+    self.publish(|snapshot| {
+      snapshot.state = state;
+      if state != ServerState::Failed {
+        snapshot.failure = None;
+      }
+      snapshot.info = info.filter(|_| state == ServerState::On);
+      snapshot.refreshed_at = Some(now);
+      snapshot.stale = false;
+    });
+  }
+
+  /// Decides whether to warn about or begin shutting down an idle server.
+  fn idle_action(&mut self, now: Instant) -> Option<IdleAction> {
+    let (Some(idle_timeout), Some(idle_since)) = (self.options.idle_timeout, self.idle_since)
+    else {
+      return None;
+    };
+    let idle = now - idle_since;
+    if idle >= idle_timeout {
+      let reason = format!("No players were online for {}s", idle_timeout.as_secs());
+      println!("Shutting down {}: {reason}", self.unit.name());
+      self.idle_since = None;
+      self.idle_warned = false;
+      self.publish(|snapshot| {
+        snapshot.last_auto_shutdown = Some(AutoShutdown {
+          reason: reason.clone(),
+          at: now,
+        })
+      });
+      return Some(IdleAction::Shutdown { reason });
+    }
+
+    let shutdown_in = idle_timeout - idle;
+    if !self.idle_warned && shutdown_in <= self.options.idle_warning {
+      self.idle_warned = true;
+      return Some(IdleAction::Warn { shutdown_in });
+    }
+    None
+  }
+
+  /// Whether the server may move to `ServerState::On` if its unit is active,
+  /// so is worth probing.
+  fn may_become_on(&self) -> bool {
+    matches!(
+      self.state,
+      ServerState::Unknown | ServerState::Off | ServerState::Booting | ServerState::On
+    )
+  }
+
+  /// Clears a failure, leaving the state to be read from the unit.
+  fn begin_reset(&mut self) {
+    debug_assert_eq!(self.state, ServerState::Failed);
+    self.next_op();
+    self.state = ServerState::Unknown;
   }
 }
 
+/// Controls a server's unit. The unit's state is refreshed by a background
+/// task, which runs until the controller is dropped, so reading the state
+/// never waits on the unit.
 pub struct ServerController<U> {
+  shared: Arc<Shared<U>>,
+  snapshot_rx: watch::Receiver<ServerSnapshot>,
+  refresh_task: JoinHandle<()>,
+}
+
+/// The parts of a controller shared with its background tasks.
+struct Shared<U> {
   server_status: Mutex<ServerStatus<U>>,
+  /// Checks that an active server is ready for players. If `None`, the
+  /// server is on as soon as its unit is active.
+  probe: Option<Box<dyn ReadinessProbe + Send + Sync>>,
+  options: ControllerOptions,
+  events_tx: broadcast::Sender<ControllerEvent>,
+  start_hooks: std::sync::Mutex<Vec<StartHook>>,
 }
 
 impl<U> ServerController<U>
 where
-  U: Unit + Send + Sync,
+  U: Unit + Send + Sync + 'static,
 {
+  /// Creates a controller with the default options. Must be called within a
+  /// tokio runtime.
   pub fn new(unit: U) -> Self {
+    Self::with_options(unit, ControllerOptions::default())
+  }
+
+  /// Creates a controller which considers the server on as soon as its unit
+  /// is active. Must be called within a tokio runtime.
+  pub fn with_options(unit: U, options: ControllerOptions) -> Self {
+    Self::build(unit, None, options)
+  }
+
+  /// Creates a controller which keeps the server booting after its unit is
+  /// active until `probe` succeeds. Must be called within a tokio runtime.
+  pub fn with_probe(
+    unit: U,
+    probe: impl ReadinessProbe + Send + Sync + 'static,
+    options: ControllerOptions,
+  ) -> Self {
+    Self::build(unit, Some(Box::new(probe)), options)
+  }
+
+  fn build(
+    unit: U,
+    probe: Option<Box<dyn ReadinessProbe + Send + Sync>>,
+    options: ControllerOptions,
+  ) -> Self {
+    let server_status = ServerStatus::new(unit, options);
+    let snapshot_rx = server_status.snapshot_tx.subscribe();
+    let shared = Arc::new(Shared {
+      server_status: Mutex::new(server_status),
+      probe,
+      options,
+      events_tx: broadcast::Sender::new(EVENT_CAPACITY),
+      start_hooks: std::sync::Mutex::default(),
+    });
+    let refresh_task = tokio::spawn(shared.clone().refresh_periodically());
     Self {
-      server_status: ServerStatus::new(unit).into(),
+      shared,
+      snapshot_rx,
+      refresh_task,
     }
   }
 
-  async fn server_status_guard(
-    &self,
-  ) -> Result<MutexGuard<'_, ServerStatus<U>>, Box<dyn ThreadSafeError>> {
-    let mut status_guard = self.server_status.lock().await;
-    status_guard.maybe_update().await?;
-    Ok(status_guard)
+  /// The server's state as of the latest refresh or transition.
+  pub fn snapshot(&self) -> ServerSnapshot {
+    self.snapshot_rx.borrow().clone()
+  }
+
+  pub fn server_state(&self) -> ServerState {
+    self.snapshot_rx.borrow().state
+  }
+
+  /// Receives a snapshot of the server's status whenever its state or
+  /// staleness changes. Transitions the unit makes on its own, like finishing
+  /// booting, are noticed on the next refresh.
+  pub fn subscribe(&self) -> watch::Receiver<ServerSnapshot> {
+    self.snapshot_rx.clone()
+  }
+
+  /// Receives the actions the controller takes on its own.
+  pub fn events(&self) -> broadcast::Receiver<ControllerEvent> {
+    self.shared.events_tx.subscribe()
+  }
+
+  /// Refreshes the server's state now rather than waiting for the background
+  /// refresh, returning the snapshot afterwards.
+  pub async fn refresh(&self) -> Result<ServerSnapshot, Box<dyn ThreadSafeError>> {
+    self.shared.refresh_now().await
+  }
+
+  /// Refreshes the server's state like `refresh`, without borrowing the
+  /// controller.
+  pub(crate) fn refresher(&self) -> Refresh {
+    let shared = self.shared.clone();
+    Arc::new(move || {
+      let shared = shared.clone();
+      Box::pin(async move { shared.refresh_now().await })
+    })
+  }
+
+  /// Boots the server like `boot_server`, without borrowing the controller.
+  pub(crate) fn waker(&self) -> Wake {
+    let shared = self.shared.clone();
+    Arc::new(move || {
+      let shared = shared.clone();
+      Box::pin(async move { shared.boot_server().await })
+    })
   }
 
-  pub async fn server_state(&self) -> Result<ServerState, Box<dyn ThreadSafeError>> {
-    Ok(self.server_status_guard().await?.state())
+  /// Runs `hook` whenever the server is booted, after it's moved to the
+  /// booting state and before its unit is started.
+  pub fn add_start_hook(&self, hook: StartHook) {
+    self.shared.start_hooks.lock().unwrap().push(hook);
   }
 
   pub async fn boot_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
-    let boot_fut = {
-      let mut guard = self.server_status_guard().await?;
+    self.shared.boot_server().await
+  }
+
+  pub async fn shutdown_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.shared.shutdown_server().await
+  }
+
+  /// Stops and starts the server again. Returns once the unit has been
+  /// started, leaving the server booting.
+  pub async fn restart_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.shared.restart_server().await
+  }
+
+  /// Cancels the boot or shutdown in flight by reversing it: a booting server
+  /// is shut down, moving to the shutdown state until its unit stops, and a
+  /// server which is shutting down is booted again. A `boot_server` or
+  /// `shutdown_server` call still awaiting its command fails with
+  /// `McError::Cancelled`. If the reversing command exits unsuccessfully, a
+  /// cancelled boot carries on booting and a cancelled shutdown is taken to
+  /// have finished. Returns `McError::InvalidOp` if neither a boot nor a
+  /// shutdown is in flight.
+  pub async fn cancel_operation(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.shared.cancel_operation().await
+  }
+
+  /// Acknowledges that the server failed, moving it to whatever state its
+  /// unit is in.
+  pub async fn reset_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.shared.reset_server().await
+  }
+}
+
+impl<U> Shared<U>
+where
+  U: Unit + Send + Sync + 'static,
+{
+  async fn refresh_now(&self) -> Result<ServerSnapshot, Box<dyn ThreadSafeError>> {
+    let guard = self.server_status.lock().await;
+    let (guard, result) = self.refresh(guard).await;
+    result?;
+    let snapshot = guard.snapshot_tx.borrow().clone();
+    Ok(snapshot)
+  }
+
+  async fn refresh_periodically(self: Arc<Self>) {
+    let mut refresh = interval(self.options.refresh_interval);
+    refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+      refresh.tick().await;
+      let idle_action = {
+        let guard = self.server_status.lock().await;
+        let (mut guard, result) = self.refresh(guard).await;
+        if let Err(err) = result {
+          println!("Failed to refresh server state: {err}");
+        }
+        guard.idle_action(Instant::now())
+      };
+
+      match idle_action {
+        Some(IdleAction::Warn { shutdown_in }) => {
+          // Nobody may be listening, which is fine.
+          let _ = self
+            .events_tx
+            .send(ControllerEvent::IdleShutdownWarning { shutdown_in });
+        }
+        Some(IdleAction::Shutdown { reason }) => {
+          let _ = self
+            .events_tx
+            .send(ControllerEvent::AutoShutdown { reason });
+          tokio::spawn(self.clone().shutdown_idle());
+        }
+        None => {}
+      }
+    }
+  }
+
+  async fn run_start_hooks(&self) {
+    let hooks: Vec<_> = self
+      .start_hooks
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|hook| hook())
+      .collect();
+    join_all(hooks).await;
+  }
+
+  async fn shutdown_idle(self: Arc<Self>) {
+    if let Err(err) = self.shutdown_server().await {
+      println!("Failed to shut down idle server: {err}");
+    }
+  }
+
+  async fn boot_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let op = {
+      let mut guard = self.lock_known().await?;
       if guard.state != ServerState::Off {
         return Err(
           McError::InvalidOp(format!("Can't turn server on in {:?} state", guard.state)).into(),
         );
       }
-      guard.begin_boot();
-      guard.unit_mut().start()
+      guard.begin_boot()
     };
+    // Hooks may take a while, so they run with the status unlocked. The boot
+    // may be cancelled meanwhile, in which case the unit isn't started.
+    self.run_start_hooks().await;
+    let boot_fut = self.lock_op(op, "boot").await?.unit_mut().start();
 
-    let exit_status = boot_fut.await?;
-    if exit_status.success() {
-      Ok(())
-    } else {
-      let mut guard = self.server_status_guard().await?;
-      guard.abort_boot();
-      Err(McError::NonzeroExit(exit_status).into())
+    let result = self
+      .await_command(op, "boot", self.options.boot_deadline, boot_fut)
+      .await;
+    match result {
+      Ok(exit_status) if exit_status.success() => Ok(()),
+      Ok(exit_status) => {
+        self.lock_op(op, "boot").await?.abort_boot();
+        Err(McError::NonzeroExit(exit_status).into())
+      }
+      Err(err) => {
+        if let Ok(mut guard) = self.lock_op(op, "boot").await {
+          guard.abort_boot();
+        }
+        Err(err)
+      }
     }
   }
 
-  pub async fn shutdown_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
-    let shutdown_fut = {
-      let mut guard = self.server_status_guard().await?;
+  async fn shutdown_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let (op, shutdown_fut) = {
+      let mut guard = self.lock_known().await?;
       if guard.state != ServerState::On {
         return Err(
           McError::InvalidOp(format!("Can't turn server off in {:?} state", guard.state)).into(),
         );
       }
-      guard.begin_shutdown();
-      guard.unit_mut().stop()
+      (guard.begin_shutdown(), guard.unit_mut().stop())
     };
 
-    let exit_status = shutdown_fut.await?;
+    let result = self
+      .await_command(op, "shutdown", self.options.shutdown_deadline, shutdown_fut)
+      .await;
+    match result {
+      Ok(exit_status) if exit_status.success() => {
+        self.lock_op(op, "shutdown").await?.complete_shutdown();
+        Ok(())
+      }
+      Ok(exit_status) => {
+        self.lock_op(op, "shutdown").await?.abort_shutdown();
+        Err(McError::NonzeroExit(exit_status).into())
+      }
+      Err(err) => {
+        if let Ok(mut guard) = self.lock_op(op, "shutdown").await {
+          guard.abort_shutdown();
+        }
+        Err(err)
+      }
+    }
+  }
+
+  async fn restart_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let (op, restart_fut) = {
+      let mut guard = self.lock_known().await?;
+      if guard.state != ServerState::On {
+        return Err(
+          McError::InvalidOp(format!("Can't restart server in {:?} state", guard.state)).into(),
+        );
+      }
+      (guard.begin_restart(), guard.unit_mut().restart())
+    };
+
+    // Restarting stops the unit and then starts it again.
+    let restart_deadline = self.options.shutdown_deadline + self.options.boot_deadline;
+
+    let result = self
+      .await_command(op, "restart", restart_deadline, restart_fut)
+      .await;
+    match result {
+      Ok(exit_status) if exit_status.success() => {
+        self.lock_op(op, "restart").await?.complete_restart();
+        Ok(())
+      }
+      Ok(exit_status) => {
+        self.lock_op(op, "restart").await?.abort_restart();
+        Err(McError::NonzeroExit(exit_status).into())
+      }
+      Err(err) => {
+        if let Ok(mut guard) = self.lock_op(op, "restart").await {
+          guard.abort_restart();
+        }
+        Err(err)
+      }
+    }
+  }
+
+  async fn cancel_operation(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let (op, cancelled, cancel_fut) = {
+      let mut guard = self.server_status.lock().await;
+      match guard.state {
+        ServerState::Booting => (
+          guard.begin_cancel_boot(),
+          ServerState::Booting,
+          guard.unit_mut().stop(),
+        ),
+        ServerState::Shutdown => (
+          guard.begin_cancel_shutdown(),
+          ServerState::Shutdown,
+          guard.unit_mut().start(),
+        ),
+        state => {
+          return Err(
+            McError::InvalidOp(format!("No operation to cancel in {state:?} state")).into(),
+          );
+        }
+      }
+    };
+
+    let deadline = match cancelled {
+      ServerState::Booting => self.options.shutdown_deadline,
+      _ => self.options.boot_deadline,
+    };
+    let exit_status = self
+      .await_command(op, "cancellation", deadline, cancel_fut)
+      .await?;
+    let mut guard = self.lock_op(op, "cancellation").await?;
+    match (cancelled, exit_status.success()) {
+      (ServerState::Booting, true) => guard.complete_shutdown(),
+      (ServerState::Booting, false) => guard.abort_cancel_boot(),
+      (_, true) => {}
+      (_, false) => guard.abort_cancel_shutdown(),
+    }
     if exit_status.success() {
-      self.server_status_guard().await?.complete_shutdown();
       Ok(())
     } else {
-      self.server_status_guard().await?.abort_shutdown();
       Err(McError::NonzeroExit(exit_status).into())
     }
   }
+
+  async fn reset_server(&self) -> Result<(), Box<dyn ThreadSafeError>> {
+    let mut guard = self.server_status.lock().await;
+    if guard.state != ServerState::Failed {
+      return Err(
+        McError::InvalidOp(format!("Can't reset server in {:?} state", guard.state)).into(),
+      );
+    }
+    guard.begin_reset();
+    let (mut guard, result) = self.refresh(guard).await;
+    if result.is_err() {
+      guard.set_state(ServerState::Unknown);
+    }
+    result
+  }
+
+  /// Refreshes the status locked by `guard`, returning it locked again along
+  /// with whether the unit could be refreshed. The readiness probe may take a
+  /// while to time out, so it runs with the status unlocked. If the status
+  /// changes meanwhile, the probe's result is dropped, leaving the next
+  /// refresh to probe again.
+  async fn refresh<'a>(
+    &'a self,
+    mut guard: MutexGuard<'a, ServerStatus<U>>,
+  ) -> (
+    MutexGuard<'a, ServerStatus<U>>,
+    Result<(), Box<dyn ThreadSafeError>>,
+  ) {
+    let active = match guard.refresh_unit().await {
+      Ok(active) => active,
+      Err(err) => return (guard, Err(err)),
+    };
+    let probe = match &self.probe {
+      Some(probe) if active && guard.may_become_on() => probe,
+      _ => {
+        guard.apply_refresh(active, active, None);
+        return (guard, Ok(()));
+      }
+    };
+
+    let (op, state) = (guard.op, guard.state);
+    drop(guard);
+    let (ready, info) = match probe.probe().await {
+      Ok(info) => (true, Some(info)),
+      Err(_) => (false, None),
+    };
+    let mut guard = self.server_status.lock().await;
+    if (guard.op, guard.state) == (op, state) {
+      guard.apply_refresh(active, ready, info);
+    }
+    (guard, Ok(()))
+  }
+
+  /// Locks the server's status, refreshing it first if it has never been
+  /// read, so operations aren't rejected for racing the first background
+  /// refresh.
+  async fn lock_known(&self) -> Result<MutexGuard<'_, ServerStatus<U>>, Box<dyn ThreadSafeError>> {
+    let guard = self.server_status.lock().await;
+    if guard.state != ServerState::Unknown {
+      return Ok(guard);
+    }
+    let (guard, result) = self.refresh(guard).await;
+    result?;
+    Ok(guard)
+  }
+
+  /// Awaits the unit command `command_fut` run by operation `op`. If it
+  /// doesn't finish within `deadline`, the server fails.
+  async fn await_command(
+    &self,
+    op: u64,
+    name: &str,
+    deadline: Duration,
+    command_fut: AsyncResult<ExitStatus>,
+  ) -> Result<ExitStatus, Box<dyn ThreadSafeError>> {
+    match timeout(deadline, command_fut).await {
+      Ok(result) => result,
+      Err(_) => {
+        self.lock_op(op, name).await?.fail(format!(
+          "Server {name} didn't finish within {}s",
+          deadline.as_secs()
+        ));
+        Err(McError::Timeout(name.to_owned()).into())
+      }
+    }
+  }
+
+  /// Locks the server's status to finish operation `op`, which fails if the
+  /// operation has since been superseded.
+  async fn lock_op(
+    &self,
+    op: u64,
+    name: &str,
+  ) -> Result<MutexGuard<'_, ServerStatus<U>>, Box<dyn ThreadSafeError>> {
+    let guard = self.server_status.lock().await;
+    if guard.op != op {
+      return Err(McError::Cancelled(name.to_owned()).into());
+    }
+    Ok(guard)
+  }
+}
+
+impl<U> Drop for ServerController<U> {
+  fn drop(&mut self) {
+    self.refresh_task.abort();
+  }
 }
@@ -6,6 +6,14 @@ pub type McResult<T> = Result<T, McError>;
 pub enum McError {
   NonzeroExit(ExitStatus),
   InvalidOp(String),
+  HashError(String),
+  CorruptCheckpoint(String),
+  UnsupportedSchemaVersion(u32),
+  InvalidConfig(String),
+  UnknownServer(String),
+  Timeout(String),
+  Cancelled(String),
+  InvalidResponse(String),
 }
 
 impl Display for McError {
@@ -17,6 +25,30 @@ impl Display for McError {
       McError::InvalidOp(msg) => {
         write!(f, "Invalid operation: {msg}")
       }
+      McError::HashError(msg) => {
+        write!(f, "Password hashing failed: {msg}")
+      }
+      McError::CorruptCheckpoint(msg) => {
+        write!(f, "Corrupt checkpoint: {msg}")
+      }
+      McError::UnsupportedSchemaVersion(version) => {
+        write!(f, "Unsupported checkpoint schema version {version}")
+      }
+      McError::InvalidConfig(msg) => {
+        write!(f, "Invalid configuration: {msg}")
+      }
+      McError::UnknownServer(id) => {
+        write!(f, "Unknown server {id}")
+      }
+      McError::Timeout(op) => {
+        write!(f, "Server {op} timed out")
+      }
+      McError::Cancelled(op) => {
+        write!(f, "Server {op} was cancelled")
+      }
+      McError::InvalidResponse(msg) => {
+        write!(f, "Invalid response from server: {msg}")
+      }
     }
   }
 }
@@ -1,9 +1,19 @@
 pub mod auth;
+pub mod checkpoint_codec;
+pub mod checkpoint_crypto;
+pub mod checkpoint_file;
+pub mod checkpoint_migration;
+pub mod checkpoint_multiplex;
+pub mod checkpoint_record;
 pub mod checkpoint_stream;
 pub mod controller;
 pub mod error;
 pub mod proto;
+pub mod readiness;
 pub mod security;
+pub mod servers;
+pub mod sleep_proxy;
+pub mod slp;
 pub mod socket_init;
 pub mod static_file_server;
 pub mod systemctl;
@@ -1,11 +1,22 @@
 use std::{
+  io,
   net::{IpAddr, SocketAddr},
+  path::PathBuf,
   str::FromStr,
+  time::Duration,
 };
 
 use clap::Parser;
 use pc_landing_page::{
-  error::ThreadSafeError, socket_init::create_socket_endpoint, static_file_server::run_file_server,
+  controller::ControllerOptions,
+  error::ThreadSafeError,
+  servers::{ServerConfig, Servers},
+  socket_init::{create_socket_endpoint, UsersCheckpoint},
+  static_file_server::run_file_server,
+};
+use tokio::signal::{
+  self,
+  unix::{signal, SignalKind},
 };
 
 #[derive(Parser, Debug)]
@@ -29,10 +40,79 @@ struct Args {
   #[arg(long, default_value_t = false)]
   client_prod: bool,
 
-  /// When passed to the program, runs a simulated Minecraft server instead of
-  /// running the systemctl service.
+  /// When passed to the program, runs simulated Minecraft servers instead of
+  /// running the systemctl services.
   #[arg(long, default_value_t = false)]
   simulated: bool,
+
+  /// A JSON file listing the servers to manage. Without it, only
+  /// mc_server.service is managed, probed at localhost:25565.
+  ///
+  /// The file holds a nonempty array of objects with the fields:
+  /// `id`, which identifies the server in requests and must be unique;
+  /// `unit`, the full name of the systemd unit running the server;
+  /// `display_name`, the name shown to users;
+  /// `mc_address` (optional), a `host:port` the server must answer a Server
+  /// List Ping on before it's considered on;
+  /// and `sleep_proxy_addr` (optional), an `ip:port` to hold while the server
+  /// is off, so players can wake it by joining. For example:
+  ///
+  /// [{"id": "survival", "unit": "survival.service", "display_name":
+  /// "Survival", "mc_address": "localhost:25565", "sleep_proxy_addr":
+  /// "0.0.0.0:25565"}]
+  #[arg(long)]
+  servers: Option<PathBuf>,
+
+  /// How often, in seconds, the state of each server's unit is refreshed.
+  #[arg(long, default_value_t = 5)]
+  refresh_interval_secs: u64,
+
+  /// How long, in seconds, a server may take to boot before it's considered
+  /// failed.
+  #[arg(long, default_value_t = 300)]
+  boot_deadline_secs: u64,
+
+  /// How long, in seconds, a server may take to shut down before it's
+  /// considered failed.
+  #[arg(long, default_value_t = 120)]
+  shutdown_deadline_secs: u64,
+
+  /// If given, servers are shut down after this many minutes with no players
+  /// online.
+  #[arg(long)]
+  idle_shutdown_mins: Option<u64>,
+
+  /// How long, in seconds, before shutting down an idle server to warn
+  /// clients about it.
+  #[arg(long, default_value_t = 60)]
+  idle_warning_secs: u64,
+
+  /// Adds an admin with this username before starting, unless a user with
+  /// the name already exists. The admin's password is read from stdin.
+  #[arg(long)]
+  add_user: Option<String>,
+
+  /// The file users are checkpointed to.
+  #[arg(long, default_value = "users.checkpoint")]
+  users_checkpoint: PathBuf,
+
+  /// Encrypts the users checkpoint with the key in this file. A key file
+  /// holds a key ID and 32 hex-encoded bytes, separated by whitespace.
+  #[arg(long)]
+  users_key_file: Option<PathBuf>,
+
+  /// Key files the users checkpoint was previously encrypted with. Any records
+  /// encrypted with these keys are re-encrypted with `--users-key-file`.
+  #[arg(long)]
+  users_old_key_file: Vec<PathBuf>,
+
+  /// Accepts plaintext records in the users checkpoint although
+  /// `--users-key-file` is given, encrypting them. Pass this once to encrypt
+  /// a checkpoint written before encryption was enabled; otherwise plaintext
+  /// records are rejected, since anyone who can write the file could inject
+  /// them.
+  #[arg(long)]
+  users_migrate_plaintext: bool,
 }
 
 #[tokio::main]
@@ -49,11 +129,72 @@ async fn main() -> Result<(), Box<dyn ThreadSafeError>> {
   let fs_addr = SocketAddr::new(addr, args.port);
   let ws_addr = SocketAddr::new(addr, args.ws_port);
 
-  match tokio::join!(
-    run_file_server(fs_addr, args.prod, args.client_prod),
-    create_socket_endpoint(args.prod, ws_addr, args.simulated).await?
-  ) {
-    (Err(err), _) | (_, Err(err)) => Err(err.into()),
-    (Ok(()), Ok(())) => Ok(()),
+  let new_admin = match args.add_user {
+    Some(username) => {
+      println!("Password for {username}:");
+      let mut password = String::new();
+      io::stdin().read_line(&mut password)?;
+      Some((username, password.trim_end_matches(['\r', '\n']).to_owned()))
+    }
+    None => None,
+  };
+
+  let server_configs = match &args.servers {
+    Some(path) => ServerConfig::load_list(path).await?,
+    None => ServerConfig::default_servers(),
+  };
+  let servers = Servers::from_configs(
+    server_configs,
+    args.simulated,
+    ControllerOptions {
+      refresh_interval: Duration::from_secs(args.refresh_interval_secs),
+      boot_deadline: Duration::from_secs(args.boot_deadline_secs),
+      shutdown_deadline: Duration::from_secs(args.shutdown_deadline_secs),
+      idle_timeout: args
+        .idle_shutdown_mins
+        .map(|mins| Duration::from_secs(60 * mins)),
+      idle_warning: Duration::from_secs(args.idle_warning_secs),
+    },
+  )
+  .await?;
+
+  let (socket_server, users_checkpoint_stream) = create_socket_endpoint(
+    args.prod,
+    ws_addr,
+    servers,
+    UsersCheckpoint {
+      path: args.users_checkpoint,
+      key_file: args.users_key_file,
+      old_key_files: args.users_old_key_file,
+      migrate_plaintext: args.users_migrate_plaintext,
+    },
+    new_admin,
+  )
+  .await?;
+
+  let result = tokio::select! {
+    results = async {
+      tokio::join!(
+        run_file_server(fs_addr, args.prod, args.client_prod),
+        socket_server
+      )
+    } => match results {
+      (Err(err), _) | (_, Err(err)) => Err(err.into()),
+      (Ok(()), Ok(())) => Ok(()),
+    },
+    result = shutdown_signal() => result.map_err(Into::into),
+  };
+
+  println!("Shutting down");
+  users_checkpoint_stream.stop().await?;
+  result
+}
+
+/// Waits for SIGINT or SIGTERM.
+async fn shutdown_signal() -> io::Result<()> {
+  let mut sigterm = signal(SignalKind::terminate())?;
+  tokio::select! {
+    result = signal::ctrl_c() => result,
+    _ = sigterm.recv() => Ok(()),
   }
 }
@@ -3,21 +3,29 @@ use serde::{de, Deserialize, Serialize};
 include!(concat!(env!("OUT_DIR"), "/mc_server.proto.rs"));
 include!(concat!(env!("OUT_DIR"), "/user.proto.rs"));
 
-impl Serialize for ServerState {
-  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-  where
-    S: serde::Serializer,
-  {
-    (*self as i32).serialize(serializer)
-  }
-}
+/// Serializes proto enums as their `i32` representation.
+macro_rules! impl_enum_serde {
+  ($enum:ty) => {
+    impl Serialize for $enum {
+      fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+      where
+        S: serde::Serializer,
+      {
+        (*self as i32).serialize(serializer)
+      }
+    }
 
-impl<'de> Deserialize<'de> for ServerState {
-  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-  where
-    D: serde::Deserializer<'de>,
-  {
-    let repr = i32::deserialize(deserializer)?;
-    Self::try_from(repr).map_err(de::Error::custom)
-  }
+    impl<'de> Deserialize<'de> for $enum {
+      fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+      where
+        D: serde::Deserializer<'de>,
+      {
+        let repr = i32::deserialize(deserializer)?;
+        Self::try_from(repr).map_err(de::Error::custom)
+      }
+    }
+  };
 }
+
+impl_enum_serde!(ServerState);
+impl_enum_serde!(Role);
@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+
+use crate::{
+  error::{McError, McResult, ThreadSafeError},
+  slp::{self, ServerInfo},
+};
+
+/// Checks whether a server whose unit is active is ready for players.
+#[async_trait]
+pub trait ReadinessProbe {
+  /// Fails if the server isn't ready yet. Otherwise returns what the server
+  /// reports about itself.
+  async fn probe(&self) -> Result<ServerInfo, Box<dyn ThreadSafeError>>;
+}
+
+/// Probes a Minecraft server with a Server List Ping, which it only answers
+/// once the world has loaded.
+pub struct SlpProbe {
+  host: String,
+  port: u16,
+}
+
+impl SlpProbe {
+  pub fn new(host: String, port: u16) -> Self {
+    Self { host, port }
+  }
+
+  /// Parses an address of the form `host:port`.
+  pub fn parse(addr: &str) -> McResult<Self> {
+    let invalid = || McError::InvalidConfig(format!("Invalid Minecraft server address {addr}"));
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse().map_err(|_| invalid())?;
+    if host.is_empty() {
+      return Err(invalid());
+    }
+    Ok(Self::new(host.to_owned(), port))
+  }
+}
+
+#[async_trait]
+impl ReadinessProbe for SlpProbe {
+  async fn probe(&self) -> Result<ServerInfo, Box<dyn ThreadSafeError>> {
+    slp::ping(&self.host, self.port).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::SlpProbe;
+
+  #[test]
+  fn test_parse_address() {
+    let probe = SlpProbe::parse("localhost:25565").unwrap();
+    assert_eq!((probe.host.as_str(), probe.port), ("localhost", 25565));
+
+    for addr in ["localhost", ":25565", "localhost:", "localhost:99999"] {
+      assert!(SlpProbe::parse(addr).is_err(), "{addr} should be invalid");
+    }
+  }
+}
@@ -0,0 +1,330 @@
+use std::{collections::HashSet, net::SocketAddr, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  controller::{ControllerOptions, ServerController, ServerSnapshot},
+  error::{McError, McResult, ThreadSafeError},
+  proto::ServerState,
+  readiness::SlpProbe,
+  systemctl::{sim_unit::SimUnit, sys_unit::SysUnit, unit::Unit},
+};
+
+/// The unit managed when no server list is given.
+const DEFAULT_SERVICE: &str = "mc_server.service";
+const DEFAULT_MC_ADDRESS: &str = "localhost:25565";
+
+/// A Minecraft server run by a systemd unit.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct ServerConfig {
+  /// Identifies the server in requests.
+  pub id: String,
+  /// The full name of the unit running the server.
+  pub unit: String,
+  /// The name the server is shown to users by.
+  pub display_name: String,
+  /// The `host:port` the Minecraft server listens on. If given, the server
+  /// isn't considered on until it answers a Server List Ping there.
+  #[serde(default)]
+  pub mc_address: Option<String>,
+  /// The address to hold with a sleep proxy while the server is off, so
+  /// players can wake it by joining. Usually the server's public port.
+  #[serde(default)]
+  pub sleep_proxy_addr: Option<SocketAddr>,
+}
+
+impl ServerConfig {
+  /// The single server managed when no server list is given.
+  pub fn default_servers() -> Vec<Self> {
+    vec![ServerConfig {
+      id: "mc_server".to_owned(),
+      unit: DEFAULT_SERVICE.to_owned(),
+      display_name: "Minecraft Server".to_owned(),
+      mc_address: Some(DEFAULT_MC_ADDRESS.to_owned()),
+      sleep_proxy_addr: None,
+    }]
+  }
+
+  /// Parses a server list, a JSON array of objects with the fields of
+  /// `ServerConfig`. The list must be nonempty, and IDs must be unique.
+  pub fn parse_list(json: &str) -> McResult<Vec<Self>> {
+    let configs: Vec<Self> = serde_json::from_str(json)
+      .map_err(|err| McError::InvalidConfig(format!("Malformed server list: {err}")))?;
+    if configs.is_empty() {
+      return Err(McError::InvalidConfig("Server list is empty".to_owned()));
+    }
+
+    let mut ids = HashSet::new();
+    for config in &configs {
+      if !ids.insert(&config.id) {
+        return Err(McError::InvalidConfig(format!(
+          "Duplicate server ID {}",
+          config.id
+        )));
+      }
+    }
+    Ok(configs)
+  }
+
+  pub async fn load_list(path: &Path) -> Result<Vec<Self>, Box<dyn ThreadSafeError>> {
+    let json = tokio::fs::read_to_string(path).await?;
+    Ok(Self::parse_list(&json)?)
+  }
+}
+
+/// A server's state as reported by `ListServers`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ServerSummary {
+  pub id: String,
+  pub display_name: String,
+  pub state: ServerState,
+  /// Why the server failed, if `state` is `ServerState::Failed`.
+  pub failure: Option<String>,
+  /// True if the server's state couldn't be refreshed, so may be out of date.
+  pub stale: bool,
+}
+
+struct ManagedServer<U> {
+  config: ServerConfig,
+  controller: ServerController<U>,
+}
+
+/// Every managed server, in the order they were configured.
+pub struct Servers<U> {
+  servers: Vec<ManagedServer<U>>,
+}
+
+impl Servers<Box<dyn Unit + Send + Sync>> {
+  /// Looks up the unit of every server in `configs` through systemctl, or
+  /// simulates each unit if `sim` is true. Every server is controlled with
+  /// `options`, and probed for readiness at its `mc_address` unless
+  /// simulated.
+  pub async fn from_configs(
+    configs: Vec<ServerConfig>,
+    sim: bool,
+    options: ControllerOptions,
+  ) -> Result<Self, Box<dyn ThreadSafeError>> {
+    let mut servers = Vec::with_capacity(configs.len());
+    for config in configs {
+      let controller = if sim {
+        let unit: Box<dyn Unit + Send + Sync> = Box::new(SimUnit::new(config.unit.clone()));
+        ServerController::with_options(unit, options)
+      } else {
+        let unit: Box<dyn Unit + Send + Sync> =
+          Box::new(SysUnit::from_systemctl(&config.unit).await?);
+        match &config.mc_address {
+          Some(addr) => ServerController::with_probe(unit, SlpProbe::parse(addr)?, options),
+          None => ServerController::with_options(unit, options),
+        }
+      };
+      servers.push(ManagedServer { config, controller });
+    }
+    Ok(Self { servers })
+  }
+}
+
+impl<U> Servers<U>
+where
+  U: Unit + Send + Sync + 'static,
+{
+  pub fn new(servers: Vec<(ServerConfig, U)>, options: ControllerOptions) -> Self {
+    Self {
+      servers: servers
+        .into_iter()
+        .map(|(config, unit)| ManagedServer {
+          config,
+          controller: ServerController::with_options(unit, options),
+        })
+        .collect(),
+    }
+  }
+
+  /// Every server's config and controller.
+  pub fn iter(&self) -> impl Iterator<Item = (&ServerConfig, &ServerController<U>)> {
+    self
+      .servers
+      .iter()
+      .map(|server| (&server.config, &server.controller))
+  }
+
+  /// The controller of the server `id`.
+  pub fn controller(&self, id: &str) -> McResult<&ServerController<U>> {
+    self
+      .servers
+      .iter()
+      .find(|server| server.config.id == id)
+      .map(|server| &server.controller)
+      .ok_or_else(|| McError::UnknownServer(id.to_owned()))
+  }
+
+  pub fn snapshot(&self, id: &str) -> McResult<ServerSnapshot> {
+    Ok(self.controller(id)?.snapshot())
+  }
+
+  pub async fn refresh(&self, id: &str) -> Result<ServerSnapshot, Box<dyn ThreadSafeError>> {
+    self.controller(id)?.refresh().await
+  }
+
+  pub async fn boot_server(&self, id: &str) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.controller(id)?.boot_server().await
+  }
+
+  pub async fn shutdown_server(&self, id: &str) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.controller(id)?.shutdown_server().await
+  }
+
+  pub async fn restart_server(&self, id: &str) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.controller(id)?.restart_server().await
+  }
+
+  pub async fn cancel_operation(&self, id: &str) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.controller(id)?.cancel_operation().await
+  }
+
+  pub async fn reset_server(&self, id: &str) -> Result<(), Box<dyn ThreadSafeError>> {
+    self.controller(id)?.reset_server().await
+  }
+
+  /// Summarizes every server.
+  pub fn list(&self) -> Vec<ServerSummary> {
+    self
+      .servers
+      .iter()
+      .map(|server| {
+        let snapshot = server.controller.snapshot();
+        ServerSummary {
+          id: server.config.id.clone(),
+          display_name: server.config.display_name.clone(),
+          state: snapshot.state,
+          failure: snapshot.failure,
+          stale: snapshot.stale,
+        }
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use tokio::time;
+
+  use crate::{
+    controller::ControllerOptions, error::McError, proto::ServerState, systemctl::sim_unit::SimUnit,
+  };
+
+  use super::{ServerConfig, ServerSummary, Servers};
+
+  fn config(id: &str) -> ServerConfig {
+    ServerConfig {
+      id: id.to_owned(),
+      unit: format!("{id}.service"),
+      display_name: id.to_uppercase(),
+      mc_address: None,
+      sleep_proxy_addr: None,
+    }
+  }
+
+  fn servers(ids: &[&str]) -> Servers<SimUnit> {
+    Servers::new(
+      ids
+        .iter()
+        .map(|id| (config(id), SimUnit::new(format!("{id}.service"))))
+        .collect(),
+      ControllerOptions::default(),
+    )
+  }
+
+  #[test]
+  fn test_parse_list() {
+    let json = r#"[
+      { "id": "survival", "unit": "survival.service", "display_name": "SURVIVAL" },
+      {
+        "id": "creative",
+        "unit": "creative.service",
+        "display_name": "CREATIVE",
+        "mc_address": "localhost:25566",
+        "sleep_proxy_addr": "0.0.0.0:25565"
+      }
+    ]"#;
+    assert_eq!(
+      ServerConfig::parse_list(json).unwrap(),
+      vec![
+        config("survival"),
+        ServerConfig {
+          mc_address: Some("localhost:25566".to_owned()),
+          sleep_proxy_addr: Some(([0, 0, 0, 0], 25565).into()),
+          ..config("creative")
+        }
+      ]
+    );
+  }
+
+  #[test]
+  fn test_parse_invalid_lists() {
+    for json in [
+      "[]",
+      "{}",
+      r#"[{ "id": "survival" }]"#,
+      r#"[
+        { "id": "survival", "unit": "a.service", "display_name": "A" },
+        { "id": "survival", "unit": "b.service", "display_name": "B" }
+      ]"#,
+    ] {
+      assert!(
+        matches!(
+          ServerConfig::parse_list(json),
+          Err(McError::InvalidConfig(_))
+        ),
+        "{json} should be invalid"
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn test_unknown_server() {
+    let servers = servers(&["survival"]);
+    assert!(matches!(
+      servers.controller("creative"),
+      Err(McError::UnknownServer(id)) if id == "creative"
+    ));
+    assert!(servers.boot_server("creative").await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_servers_are_independent() {
+    time::pause();
+    let servers = servers(&["survival", "creative", "modded"]);
+    servers.boot_server("creative").await.unwrap();
+    time::sleep(Duration::from_secs(6)).await;
+    servers.boot_server("modded").await.unwrap();
+
+    assert_eq!(
+      servers.list(),
+      vec![
+        ServerSummary {
+          id: "survival".to_owned(),
+          display_name: "SURVIVAL".to_owned(),
+          state: ServerState::Off,
+          failure: None,
+          stale: false,
+        },
+        ServerSummary {
+          id: "creative".to_owned(),
+          display_name: "CREATIVE".to_owned(),
+          state: ServerState::On,
+          failure: None,
+          stale: false,
+        },
+        ServerSummary {
+          id: "modded".to_owned(),
+          display_name: "MODDED".to_owned(),
+          state: ServerState::Booting,
+          failure: None,
+          stale: false,
+        },
+      ]
+    );
+  }
+}
@@ -0,0 +1,296 @@
+//! Holds a server's Minecraft port while the server is off, so players can
+//! wake it from their Minecraft client.
+//!
+//! The proxy answers Server List Pings with a sleeping MOTD. When a player
+//! tries to join, it boots the server and disconnects them with a message to
+//! retry once it's up. The proxy stops listening before the server's unit is
+//! started, through a start hook on its controller, so the real server can
+//! always bind the port. A unit may also be started outside the controller,
+//! so the proxy refreshes the server's state before taking the port.
+
+use std::{io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  select,
+  sync::{mpsc, oneshot, watch},
+  time::{sleep, timeout},
+};
+
+use crate::{
+  controller::{ServerController, ServerSnapshot, StartHook},
+  error::ThreadSafeError,
+  proto::ServerState,
+  slp::{
+    invalid, packet, read_packet, read_string, read_varint, write_string, HANDSHAKE_PACKET_ID,
+    LOGIN_DISCONNECT_PACKET_ID, NEXT_STATE_STATUS, PING_PACKET_ID, STATUS_PACKET_ID,
+  },
+  systemctl::unit::Unit,
+};
+
+pub const SLEEPING_MOTD: &str = "Server sleeping – join to wake";
+pub const WAKING_MESSAGE: &str = "Server is starting, retry in ~60s";
+
+const NEXT_STATE_LOGIN: i32 = 2;
+const NEXT_STATE_TRANSFER: i32 = 3;
+
+/// How long a client may take to say what it wants.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before first retrying to bind the port. The delay
+/// doubles with each failure, up to `MAX_REBIND_DELAY`.
+const REBIND_DELAY: Duration = Duration::from_secs(5);
+const MAX_REBIND_DELAY: Duration = Duration::from_secs(300);
+
+/// Refreshes the server's state, returning the snapshot afterwards.
+pub type Refresh = Arc<
+  dyn Fn() -> BoxFuture<'static, Result<ServerSnapshot, Box<dyn ThreadSafeError>>> + Send + Sync,
+>;
+
+/// Boots the server a player is trying to join.
+pub type Wake =
+  Arc<dyn Fn() -> BoxFuture<'static, Result<(), Box<dyn ThreadSafeError>>> + Send + Sync>;
+
+/// Asks a `SleepProxy` to stop listening.
+type ReleaseRequest = oneshot::Sender<()>;
+
+pub struct SleepProxy {
+  addr: SocketAddr,
+  release_rx: mpsc::Receiver<ReleaseRequest>,
+}
+
+/// Frees the port a `SleepProxy` listens on.
+#[derive(Clone)]
+pub struct ProxyHandle {
+  release_tx: mpsc::Sender<ReleaseRequest>,
+}
+
+impl ProxyHandle {
+  /// Returns once the proxy has stopped listening. It won't listen again
+  /// until the server is next seen to be off.
+  pub async fn release(&self) {
+    let (ack_tx, ack_rx) = oneshot::channel();
+    if self.release_tx.send(ack_tx).await.is_ok() {
+      // The proxy only drops the request if it's stopped, so has released the
+      // port anyway.
+      let _ = ack_rx.await;
+    }
+  }
+
+  /// A hook releasing the port before the server's unit is started.
+  pub fn start_hook(self) -> StartHook {
+    Box::new(move || {
+      let handle = self.clone();
+      Box::pin(async move { handle.release().await })
+    })
+  }
+}
+
+impl SleepProxy {
+  /// Spawns a proxy holding `addr` whenever `controller`'s server is off. The
+  /// proxy releases the port before the controller starts the server's unit.
+  pub fn spawn<U>(addr: SocketAddr, controller: &ServerController<U>)
+  where
+    U: Unit + Send + Sync + 'static,
+  {
+    let (proxy, handle) = Self::new(addr);
+    controller.add_start_hook(handle.start_hook());
+    tokio::spawn(proxy.run(
+      controller.subscribe(),
+      controller.refresher(),
+      controller.waker(),
+    ));
+  }
+
+  /// Creates a proxy listening on `addr`, along with a handle to release the
+  /// port with.
+  fn new(addr: SocketAddr) -> (Self, ProxyHandle) {
+    let (release_tx, release_rx) = mpsc::channel(1);
+    (Self { addr, release_rx }, ProxyHandle { release_tx })
+  }
+
+  /// Listens whenever `snapshots` says the server is off, and `refresh`
+  /// confirms it, calling `wake` when a player tries to join. Runs until
+  /// `snapshots` closes.
+  async fn run(
+    mut self,
+    mut snapshots: watch::Receiver<ServerSnapshot>,
+    refresh: Refresh,
+    wake: Wake,
+  ) {
+    let mut rebind_delay = REBIND_DELAY;
+    loop {
+      while snapshots.borrow_and_update().state != ServerState::Off {
+        select! {
+          changed = snapshots.changed() => {
+            if changed.is_err() {
+              return;
+            }
+          }
+          Some(ack) = self.release_rx.recv() => {
+            let _ = ack.send(());
+          }
+        }
+      }
+
+      // The snapshot is only as fresh as the last refresh, so the unit may
+      // have been started since.
+      let listener = match refresh().await {
+        Ok(snapshot) if snapshot.state != ServerState::Off => continue,
+        Ok(_) => match TcpListener::bind(self.addr).await {
+          Ok(listener) => Some(listener),
+          Err(err) if err.kind() == ErrorKind::AddrInUse => {
+            println!(
+              "Sleep proxy can't bind {}, which something else is using; retrying in {}s",
+              self.addr,
+              rebind_delay.as_secs()
+            );
+            None
+          }
+          Err(err) => {
+            println!("Sleep proxy failed to bind {}: {err}", self.addr);
+            None
+          }
+        },
+        Err(err) => {
+          println!("Sleep proxy couldn't check the server is off: {err}");
+          None
+        }
+      };
+      let Some(listener) = listener else {
+        if !self.back_off(rebind_delay, &mut snapshots).await {
+          return;
+        }
+        rebind_delay = (rebind_delay * 2).min(MAX_REBIND_DELAY);
+        continue;
+      };
+      rebind_delay = REBIND_DELAY;
+      if !self.serve(listener, &mut snapshots, &wake).await {
+        return;
+      }
+    }
+  }
+
+  /// Waits `delay` before trying to listen again, or less if the server's
+  /// state changes meanwhile. Returns false if `snapshots` closed.
+  async fn back_off(
+    &mut self,
+    delay: Duration,
+    snapshots: &mut watch::Receiver<ServerSnapshot>,
+  ) -> bool {
+    let retry_at = sleep(delay);
+    tokio::pin!(retry_at);
+    loop {
+      select! {
+        _ = &mut retry_at => return true,
+        changed = snapshots.changed() => return changed.is_ok(),
+        Some(ack) = self.release_rx.recv() => {
+          let _ = ack.send(());
+        }
+      }
+    }
+  }
+
+  /// Accepts connections on `listener` until the server leaves the off
+  /// state or the port is released. Returns false if `snapshots` closed.
+  async fn serve(
+    &mut self,
+    listener: TcpListener,
+    snapshots: &mut watch::Receiver<ServerSnapshot>,
+    wake: &Wake,
+  ) -> bool {
+    loop {
+      select! {
+        accepted = listener.accept() => match accepted {
+          Ok((stream, _)) => {
+            tokio::spawn(handle_connection(stream, wake.clone()));
+          }
+          Err(err) => println!("Sleep proxy failed to accept a connection: {err}"),
+        },
+        changed = snapshots.changed() => {
+          if changed.is_err() {
+            return false;
+          }
+          if snapshots.borrow_and_update().state != ServerState::Off {
+            return true;
+          }
+        }
+        Some(ack) = self.release_rx.recv() => {
+          drop(listener);
+          let _ = ack.send(());
+          return true;
+        }
+      }
+    }
+  }
+}
+
+async fn handle_connection(mut stream: TcpStream, wake: Wake) {
+  match timeout(CONNECTION_TIMEOUT, greet(&mut stream, wake)).await {
+    Ok(Ok(())) => {}
+    Ok(Err(err)) => println!("Sleep proxy connection failed: {err}"),
+    Err(_) => println!("Sleep proxy connection timed out"),
+  }
+}
+
+/// Reads the client's handshake, and answers a status request or login.
+async fn greet(stream: &mut TcpStream, wake: Wake) -> Result<(), Box<dyn ThreadSafeError>> {
+  let (id, fields) = read_packet(stream).await?;
+  if id != HANDSHAKE_PACKET_ID {
+    return Err(invalid(format!("Expected a handshake, got packet {id:#x}")).into());
+  }
+  let mut fields = fields.as_slice();
+  let protocol_version = read_varint(&mut fields).await?;
+  read_string(&mut fields).await?;
+  fields.read_u16().await?;
+  let next_state = read_varint(&mut fields).await?;
+
+  match next_state {
+    NEXT_STATE_STATUS => answer_status(stream, protocol_version).await,
+    NEXT_STATE_LOGIN | NEXT_STATE_TRANSFER => {
+      // Boot in the background, so the player isn't kept waiting and the
+      // boot isn't abandoned if the connection times out.
+      tokio::spawn(async move {
+        if let Err(err) = wake().await {
+          println!("Sleep proxy failed to boot the server: {err}");
+        }
+      });
+      let mut data = Vec::new();
+      write_string(&mut data, &json!({ "text": WAKING_MESSAGE }).to_string());
+      stream
+        .write_all(&packet(LOGIN_DISCONNECT_PACKET_ID, &data))
+        .await?;
+      Ok(())
+    }
+    _ => Err(invalid(format!("Unknown next state {next_state}")).into()),
+  }
+}
+
+async fn answer_status(
+  stream: &mut TcpStream,
+  protocol_version: i32,
+) -> Result<(), Box<dyn ThreadSafeError>> {
+  let (id, _) = read_packet(stream).await?;
+  if id != STATUS_PACKET_ID {
+    return Err(invalid(format!("Expected a status request, got packet {id:#x}")).into());
+  }
+
+  // Echo the client's protocol version, so it doesn't report the server as
+  // incompatible.
+  let status = json!({
+    "version": { "name": "Sleeping", "protocol": protocol_version },
+    "players": { "max": 0, "online": 0 },
+    "description": { "text": SLEEPING_MOTD },
+  });
+  let mut data = Vec::new();
+  write_string(&mut data, &status.to_string());
+  stream.write_all(&packet(STATUS_PACKET_ID, &data)).await?;
+
+  // Clients measure latency with a ping after the status, which is echoed.
+  if let Ok((PING_PACKET_ID, payload)) = read_packet(stream).await {
+    stream.write_all(&packet(PING_PACKET_ID, &payload)).await?;
+  }
+  Ok(())
+}
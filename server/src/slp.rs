@@ -0,0 +1,327 @@
+//! A client for Minecraft's Server List Ping, which asks a server for the
+//! status shown in the multiplayer server list.
+//!
+//! Packets are framed by their length as a VarInt, followed by the packet ID
+//! as a VarInt and the packet's fields. The client sends a handshake with
+//! next state 1 (status) and an empty status request, and the server answers
+//! with a status response holding a JSON string.
+//!
+//! The packet helpers are shared with the sleep proxy, which plays the
+//! server's side of the protocol.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+  net::TcpStream,
+  time::timeout,
+};
+
+use crate::error::{McError, ThreadSafeError};
+
+/// How long a ping may take before it's abandoned.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The protocol version sent in the handshake. Servers answer status requests
+/// regardless of the client's version.
+const PROTOCOL_VERSION: i32 = -1;
+pub(crate) const HANDSHAKE_PACKET_ID: i32 = 0x00;
+pub(crate) const STATUS_PACKET_ID: i32 = 0x00;
+pub(crate) const PING_PACKET_ID: i32 = 0x01;
+pub(crate) const LOGIN_DISCONNECT_PACKET_ID: i32 = 0x00;
+pub(crate) const NEXT_STATE_STATUS: i32 = 1;
+
+/// Bounds the size of a status response, which is at most a few KB in
+/// practice.
+const MAX_PACKET_LEN: usize = 1 << 20;
+
+/// What a server reports about itself in the server list.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ServerInfo {
+  /// The message of the day, with formatting removed.
+  pub motd: String,
+  /// The name of the server's version, e.g. "1.20.4".
+  pub version: String,
+  pub players_online: u32,
+  pub players_max: u32,
+  /// The names of some of the online players. Servers only send a sample of
+  /// up to 12 players, and may hide them entirely.
+  pub players: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct StatusVersion {
+  name: String,
+}
+
+#[derive(Deserialize)]
+struct StatusPlayer {
+  name: String,
+}
+
+#[derive(Deserialize)]
+struct StatusPlayers {
+  max: u32,
+  online: u32,
+  #[serde(default)]
+  sample: Vec<StatusPlayer>,
+}
+
+#[derive(Deserialize)]
+struct StatusResponse {
+  version: StatusVersion,
+  players: StatusPlayers,
+  #[serde(default)]
+  description: Value,
+}
+
+pub(crate) fn invalid(msg: impl Into<String>) -> McError {
+  McError::InvalidResponse(msg.into())
+}
+
+pub fn write_varint(buf: &mut Vec<u8>, value: i32) {
+  let mut value = value as u32;
+  loop {
+    if value & !0x7f == 0 {
+      buf.push(value as u8);
+      return;
+    }
+    buf.push((value & 0x7f) as u8 | 0x80);
+    value >>= 7;
+  }
+}
+
+pub async fn read_varint<R>(reader: &mut R) -> Result<i32, Box<dyn ThreadSafeError>>
+where
+  R: AsyncRead + Unpin,
+{
+  let mut value = 0u32;
+  for i in 0..5 {
+    let byte = reader.read_u8().await?;
+    value |= ((byte & 0x7f) as u32) << (7 * i);
+    if byte & 0x80 == 0 {
+      return Ok(value as i32);
+    }
+  }
+  Err(invalid("VarInt is longer than 5 bytes").into())
+}
+
+pub fn write_string(buf: &mut Vec<u8>, value: &str) {
+  write_varint(buf, value.len() as i32);
+  buf.extend(value.as_bytes());
+}
+
+pub(crate) async fn read_string<R>(reader: &mut R) -> Result<String, Box<dyn ThreadSafeError>>
+where
+  R: AsyncRead + Unpin,
+{
+  let len = read_varint(reader).await?;
+  let len = usize::try_from(len)
+    .ok()
+    .filter(|len| *len <= MAX_PACKET_LEN)
+    .ok_or_else(|| invalid(format!("Bad string length {len}")))?;
+  let mut bytes = vec![0; len];
+  reader.read_exact(&mut bytes).await?;
+  Ok(String::from_utf8(bytes).map_err(|_| invalid("String isn't UTF-8"))?)
+}
+
+/// Frames a packet with ID `id` and fields `data`.
+pub fn packet(id: i32, data: &[u8]) -> Vec<u8> {
+  let mut body = Vec::with_capacity(data.len() + 5);
+  write_varint(&mut body, id);
+  body.extend(data);
+
+  let mut packet = Vec::with_capacity(body.len() + 5);
+  write_varint(&mut packet, body.len() as i32);
+  packet.extend(body);
+  packet
+}
+
+fn handshake(host: &str, port: u16) -> Vec<u8> {
+  let mut data = Vec::new();
+  write_varint(&mut data, PROTOCOL_VERSION);
+  write_string(&mut data, host);
+  data.extend(port.to_be_bytes());
+  write_varint(&mut data, NEXT_STATE_STATUS);
+  packet(HANDSHAKE_PACKET_ID, &data)
+}
+
+/// Reads one packet, returning its ID and fields.
+pub(crate) async fn read_packet<R>(
+  reader: &mut R,
+) -> Result<(i32, Vec<u8>), Box<dyn ThreadSafeError>>
+where
+  R: AsyncRead + Unpin,
+{
+  let len = read_varint(reader).await?;
+  let len = usize::try_from(len)
+    .ok()
+    .filter(|len| *len <= MAX_PACKET_LEN)
+    .ok_or_else(|| invalid(format!("Bad packet length {len}")))?;
+  let mut body = vec![0; len];
+  reader.read_exact(&mut body).await?;
+
+  let mut fields = body.as_slice();
+  let id = read_varint(&mut fields).await?;
+  Ok((id, fields.to_vec()))
+}
+
+/// Flattens a chat component into its plain text.
+fn chat_text(component: &Value) -> String {
+  match component {
+    Value::String(text) => text.clone(),
+    Value::Array(components) => components.iter().map(chat_text).collect(),
+    Value::Object(fields) => {
+      let mut text = fields.get("text").map(chat_text).unwrap_or_default();
+      if let Some(extra) = fields.get("extra") {
+        text += &chat_text(extra);
+      }
+      text
+    }
+    _ => String::new(),
+  }
+}
+
+/// Removes `§` formatting codes from legacy-formatted text.
+fn strip_formatting(text: &str) -> String {
+  let mut stripped = String::with_capacity(text.len());
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    if c == '§' {
+      chars.next();
+    } else {
+      stripped.push(c);
+    }
+  }
+  stripped
+}
+
+/// Parses the JSON of a status response.
+pub fn parse_status(json: &str) -> Result<ServerInfo, McError> {
+  let status: StatusResponse = serde_json::from_str(json)
+    .map_err(|err| invalid(format!("Malformed status response: {err}")))?;
+  Ok(ServerInfo {
+    motd: strip_formatting(&chat_text(&status.description)),
+    version: status.version.name,
+    players_online: status.players.online,
+    players_max: status.players.max,
+    players: status
+      .players
+      .sample
+      .into_iter()
+      .map(|player| player.name)
+      .collect(),
+  })
+}
+
+async fn ping_stream(
+  stream: &mut TcpStream,
+  host: &str,
+  port: u16,
+) -> Result<ServerInfo, Box<dyn ThreadSafeError>> {
+  let mut request = handshake(host, port);
+  request.extend(packet(STATUS_PACKET_ID, &[]));
+  stream.write_all(&request).await?;
+
+  let (id, fields) = read_packet(stream).await?;
+  if id != STATUS_PACKET_ID {
+    return Err(invalid(format!("Expected a status response, got packet {id:#x}")).into());
+  }
+  let mut fields = fields.as_slice();
+  let len = read_varint(&mut fields).await?;
+  let json = usize::try_from(len)
+    .ok()
+    .and_then(|len| fields.get(..len))
+    .ok_or_else(|| invalid("Status response is truncated"))?;
+  let json = std::str::from_utf8(json).map_err(|_| invalid("Status response isn't UTF-8"))?;
+  Ok(parse_status(json)?)
+}
+
+/// Pings the server at `host`:`port`, giving up after `PING_TIMEOUT`.
+pub async fn ping(host: &str, port: u16) -> Result<ServerInfo, Box<dyn ThreadSafeError>> {
+  timeout(PING_TIMEOUT, async {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    ping_stream(&mut stream, host, port).await
+  })
+  .await
+  .map_err(|_| McError::Timeout("ping".to_owned()))?
+}
+
+#[cfg(test)]
+mod test {
+  use super::{parse_status, read_varint, strip_formatting, write_varint, ServerInfo};
+
+  #[tokio::test]
+  async fn test_varint_round_trip() {
+    for (value, encoded) in [
+      (0, vec![0x00]),
+      (1, vec![0x01]),
+      (127, vec![0x7f]),
+      (128, vec![0x80, 0x01]),
+      (25565, vec![0xdd, 0xc7, 0x01]),
+      (i32::MAX, vec![0xff, 0xff, 0xff, 0xff, 0x07]),
+      (-1, vec![0xff, 0xff, 0xff, 0xff, 0x0f]),
+    ] {
+      let mut buf = Vec::new();
+      write_varint(&mut buf, value);
+      assert_eq!(buf, encoded, "encoding {value}");
+      assert_eq!(read_varint(&mut buf.as_slice()).await.unwrap(), value);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_varint_too_long() {
+    let bytes = [0xff; 6];
+    assert!(read_varint(&mut bytes.as_slice()).await.is_err());
+  }
+
+  #[test]
+  fn test_parse_status() {
+    let json = r#"{
+      "version": { "name": "1.20.4", "protocol": 765 },
+      "players": {
+        "max": 20,
+        "online": 3,
+        "sample": [
+          { "name": "alice", "id": "4566e69f-c907-48ee-8d71-d7ba5aa00d20" },
+          { "name": "bob", "id": "4566e69f-c907-48ee-8d71-d7ba5aa00d21" }
+        ]
+      },
+      "description": { "text": "A ", "extra": [{ "text": "Minecraft", "bold": true }, " Server"] }
+    }"#;
+    assert_eq!(
+      parse_status(json).unwrap(),
+      ServerInfo {
+        motd: "A Minecraft Server".to_owned(),
+        version: "1.20.4".to_owned(),
+        players_online: 3,
+        players_max: 20,
+        players: vec!["alice".to_owned(), "bob".to_owned()],
+      }
+    );
+  }
+
+  #[test]
+  fn test_parse_legacy_description() {
+    let json = r#"{
+      "version": { "name": "1.8.9", "protocol": 47 },
+      "players": { "max": 10, "online": 0 },
+      "description": "§aHello§r world"
+    }"#;
+    assert_eq!(parse_status(json).unwrap().motd, "Hello world");
+  }
+
+  #[test]
+  fn test_parse_malformed_status() {
+    assert!(parse_status("{}").is_err());
+    assert!(parse_status("not json").is_err());
+  }
+
+  #[test]
+  fn test_strip_formatting() {
+    assert_eq!(strip_formatting("§l§6Gold§r"), "Gold");
+    assert_eq!(strip_formatting("trailing §"), "trailing ");
+  }
+}
@@ -1,28 +1,177 @@
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use async_sockets::{
   AsyncSocket, AsyncSocketContext, AsyncSocketEmitters, AsyncSocketListeners, AsyncSocketOptions,
   AsyncSocketResponders, AsyncSocketSecurity, Status,
 };
 use serde::Deserialize;
-use tokio::task::JoinHandle;
+use tokio::{
+  sync::{broadcast::error::RecvError, Mutex},
+  task::{spawn_blocking, JoinHandle},
+};
 
 use crate::{
-  controller::ServerController,
+  auth::{SessionStore, UserStore},
+  checkpoint_stream::{
+    CheckpointStreamHandle, CheckpointStreamOptions, CheckpointTrigger, CompactionPolicy,
+  },
+  controller::ControllerEvent,
   error::ThreadSafeError,
-  proto::ServerState,
+  proto::{Role, ServerState},
   security::{CERTFILE, KEYFILE},
-  systemctl::{sim_unit::SimUnit, sys_unit::SysUnit, unit::Unit},
+  servers::{ServerSummary, Servers},
+  sleep_proxy::SleepProxy,
+  slp::ServerInfo,
+  systemctl::unit::Unit,
 };
 
-const MC_SERVER_SERVICE: &str = "mc_server.service";
+/// Users change rarely, so this mostly bounds how much a long-lived checkpoint
+/// can accumulate.
+const USERS_COMPACTION: CompactionPolicy = CompactionPolicy {
+  max_increments: Some(1000),
+  max_bytes: Some(1 << 20),
+};
 
 struct Globals {
-  server_controller: ServerController<Box<dyn Unit + Send + Sync>>,
+  servers: Servers<Box<dyn Unit + Send + Sync>>,
+  users: Arc<Mutex<UserStore>>,
+  sessions: Mutex<SessionStore>,
+  /// Every connected client, which events are broadcast to.
+  clients: Mutex<Vec<AsyncSocketContext<ServerEmitEvents>>>,
 }
 
-#[derive(AsyncSocketEmitters)]
-enum ServerEmitEvents {}
+impl Globals {
+  /// Checks `password` against `username`'s, returning a new session token if
+  /// it matches.
+  async fn login(
+    &self,
+    username: String,
+    password: String,
+  ) -> Result<Option<String>, Box<dyn ThreadSafeError>> {
+    let check = self.users.lock().await.password_check(&username);
+    // Hashing would stall every other task on this thread, and holding the
+    // store would stall every other request.
+    let Some(verified) = spawn_blocking(move || check.verify(&password)).await?? else {
+      return Ok(None);
+    };
+    if !self.users.lock().await.complete_password_check(verified) {
+      return Ok(None);
+    }
+    Ok(Some(self.sessions.lock().await.create_session(username)))
+  }
+
+  /// Adds a user, hashing their password off the executor without holding the
+  /// store, as `login` does.
+  async fn add_user(
+    &self,
+    username: String,
+    password: String,
+    role: Role,
+  ) -> Result<(), Box<dyn ThreadSafeError>> {
+    let hasher = self.users.lock().await.password_hasher();
+    let password = spawn_blocking(move || hasher.hash(&password)).await??;
+    self
+      .users
+      .lock()
+      .await
+      .add_hashed_user(username, password, role)?;
+    Ok(())
+  }
+
+  /// Returns the role of the user the session `token` belongs to, or `None`
+  /// if the session is invalid or its user no longer exists.
+  async fn session_role(&self, token: &str) -> Option<Role> {
+    let username = self.sessions.lock().await.authenticate(token)?.to_owned();
+    self
+      .users
+      .lock()
+      .await
+      .find_user(&username)
+      .map(|user| user.role())
+  }
+
+  /// Emits `event` to every connected client, forgetting clients which have
+  /// disconnected.
+  async fn broadcast(&self, event: ServerEmitEvents) {
+    let mut clients = self.clients.lock().await;
+    let mut connected = Vec::with_capacity(clients.len());
+    for client in clients.drain(..) {
+      if client.emit(event.clone()).await.is_ok() {
+        connected.push(client);
+      }
+    }
+    *clients = connected;
+  }
+
+  /// Broadcasts the state of the server `server_id` whenever it changes.
+  async fn watch_server_state(self: Arc<Self>, server_id: String) {
+    let Ok(controller) = self.servers.controller(&server_id) else {
+      return;
+    };
+    let mut snapshots = controller.subscribe();
+    let mut last_state = snapshots.borrow_and_update().state;
+    while snapshots.changed().await.is_ok() {
+      let state = snapshots.borrow_and_update().state;
+      if state == last_state {
+        // Only the staleness changed.
+        continue;
+      }
+      last_state = state;
+      self
+        .broadcast(ServerEmitEvents::ServerStateChanged {
+          server_id: server_id.clone(),
+          state,
+        })
+        .await;
+    }
+  }
+
+  /// Broadcasts the actions the controller of server `server_id` takes on its
+  /// own.
+  async fn forward_controller_events(self: Arc<Self>, server_id: String) {
+    let Ok(controller) = self.servers.controller(&server_id) else {
+      return;
+    };
+    let mut events = controller.events();
+    loop {
+      let event = match events.recv().await {
+        Ok(event) => event,
+        Err(RecvError::Lagged(_)) => continue,
+        Err(RecvError::Closed) => return,
+      };
+      let server_id = server_id.clone();
+      let event = match event {
+        ControllerEvent::IdleShutdownWarning { shutdown_in } => {
+          ServerEmitEvents::IdleShutdownWarning {
+            server_id,
+            shutdown_in_secs: shutdown_in.as_secs(),
+          }
+        }
+        ControllerEvent::AutoShutdown { reason } => {
+          ServerEmitEvents::AutoShutdown { server_id, reason }
+        }
+      };
+      self.broadcast(event).await;
+    }
+  }
+}
+
+#[derive(AsyncSocketEmitters, Clone)]
+enum ServerEmitEvents {
+  /// The server `server_id` transitioned to `state`.
+  ServerStateChanged {
+    server_id: String,
+    state: ServerState,
+  },
+  /// The server `server_id` has no players, and will be shut down in
+  /// `shutdown_in_secs` unless one joins.
+  IdleShutdownWarning {
+    server_id: String,
+    shutdown_in_secs: u64,
+  },
+  /// The server `server_id` is being shut down automatically.
+  AutoShutdown { server_id: String, reason: String },
+}
 
 #[derive(AsyncSocketListeners)]
 enum ClientEmitEvents {}
@@ -36,40 +185,231 @@ enum FromClientResponses {}
 
 #[derive(AsyncSocketListeners)]
 enum FromClientRequests {
-  McServerStatus {},
-  BootServer {},
-  ShutdownServer {},
+  Login {
+    username: String,
+    password: String,
+  },
+  Logout {
+    token: String,
+  },
+  ListServers {
+    token: String,
+  },
+  McServerStatus {
+    token: String,
+    server_id: String,
+  },
+  McServerInfo {
+    token: String,
+    server_id: String,
+  },
+  BootServer {
+    token: String,
+    server_id: String,
+  },
+  ShutdownServer {
+    token: String,
+    server_id: String,
+  },
+  RestartServer {
+    token: String,
+    server_id: String,
+  },
+  CancelOperation {
+    token: String,
+    server_id: String,
+  },
+  ResetServer {
+    token: String,
+    server_id: String,
+  },
+  AddUser {
+    token: String,
+    username: String,
+    password: String,
+    role: Role,
+  },
+  RemoveUser {
+    token: String,
+    username: String,
+  },
+  SetUserRole {
+    token: String,
+    username: String,
+    role: Role,
+  },
+}
+
+impl FromClientRequests {
+  /// The session token authenticating this request and the least privileged
+  /// role allowed to make it, or `None` if the request doesn't require the
+  /// client to be logged in.
+  fn required_role(&self) -> Option<(&str, Role)> {
+    match self {
+      FromClientRequests::Login { .. } | FromClientRequests::Logout { .. } => None,
+      FromClientRequests::ListServers { token }
+      | FromClientRequests::McServerStatus { token, .. }
+      | FromClientRequests::McServerInfo { token, .. } => Some((token, Role::Viewer)),
+      FromClientRequests::BootServer { token, .. }
+      | FromClientRequests::ShutdownServer { token, .. }
+      | FromClientRequests::RestartServer { token, .. }
+      | FromClientRequests::CancelOperation { token, .. }
+      | FromClientRequests::ResetServer { token, .. } => Some((token, Role::Operator)),
+      FromClientRequests::AddUser { token, .. }
+      | FromClientRequests::RemoveUser { token, .. }
+      | FromClientRequests::SetUserRole { token, .. } => Some((token, Role::Admin)),
+    }
+  }
 }
 
 #[derive(AsyncSocketResponders)]
 enum ToClientResponses {
-  McServerStatus { state: ServerState },
+  Login {
+    token: String,
+  },
+  Logout {},
+  ListServers {
+    servers: Vec<ServerSummary>,
+  },
+  McServerStatus {
+    state: ServerState,
+    failure: Option<String>,
+    info: Option<ServerInfo>,
+    stale: bool,
+  },
+  McServerInfo {
+    info: Option<ServerInfo>,
+  },
   BootServer {},
   ShutdownServer {},
+  RestartServer {},
+  CancelOperation {},
+  ResetServer {},
+  AddUser {},
+  RemoveUser {},
+  SetUserRole {},
+  /// Answers any request whose session token is missing, expired, or lacks
+  /// the role the request requires, or a login with the wrong password. Sent
+  /// in place of the request's own response, rather than as an error status,
+  /// so clients can tell it apart from server faults and log in again.
+  Unauthorized {
+    reason: String,
+  },
+}
+
+fn unauthorized(reason: impl Into<String>) -> Status<ToClientResponses> {
+  Status::Ok(ToClientResponses::Unauthorized {
+    reason: reason.into(),
+  })
 }
 
-async fn handle_connect_event(_context: AsyncSocketContext<ServerEmitEvents>) {}
+async fn handle_connect_event(
+  context: AsyncSocketContext<ServerEmitEvents>,
+  globals: Arc<Globals>,
+) {
+  globals.clients.lock().await.push(context);
+}
 
 async fn handle_call_event(
   event: FromClientRequests,
   _context: AsyncSocketContext<ServerEmitEvents>,
   globals: Arc<Globals>,
 ) -> Status<ToClientResponses> {
+  if let Some((token, required_role)) = event.required_role() {
+    match globals.session_role(token).await {
+      None => return unauthorized("Not logged in"),
+      Some(role) if role < required_role => {
+        return unauthorized(format!("Requires {required_role:?} role"))
+      }
+      Some(_) => {}
+    }
+  }
+
   match event {
-    FromClientRequests::McServerStatus {} => match globals.server_controller.server_state().await {
-      Ok(state) => Status::Ok(ToClientResponses::McServerStatus { state }),
-      Err(err) => Status::InternalServerError(format!("Failed to read MC server status: {err}")),
-    },
-    FromClientRequests::BootServer {} => match globals.server_controller.boot_server().await {
-      Ok(()) => Status::Ok(ToClientResponses::BootServer {}),
-      Err(err) => Status::InternalServerError(format!("Failed to boot server: {err}")),
-    },
-    FromClientRequests::ShutdownServer {} => {
-      match globals.server_controller.shutdown_server().await {
-        Ok(()) => Status::Ok(ToClientResponses::ShutdownServer {}),
+    FromClientRequests::Login { username, password } => {
+      match globals.login(username, password).await {
+        Ok(Some(token)) => Status::Ok(ToClientResponses::Login { token }),
+        Ok(None) => unauthorized("Invalid username or password"),
+        Err(err) => Status::InternalServerError(format!("Failed to log in: {err}")),
+      }
+    }
+    FromClientRequests::Logout { token } => {
+      globals.sessions.lock().await.end_session(&token);
+      Status::Ok(ToClientResponses::Logout {})
+    }
+    FromClientRequests::ListServers { .. } => Status::Ok(ToClientResponses::ListServers {
+      servers: globals.servers.list(),
+    }),
+    FromClientRequests::McServerStatus { server_id, .. } => {
+      match globals.servers.snapshot(&server_id) {
+        Ok(snapshot) => Status::Ok(ToClientResponses::McServerStatus {
+          state: snapshot.state,
+          failure: snapshot.failure,
+          info: snapshot.info,
+          stale: snapshot.stale,
+        }),
+        Err(err) => Status::InternalServerError(format!("Failed to read MC server status: {err}")),
+      }
+    }
+    FromClientRequests::McServerInfo { server_id, .. } => {
+      match globals.servers.snapshot(&server_id) {
+        Ok(snapshot) => Status::Ok(ToClientResponses::McServerInfo {
+          info: snapshot.info,
+        }),
+        Err(err) => Status::InternalServerError(format!("Failed to read MC server info: {err}")),
+      }
+    }
+    FromClientRequests::BootServer { server_id, .. } => {
+      match globals.servers.boot_server(&server_id).await {
+        Ok(()) => Status::Ok(ToClientResponses::BootServer {}),
         Err(err) => Status::InternalServerError(format!("Failed to boot server: {err}")),
       }
     }
+    FromClientRequests::ShutdownServer { server_id, .. } => {
+      match globals.servers.shutdown_server(&server_id).await {
+        Ok(()) => Status::Ok(ToClientResponses::ShutdownServer {}),
+        Err(err) => Status::InternalServerError(format!("Failed to shut down server: {err}")),
+      }
+    }
+    FromClientRequests::RestartServer { server_id, .. } => {
+      match globals.servers.restart_server(&server_id).await {
+        Ok(()) => Status::Ok(ToClientResponses::RestartServer {}),
+        Err(err) => Status::InternalServerError(format!("Failed to restart server: {err}")),
+      }
+    }
+    FromClientRequests::CancelOperation { server_id, .. } => {
+      match globals.servers.cancel_operation(&server_id).await {
+        Ok(()) => Status::Ok(ToClientResponses::CancelOperation {}),
+        Err(err) => Status::InternalServerError(format!("Failed to cancel operation: {err}")),
+      }
+    }
+    FromClientRequests::ResetServer { server_id, .. } => {
+      match globals.servers.reset_server(&server_id).await {
+        Ok(()) => Status::Ok(ToClientResponses::ResetServer {}),
+        Err(err) => Status::InternalServerError(format!("Failed to reset server: {err}")),
+      }
+    }
+    FromClientRequests::AddUser {
+      username,
+      password,
+      role,
+      ..
+    } => match globals.add_user(username, password, role).await {
+      Ok(()) => Status::Ok(ToClientResponses::AddUser {}),
+      Err(err) => Status::InternalServerError(format!("Failed to add user: {err}")),
+    },
+    FromClientRequests::RemoveUser { username, .. } => {
+      match globals.users.lock().await.remove_user(&username) {
+        Ok(()) => Status::Ok(ToClientResponses::RemoveUser {}),
+        Err(err) => Status::InternalServerError(format!("Failed to remove user: {err}")),
+      }
+    }
+    FromClientRequests::SetUserRole { username, role, .. } => {
+      match globals.users.lock().await.set_role(&username, role) {
+        Ok(()) => Status::Ok(ToClientResponses::SetUserRole {}),
+        Err(err) => Status::InternalServerError(format!("Failed to set user role: {err}")),
+      }
+    }
   }
 }
 
@@ -80,11 +420,33 @@ async fn handle_emit_event(
   match event {}
 }
 
+/// Where and how users are checkpointed.
+pub struct UsersCheckpoint {
+  pub path: PathBuf,
+  /// The key file users are encrypted with, if any.
+  pub key_file: Option<PathBuf>,
+  /// Key files records may have been written with, which are re-encrypted
+  /// with `key_file`.
+  pub old_key_files: Vec<PathBuf>,
+  /// Accepts plaintext records although `key_file` is given, to encrypt a
+  /// checkpoint written before encryption was enabled.
+  pub migrate_plaintext: bool,
+}
+
+/// Starts the websocket endpoint, managing `servers`. Users are checkpointed as
+/// described by `users_checkpoint`, and if `new_admin` is a (username,
+/// password) pair, an admin with those credentials is added before starting,
+/// unless the user already exists.
+/// Returns the server task along with a handle to the users' checkpoint
+/// stream, which should be stopped before exiting so the last changes aren't
+/// lost.
 pub async fn create_socket_endpoint(
   prod: bool,
   addr: SocketAddr,
-  sim: bool,
-) -> Result<JoinHandle<()>, Box<dyn ThreadSafeError>> {
+  servers: Servers<Box<dyn Unit + Send + Sync>>,
+  users_checkpoint: UsersCheckpoint,
+  new_admin: Option<(String, String)>,
+) -> Result<(JoinHandle<()>, CheckpointStreamHandle), Box<dyn ThreadSafeError>> {
   let options = AsyncSocketOptions::new()
     .with_path("horsney")
     .with_bind_addr(addr)
@@ -100,28 +462,83 @@ pub async fn create_socket_endpoint(
     options
   };
 
-  let unit: Box<dyn Unit + Send + Sync> = if sim {
-    Box::new(SimUnit::new(MC_SERVER_SERVICE.to_owned()))
-  } else {
-    Box::new(SysUnit::from_systemctl(MC_SERVER_SERVICE).await?)
-  };
+  let users = Arc::new(Mutex::new(UserStore::new()));
+  let recovery = CheckpointStreamOptions {
+    trigger: CheckpointTrigger::Notify {
+      debounce: Some(Duration::from_millis(100)),
+      max_latency: Some(Duration::from_secs(1)),
+    },
+    compaction: USERS_COMPACTION,
+    key_file: users_checkpoint.key_file,
+    old_key_files: users_checkpoint.old_key_files,
+    migrate_plaintext: users_checkpoint.migrate_plaintext,
+    ..CheckpointStreamOptions::default()
+  }
+  .recover(&users_checkpoint.path, users.clone())
+  .await?;
+  println!(
+    "Recovered {} user checkpoint records from {}",
+    recovery.records_applied,
+    users_checkpoint.path.display()
+  );
+  if recovery.discarded_bytes != 0 {
+    println!(
+      "Discarded {} bytes of an incomplete user checkpoint record",
+      recovery.discarded_bytes
+    );
+  }
+  if recovery.rekeyed {
+    println!("Re-encrypted the user checkpoint with the current key");
+  }
+  users
+    .lock()
+    .await
+    .set_checkpoint_notifier(recovery.stream.notifier());
+  let users_checkpoint_stream = recovery.stream.start();
+
+  if let Some((username, password)) = new_admin {
+    let mut users = users.lock().await;
+    // Restarting with the same flags shouldn't fail, so an existing user is
+    // left as is.
+    if users.find_user(&username).is_some() {
+      println!("User {username} already exists, not adding it");
+    } else {
+      users.add_user(username, password, Role::Admin)?;
+    }
+  }
 
   let globals = Arc::new(Globals {
-    server_controller: ServerController::new(unit),
+    servers,
+    users,
+    sessions: SessionStore::new().into(),
+    clients: Mutex::default(),
   });
 
-  Ok(tokio::spawn(async move {
+  for (config, controller) in globals.servers.iter() {
+    tokio::spawn(globals.clone().watch_server_state(config.id.clone()));
+    tokio::spawn(globals.clone().forward_controller_events(config.id.clone()));
+    if let Some(addr) = config.sleep_proxy_addr {
+      SleepProxy::spawn(addr, controller);
+    }
+  }
+
+  let server = tokio::spawn(async move {
     println!(
       "Starting server on {}://{addr}",
       if prod { "wss" } else { "ws" }
     );
     AsyncSocket::new(
       options,
-      handle_connect_event,
+      {
+        let globals = globals.clone();
+        move |context| handle_connect_event(context, globals.clone())
+      },
       handle_emit_event,
       move |event, context| handle_call_event(event, context, globals.clone()),
     )
     .start_server()
     .await
-  }))
+  });
+
+  Ok((server, users_checkpoint_stream))
 }
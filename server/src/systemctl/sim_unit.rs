@@ -1,7 +1,18 @@
-use std::{os::unix::process::ExitStatusExt, process::ExitStatus, time::Duration};
+use std::{
+  os::unix::process::ExitStatusExt,
+  process::ExitStatus,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
 
 use async_trait::async_trait;
-use futures_util::{future::ready, FutureExt};
+use futures_util::{
+  future::{pending, ready},
+  FutureExt,
+};
 use tokio::time::{sleep, Instant};
 
 use crate::{
@@ -17,6 +28,7 @@ pub struct SimUnit {
   name: String,
   state: ServerState,
   last_update: Instant,
+  faults: SimFaults,
 }
 
 impl SimUnit {
@@ -25,8 +37,66 @@ impl SimUnit {
       name,
       state: ServerState::Off,
       last_update: Instant::now(),
+      faults: SimFaults::default(),
     }
   }
+
+  /// A handle for injecting faults into this unit after it's been handed off.
+  pub fn faults(&self) -> SimFaults {
+    self.faults.clone()
+  }
+}
+
+/// Faults to inject into a `SimUnit`.
+#[derive(Clone, Default)]
+pub struct SimFaults {
+  fail_refresh: Arc<AtomicBool>,
+  hang: Arc<AtomicBool>,
+  fail_ops: Arc<AtomicBool>,
+  stop_delay: Arc<Mutex<Option<Duration>>>,
+}
+
+impl SimFaults {
+  /// Makes every refresh of the unit fail until cleared.
+  pub fn set_refresh_fails(&self, fails: bool) {
+    self.fail_refresh.store(fails, Ordering::Relaxed);
+  }
+
+  /// Makes operations started on the unit never finish: a started unit never
+  /// becomes active, and stopping or restarting the unit never returns.
+  pub fn set_ops_hang(&self, hang: bool) {
+    self.hang.store(hang, Ordering::Relaxed);
+  }
+
+  /// Makes starting, stopping and restarting the unit fail to run at all, as
+  /// if systemctl couldn't be spawned, leaving the unit's state unchanged.
+  pub fn set_ops_fail(&self, fail: bool) {
+    self.fail_ops.store(fail, Ordering::Relaxed);
+  }
+
+  /// Makes stopping the unit, including as part of a restart, take `delay`
+  /// rather than `OP_DELAY`.
+  pub fn set_stop_delay(&self, delay: Duration) {
+    *self.stop_delay.lock().unwrap() = Some(delay);
+  }
+
+  fn stop_delay(&self) -> Duration {
+    self.stop_delay.lock().unwrap().unwrap_or(OP_DELAY)
+  }
+
+  fn ops_hang(&self) -> bool {
+    self.hang.load(Ordering::Relaxed)
+  }
+
+  /// The error returned by an operation, if operations are failing.
+  fn op_error(&self, name: &str) -> Option<AsyncResult<ExitStatus>> {
+    if !self.fail_ops.load(Ordering::Relaxed) {
+      return None;
+    }
+    Some(Box::pin(ready(Err(
+      McError::InvalidOp(format!("Simulated failure to run {name}")).into(),
+    ))))
+  }
 }
 
 #[async_trait]
@@ -36,6 +106,12 @@ impl Unit for SimUnit {
   }
 
   async fn refresh(&mut self) -> Result<(), Box<dyn ThreadSafeError>> {
+    if self.faults.fail_refresh.load(Ordering::Relaxed) {
+      return Err(McError::InvalidOp(format!("Simulated refresh failure of {}", self.name)).into());
+    }
+    if self.faults.ops_hang() {
+      return Ok(());
+    }
     let now = Instant::now();
     if self.state == ServerState::Booting && now >= self.last_update + OP_DELAY {
       self.state = ServerState::On;
@@ -45,9 +121,24 @@ impl Unit for SimUnit {
     Ok(())
   }
 
+  /// Stops the unit, taking `OP_DELAY` unless set otherwise, then starts it
+  /// again, taking another `OP_DELAY` after the returned future completes.
   fn restart(&mut self) -> AsyncResult<ExitStatus> {
-    #[allow(unreachable_code)]
-    Box::pin(ready(unimplemented!()))
+    if self.state != ServerState::On {
+      return Box::pin(ready(Err(
+        McError::InvalidOp(format!("Server is not in On state: {:?}", self.state)).into(),
+      )));
+    }
+    if let Some(err) = self.faults.op_error("restart") {
+      return err;
+    }
+    if self.faults.ops_hang() {
+      return Box::pin(pending());
+    }
+    let stop_delay = self.faults.stop_delay();
+    self.state = ServerState::Booting;
+    self.last_update = Instant::now() + stop_delay;
+    Box::pin(sleep(stop_delay).map(|_| Ok(ExitStatus::from_raw(0))))
   }
 
   fn start(&mut self) -> AsyncResult<ExitStatus> {
@@ -56,20 +147,29 @@ impl Unit for SimUnit {
         McError::InvalidOp(format!("Server is not in Off state: {:?}", self.state)).into(),
       )));
     }
+    if let Some(err) = self.faults.op_error("start") {
+      return err;
+    }
     self.state = ServerState::Booting;
     self.last_update = Instant::now();
     Box::pin(ready(Ok(ExitStatus::from_raw(0))))
   }
 
   fn stop(&mut self) -> AsyncResult<ExitStatus> {
-    if self.state != ServerState::On {
+    if !matches!(self.state, ServerState::On | ServerState::Booting) {
       return Box::pin(ready(Err(
-        McError::InvalidOp(format!("Server is not in On state: {:?}", self.state)).into(),
+        McError::InvalidOp(format!("Server is not on or booting: {:?}", self.state)).into(),
       )));
     }
+    if let Some(err) = self.faults.op_error("stop") {
+      return err;
+    }
+    if self.faults.ops_hang() {
+      return Box::pin(pending());
+    }
     self.state = ServerState::Off;
     self.last_update = Instant::now();
-    Box::pin(sleep(OP_DELAY).map(|_| Ok(ExitStatus::from_raw(0))))
+    Box::pin(sleep(self.faults.stop_delay()).map(|_| Ok(ExitStatus::from_raw(0))))
   }
 
   fn reload(&mut self) -> AsyncResult<ExitStatus> {
@@ -1,35 +1,156 @@
 use std::time::Duration;
 
 use futures_util::Future;
-use pc_landing_page::proto::ServerState;
+use pc_landing_page::{
+  controller::ControllerEvent,
+  proto::ServerState,
+  systemctl::{sim_unit::SimUnit, unit::Unit},
+};
 use rstest::{fixture, rstest};
 use tokio::{
   join,
   time::{self, Instant},
 };
 
-use self::fixtures::Fixture;
+use self::fixtures::{
+  Fixture, SimProbe, BOOT_DEADLINE, IDLE_WARNING, REFRESH_INTERVAL, SHUTDOWN_DEADLINE,
+};
 
 mod fixtures {
+  use std::{
+    sync::{
+      atomic::{AtomicBool, AtomicU32, Ordering},
+      Arc, Mutex,
+    },
+    time::Duration,
+  };
+
+  use async_trait::async_trait;
   use pc_landing_page::{
-    controller::ServerController,
-    systemctl::{sim_unit::SimUnit, unit::Unit},
+    controller::{ControllerOptions, ServerController},
+    error::{McError, ThreadSafeError},
+    readiness::ReadinessProbe,
+    slp::ServerInfo,
+    systemctl::sim_unit::{SimFaults, SimUnit},
   };
+  use tokio::time;
+
+  pub const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+  pub const BOOT_DEADLINE: Duration = Duration::from_secs(30);
+  pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(20);
+  pub const IDLE_WARNING: Duration = Duration::from_secs(20);
+
+  fn options(refresh_interval: Duration) -> ControllerOptions {
+    ControllerOptions {
+      refresh_interval,
+      boot_deadline: BOOT_DEADLINE,
+      shutdown_deadline: SHUTDOWN_DEADLINE,
+      idle_timeout: None,
+      idle_warning: IDLE_WARNING,
+    }
+  }
+
+  /// A readiness probe which succeeds once marked ready.
+  #[derive(Clone, Default)]
+  pub struct SimProbe {
+    ready: Arc<AtomicBool>,
+    players_online: Arc<AtomicU32>,
+    delay: Arc<Mutex<Duration>>,
+  }
+
+  impl SimProbe {
+    pub fn set_ready(&self, ready: bool) {
+      self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn set_players_online(&self, players_online: u32) {
+      self.players_online.store(players_online, Ordering::Relaxed);
+    }
+
+    /// Makes each probe take `delay` to answer, like a server that's slow to
+    /// respond.
+    pub fn set_delay(&self, delay: Duration) {
+      *self.delay.lock().unwrap() = delay;
+    }
+
+    pub fn info() -> ServerInfo {
+      ServerInfo {
+        motd: "A Minecraft Server".to_owned(),
+        version: "1.20.4".to_owned(),
+        players_online: 0,
+        players_max: 20,
+        players: vec![],
+      }
+    }
+  }
+
+  #[async_trait]
+  impl ReadinessProbe for SimProbe {
+    async fn probe(&self) -> Result<ServerInfo, Box<dyn ThreadSafeError>> {
+      let delay = *self.delay.lock().unwrap();
+      time::sleep(delay).await;
+      if self.ready.load(Ordering::Relaxed) {
+        Ok(ServerInfo {
+          players_online: self.players_online.load(Ordering::Relaxed),
+          ..Self::info()
+        })
+      } else {
+        Err(McError::InvalidOp("Connection refused".to_owned()).into())
+      }
+    }
+  }
 
   pub struct Fixture {
     controller: ServerController<SimUnit>,
+    faults: SimFaults,
   }
 
   impl Fixture {
     pub fn new() -> Self {
+      Self::with_refresh_interval(REFRESH_INTERVAL)
+    }
+
+    pub fn with_refresh_interval(refresh_interval: Duration) -> Self {
+      let unit = SimUnit::new("test_unit.service".to_owned());
+      let faults = unit.faults();
+      Self {
+        controller: ServerController::with_options(unit, options(refresh_interval)),
+        faults,
+      }
+    }
+
+    pub fn with_probe(probe: SimProbe) -> Self {
+      Self::with_unit_and_probe(SimUnit::new("test_unit.service".to_owned()), probe)
+    }
+
+    pub fn with_unit_and_probe(unit: SimUnit, probe: SimProbe) -> Self {
+      let faults = unit.faults();
       Self {
-        controller: ServerController::new(SimUnit::new("test_unit.service".to_owned())),
+        controller: ServerController::with_probe(unit, probe, options(REFRESH_INTERVAL)),
+        faults,
       }
     }
 
-    pub fn controller(&self) -> &ServerController<impl Unit> {
+    pub fn with_idle_timeout(probe: SimProbe, idle_timeout: Duration) -> Self {
+      let unit = SimUnit::new("test_unit.service".to_owned());
+      let faults = unit.faults();
+      let options = ControllerOptions {
+        idle_timeout: Some(idle_timeout),
+        ..options(REFRESH_INTERVAL)
+      };
+      Self {
+        controller: ServerController::with_probe(unit, probe, options),
+        faults,
+      }
+    }
+
+    pub fn controller(&self) -> &ServerController<SimUnit> {
       &self.controller
     }
+
+    pub fn faults(&self) -> &SimFaults {
+      &self.faults
+    }
   }
 }
 
@@ -42,20 +163,16 @@ fn boot_test() -> Fixture {
 #[rstest]
 #[tokio::test]
 async fn test_default_state(boot_test: Fixture) {
-  assert_eq!(
-    boot_test.controller().server_state().await.unwrap(),
-    ServerState::Off
-  );
+  assert_eq!(boot_test.controller().server_state(), ServerState::Unknown);
+  time::sleep(Duration::from_millis(1)).await;
+  assert_eq!(boot_test.controller().server_state(), ServerState::Off);
 }
 
 #[rstest]
 #[tokio::test]
 async fn test_boot_moves_to_booting_state(boot_test: Fixture) {
   assert!(boot_test.controller().boot_server().await.is_ok());
-  assert_eq!(
-    boot_test.controller().server_state().await.unwrap(),
-    ServerState::Booting
-  );
+  assert_eq!(boot_test.controller().server_state(), ServerState::Booting);
 }
 
 #[rstest]
@@ -63,10 +180,7 @@ async fn test_boot_moves_to_booting_state(boot_test: Fixture) {
 async fn test_boot_stays_booting_for_5s(boot_test: Fixture) {
   assert!(boot_test.controller().boot_server().await.is_ok());
   time::sleep(Duration::from_millis(4990)).await;
-  assert_eq!(
-    boot_test.controller().server_state().await.unwrap(),
-    ServerState::Booting
-  );
+  assert_eq!(boot_test.controller().server_state(), ServerState::Booting);
 }
 
 #[rstest]
@@ -74,10 +188,7 @@ async fn test_boot_stays_booting_for_5s(boot_test: Fixture) {
 async fn test_boot_completes_after_5s(boot_test: Fixture) {
   assert!(boot_test.controller().boot_server().await.is_ok());
   time::sleep(Duration::from_millis(5001)).await;
-  assert_eq!(
-    boot_test.controller().server_state().await.unwrap(),
-    ServerState::On
-  );
+  assert_eq!(boot_test.controller().server_state(), ServerState::On);
 }
 
 #[rstest]
@@ -123,12 +234,12 @@ async fn test_shutdown_moves_to_shutdown_state(shutdown_test: impl Future<Output
   let (state, shutdown_result) = join!(
     async {
       time::sleep(Duration::from_millis(1)).await;
-      shutdown_test.controller().server_state().await
+      shutdown_test.controller().server_state()
     },
     shutdown_test.controller().shutdown_server(),
   );
   assert!(shutdown_result.is_ok());
-  assert_eq!(state.unwrap(), ServerState::Shutdown);
+  assert_eq!(state, ServerState::Shutdown);
 }
 
 #[rstest]
@@ -138,12 +249,12 @@ async fn test_shutdown_stays_shutting_down_for_5s(shutdown_test: impl Future<Out
   let (state, shutdown_result) = join!(
     async {
       time::sleep(Duration::from_millis(4990)).await;
-      shutdown_test.controller().server_state().await
+      shutdown_test.controller().server_state()
     },
     shutdown_test.controller().shutdown_server(),
   );
   assert!(shutdown_result.is_ok());
-  assert_eq!(state.unwrap(), ServerState::Shutdown);
+  assert_eq!(state, ServerState::Shutdown);
 }
 
 #[rstest]
@@ -153,12 +264,12 @@ async fn test_shutdown_completes_after_5s(shutdown_test: impl Future<Output = Fi
   let (state, shutdown_result) = join!(
     async {
       time::sleep(Duration::from_millis(5001)).await;
-      shutdown_test.controller().server_state().await
+      shutdown_test.controller().server_state()
     },
     shutdown_test.controller().shutdown_server(),
   );
   assert!(shutdown_result.is_ok());
-  assert_eq!(state.unwrap(), ServerState::Off);
+  assert_eq!(state, ServerState::Off);
 }
 
 #[rstest]
@@ -182,3 +293,703 @@ async fn test_shutdown_fails_if_already_off(shutdown_test: impl Future<Output =
   assert!(shutdown_test.controller().shutdown_server().await.is_ok());
   assert!(shutdown_test.controller().shutdown_server().await.is_err());
 }
+
+#[rstest]
+#[tokio::test]
+async fn test_boot_publishes_state_changes(boot_test: Fixture) {
+  let mut states = boot_test.controller().subscribe();
+  assert_eq!(states.borrow_and_update().state, ServerState::Unknown);
+
+  boot_test.controller().boot_server().await.unwrap();
+  assert!(states.has_changed().unwrap());
+  assert_eq!(states.borrow_and_update().state, ServerState::Booting);
+
+  time::sleep(Duration::from_secs(6)).await;
+  assert!(states.has_changed().unwrap());
+  assert_eq!(states.borrow_and_update().state, ServerState::On);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_refresh_without_transition_publishes_nothing(boot_test: Fixture) {
+  time::sleep(Duration::from_millis(1)).await;
+  let mut states = boot_test.controller().subscribe();
+  assert_eq!(states.borrow_and_update().state, ServerState::Off);
+
+  time::sleep(Duration::from_secs(6)).await;
+  assert!(!states.has_changed().unwrap());
+  assert!(
+    boot_test.controller().snapshot().refreshed_at.unwrap() > Instant::now() - REFRESH_INTERVAL
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_refresh_interval_is_configurable() {
+  time::pause();
+  let fixture = Fixture::with_refresh_interval(Duration::from_secs(10));
+  fixture.controller().boot_server().await.unwrap();
+
+  time::sleep(Duration::from_secs(9)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Booting);
+  time::sleep(Duration::from_secs(2)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_failed_refresh_marks_state_stale(boot_test: Fixture) {
+  time::sleep(Duration::from_millis(1)).await;
+  let refreshed_at = boot_test.controller().snapshot().refreshed_at;
+  assert!(refreshed_at.is_some());
+  assert!(!boot_test.controller().snapshot().stale);
+
+  boot_test.faults().set_refresh_fails(true);
+  time::sleep(REFRESH_INTERVAL).await;
+  let snapshot = boot_test.controller().snapshot();
+  assert!(snapshot.stale);
+  assert_eq!(snapshot.state, ServerState::Off);
+  assert_eq!(snapshot.refreshed_at, refreshed_at);
+
+  boot_test.faults().set_refresh_fails(false);
+  time::sleep(REFRESH_INTERVAL).await;
+  let snapshot = boot_test.controller().snapshot();
+  assert!(!snapshot.stale);
+  assert!(snapshot.refreshed_at > refreshed_at);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_staleness_is_published(boot_test: Fixture) {
+  time::sleep(Duration::from_millis(1)).await;
+  let mut states = boot_test.controller().subscribe();
+  states.borrow_and_update();
+
+  boot_test.faults().set_refresh_fails(true);
+  states.changed().await.unwrap();
+  assert!(states.borrow_and_update().stale);
+
+  boot_test.faults().set_refresh_fails(false);
+  states.changed().await.unwrap();
+  assert!(!states.borrow_and_update().stale);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_shutdown_publishes_state_changes(shutdown_test: impl Future<Output = Fixture>) {
+  let shutdown_test = shutdown_test.await;
+  let mut states = shutdown_test.controller().subscribe();
+  assert_eq!(states.borrow_and_update().state, ServerState::On);
+
+  let (seen, shutdown_result) = join!(
+    async {
+      states.changed().await.unwrap();
+      let shutting_down = states.borrow_and_update().state;
+      states.changed().await.unwrap();
+      (shutting_down, states.borrow_and_update().state)
+    },
+    shutdown_test.controller().shutdown_server(),
+  );
+  assert!(shutdown_result.is_ok());
+  assert_eq!(seen, (ServerState::Shutdown, ServerState::Off));
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_awaits_stop(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+
+  let start = Instant::now();
+  assert!(restart_test.controller().restart_server().await.is_ok());
+  let end = Instant::now();
+
+  assert!(end - start >= Duration::from_secs(5));
+  assert_eq!(
+    restart_test.controller().server_state(),
+    ServerState::Booting
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_moves_to_restarting_state(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+  let (states, restart_result) = join!(
+    async {
+      time::sleep(Duration::from_millis(1)).await;
+      let restarting = restart_test.controller().server_state();
+      time::sleep(Duration::from_millis(4990)).await;
+      (restarting, restart_test.controller().server_state())
+    },
+    restart_test.controller().restart_server(),
+  );
+  assert!(restart_result.is_ok());
+  assert_eq!(states, (ServerState::Restarting, ServerState::Restarting));
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_completes_after_10s(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+  restart_test.controller().restart_server().await.unwrap();
+
+  time::sleep(Duration::from_millis(4990)).await;
+  assert_eq!(
+    restart_test.controller().server_state(),
+    ServerState::Booting
+  );
+  time::sleep(REFRESH_INTERVAL).await;
+  assert_eq!(restart_test.controller().server_state(), ServerState::On);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_fails_if_off(boot_test: Fixture) {
+  assert!(boot_test.controller().restart_server().await.is_err());
+  assert_eq!(boot_test.controller().server_state(), ServerState::Off);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_fails_if_booting(boot_test: Fixture) {
+  boot_test.controller().boot_server().await.unwrap();
+  assert!(boot_test.controller().restart_server().await.is_err());
+  assert_eq!(boot_test.controller().server_state(), ServerState::Booting);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_ops_fail_while_restarting(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+  let (results, restart_result) = join!(
+    async {
+      time::sleep(Duration::from_millis(1)).await;
+      (
+        restart_test.controller().boot_server().await,
+        restart_test.controller().shutdown_server().await,
+        restart_test.controller().restart_server().await,
+      )
+    },
+    restart_test.controller().restart_server(),
+  );
+  assert!(restart_result.is_ok());
+  assert!(results.0.is_err());
+  assert!(results.1.is_err());
+  assert!(results.2.is_err());
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_publishes_state_changes(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+  let mut states = restart_test.controller().subscribe();
+  assert_eq!(states.borrow_and_update().state, ServerState::On);
+
+  let (seen, restart_result) = join!(
+    async {
+      let mut seen = vec![];
+      while seen.last() != Some(&ServerState::On) {
+        states.changed().await.unwrap();
+        seen.push(states.borrow_and_update().state);
+      }
+      seen
+    },
+    restart_test.controller().restart_server(),
+  );
+  assert!(restart_result.is_ok());
+  assert_eq!(
+    seen,
+    vec![
+      ServerState::Restarting,
+      ServerState::Booting,
+      ServerState::On
+    ]
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_boot_fails_after_deadline(boot_test: Fixture) {
+  boot_test.faults().set_ops_hang(true);
+  boot_test.controller().boot_server().await.unwrap();
+
+  time::sleep(BOOT_DEADLINE - Duration::from_secs(2)).await;
+  assert_eq!(boot_test.controller().server_state(), ServerState::Booting);
+  time::sleep(Duration::from_secs(3)).await;
+  let snapshot = boot_test.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Failed);
+  assert_eq!(
+    snapshot.failure.as_deref(),
+    Some("Server didn't finish booting within 30s")
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_boot_within_deadline_succeeds(boot_test: Fixture) {
+  boot_test.controller().boot_server().await.unwrap();
+  time::sleep(BOOT_DEADLINE + Duration::from_secs(1)).await;
+  let snapshot = boot_test.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::On);
+  assert_eq!(snapshot.failure, None);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_shutdown_fails_after_deadline(shutdown_test: impl Future<Output = Fixture>) {
+  let shutdown_test = shutdown_test.await;
+  shutdown_test.faults().set_ops_hang(true);
+
+  let start = Instant::now();
+  assert!(shutdown_test.controller().shutdown_server().await.is_err());
+  assert!(Instant::now() - start >= SHUTDOWN_DEADLINE);
+  let snapshot = shutdown_test.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Failed);
+  assert_eq!(
+    snapshot.failure.as_deref(),
+    Some("Server shutdown didn't finish within 20s")
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_fails_after_deadline(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+  restart_test.faults().set_ops_hang(true);
+  assert!(restart_test.controller().restart_server().await.is_err());
+  assert_eq!(
+    restart_test.controller().server_state(),
+    ServerState::Failed
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_boot_command_error_returns_to_off(boot_test: Fixture) {
+  boot_test.faults().set_ops_fail(true);
+  assert!(boot_test.controller().boot_server().await.is_err());
+  let snapshot = boot_test.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Off);
+  assert_eq!(snapshot.failure, None);
+
+  boot_test.faults().set_ops_fail(false);
+  boot_test.controller().boot_server().await.unwrap();
+  assert_eq!(boot_test.controller().server_state(), ServerState::Booting);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_shutdown_command_error_returns_to_on(shutdown_test: impl Future<Output = Fixture>) {
+  let shutdown_test = shutdown_test.await;
+  shutdown_test.faults().set_ops_fail(true);
+  assert!(shutdown_test.controller().shutdown_server().await.is_err());
+  assert_eq!(shutdown_test.controller().server_state(), ServerState::On);
+
+  shutdown_test.faults().set_ops_fail(false);
+  shutdown_test.controller().shutdown_server().await.unwrap();
+  assert_eq!(shutdown_test.controller().server_state(), ServerState::Off);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_restart_with_slow_stop_succeeds(shutdown_test: impl Future<Output = Fixture>) {
+  let restart_test = shutdown_test.await;
+  restart_test
+    .faults()
+    .set_stop_delay(SHUTDOWN_DEADLINE + Duration::from_secs(5));
+  restart_test.controller().restart_server().await.unwrap();
+  assert_eq!(
+    restart_test.controller().server_state(),
+    ServerState::Booting
+  );
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_failed_state_survives_refreshes(boot_test: Fixture) {
+  boot_test.faults().set_ops_hang(true);
+  boot_test.controller().boot_server().await.unwrap();
+  time::sleep(BOOT_DEADLINE + Duration::from_secs(1)).await;
+
+  boot_test.faults().set_ops_hang(false);
+  time::sleep(Duration::from_secs(10)).await;
+  assert_eq!(boot_test.controller().server_state(), ServerState::Failed);
+  assert!(boot_test.controller().boot_server().await.is_err());
+  assert!(boot_test.controller().shutdown_server().await.is_err());
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_reset_moves_to_unit_state(boot_test: Fixture) {
+  boot_test.faults().set_ops_hang(true);
+  boot_test.controller().boot_server().await.unwrap();
+  time::sleep(BOOT_DEADLINE + Duration::from_secs(1)).await;
+
+  boot_test.controller().reset_server().await.unwrap();
+  let snapshot = boot_test.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Off);
+  assert_eq!(snapshot.failure, None);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_reset_after_failed_shutdown(shutdown_test: impl Future<Output = Fixture>) {
+  let shutdown_test = shutdown_test.await;
+  shutdown_test.faults().set_ops_hang(true);
+  assert!(shutdown_test.controller().shutdown_server().await.is_err());
+
+  shutdown_test.controller().reset_server().await.unwrap();
+  assert_eq!(shutdown_test.controller().server_state(), ServerState::On);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_reset_fails_if_not_failed(boot_test: Fixture) {
+  time::sleep(Duration::from_millis(1)).await;
+  assert!(boot_test.controller().reset_server().await.is_err());
+  assert_eq!(boot_test.controller().server_state(), ServerState::Off);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_cancel_boot_stops_server(boot_test: Fixture) {
+  boot_test.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_secs(1)).await;
+
+  let (state, cancel_result) = join!(
+    async {
+      time::sleep(Duration::from_millis(1)).await;
+      boot_test.controller().server_state()
+    },
+    boot_test.controller().cancel_operation(),
+  );
+  assert!(cancel_result.is_ok());
+  assert_eq!(state, ServerState::Shutdown);
+  assert_eq!(boot_test.controller().server_state(), ServerState::Off);
+
+  time::sleep(Duration::from_secs(10)).await;
+  assert_eq!(boot_test.controller().server_state(), ServerState::Off);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_cancel_shutdown_boots_server(shutdown_test: impl Future<Output = Fixture>) {
+  let shutdown_test = shutdown_test.await;
+  let (shutdown_result, cancel_result) =
+    join!(shutdown_test.controller().shutdown_server(), async {
+      time::sleep(Duration::from_secs(1)).await;
+      shutdown_test.controller().cancel_operation().await
+    });
+  assert!(cancel_result.is_ok());
+  assert!(shutdown_result.is_err());
+  assert_eq!(
+    shutdown_test.controller().server_state(),
+    ServerState::Booting
+  );
+
+  time::sleep(Duration::from_secs(6)).await;
+  assert_eq!(shutdown_test.controller().server_state(), ServerState::On);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_cancel_fails_without_operation(boot_test: Fixture) {
+  time::sleep(Duration::from_millis(1)).await;
+  assert!(boot_test.controller().cancel_operation().await.is_err());
+  assert_eq!(boot_test.controller().server_state(), ServerState::Off);
+
+  boot_test.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_secs(6)).await;
+  assert!(boot_test.controller().cancel_operation().await.is_err());
+  assert_eq!(boot_test.controller().server_state(), ServerState::On);
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_cancelled_boot_can_be_retried(boot_test: Fixture) {
+  boot_test.controller().boot_server().await.unwrap();
+  boot_test.controller().cancel_operation().await.unwrap();
+  boot_test.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_secs(6)).await;
+  assert_eq!(boot_test.controller().server_state(), ServerState::On);
+}
+
+/// Cancels a boot at the moment the refresh which sees the unit become active
+/// runs, in whichever order the two take the lock. Either the cancel wins and
+/// the server ends up off, or the refresh wins and there's nothing to cancel.
+#[rstest]
+#[case::cancel_first(Duration::ZERO)]
+#[case::refresh_first(Duration::from_millis(1))]
+#[tokio::test]
+async fn test_cancel_boot_racing_refresh(#[case] after_refresh: Duration) {
+  time::pause();
+  let fixture = Fixture::new();
+  fixture.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_secs(5) + after_refresh).await;
+
+  let cancel_result = fixture.controller().cancel_operation().await;
+  time::sleep(Duration::from_secs(6)).await;
+  let state = fixture.controller().server_state();
+  match cancel_result {
+    Ok(()) => assert_eq!(state, ServerState::Off),
+    Err(_) => assert_eq!(state, ServerState::On),
+  }
+  assert_eq!(cancel_result.is_ok(), after_refresh.is_zero());
+}
+
+#[rstest]
+#[tokio::test]
+async fn test_cancelled_shutdown_completing_late_changes_nothing(
+  shutdown_test: impl Future<Output = Fixture>,
+) {
+  let shutdown_test = shutdown_test.await;
+  let mut states = shutdown_test.controller().subscribe();
+  states.borrow_and_update();
+
+  let (seen, _, _) = join!(
+    async {
+      let mut seen = vec![];
+      while seen.last() != Some(&ServerState::On) {
+        states.changed().await.unwrap();
+        seen.push(states.borrow_and_update().state);
+      }
+      seen
+    },
+    shutdown_test.controller().shutdown_server(),
+    async {
+      time::sleep(Duration::from_secs(1)).await;
+      shutdown_test.controller().cancel_operation().await
+    },
+  );
+  assert_eq!(
+    seen,
+    vec![ServerState::Shutdown, ServerState::Booting, ServerState::On]
+  );
+  time::sleep(Duration::from_secs(10)).await;
+  assert_eq!(shutdown_test.controller().server_state(), ServerState::On);
+}
+
+#[tokio::test]
+async fn test_boot_waits_for_readiness() {
+  time::pause();
+  let probe = SimProbe::default();
+  let fixture = Fixture::with_probe(probe.clone());
+  fixture.controller().boot_server().await.unwrap();
+
+  time::sleep(Duration::from_secs(10)).await;
+  let snapshot = fixture.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Booting);
+  assert_eq!(snapshot.info, None);
+
+  probe.set_ready(true);
+  time::sleep(REFRESH_INTERVAL).await;
+  let snapshot = fixture.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::On);
+  assert_eq!(snapshot.info, Some(SimProbe::info()));
+}
+
+#[tokio::test]
+async fn test_boot_fails_if_never_ready() {
+  time::pause();
+  let fixture = Fixture::with_probe(SimProbe::default());
+  fixture.controller().boot_server().await.unwrap();
+
+  time::sleep(BOOT_DEADLINE + Duration::from_secs(1)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Failed);
+}
+
+#[tokio::test]
+async fn test_unit_started_elsewhere_fails_if_never_ready() {
+  time::pause();
+  // As if started before the controller, e.g. by hand.
+  let mut unit = SimUnit::new("test_unit.service".to_owned());
+  unit.start().await.unwrap();
+  let fixture = Fixture::with_unit_and_probe(unit, SimProbe::default());
+
+  time::sleep(Duration::from_secs(6)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Booting);
+  time::sleep(BOOT_DEADLINE).await;
+  let snapshot = fixture.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Failed);
+  assert_eq!(
+    snapshot.failure.as_deref(),
+    Some("Server didn't finish booting within 30s")
+  );
+}
+
+#[tokio::test]
+async fn test_on_while_probe_fails() {
+  time::pause();
+  let probe = SimProbe::default();
+  probe.set_ready(true);
+  let fixture = Fixture::with_probe(probe.clone());
+  fixture.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_secs(6)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+
+  probe.set_ready(false);
+  time::sleep(REFRESH_INTERVAL).await;
+  let snapshot = fixture.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::On);
+  assert_eq!(snapshot.info, None);
+}
+
+#[tokio::test]
+async fn test_info_cleared_after_shutdown() {
+  time::pause();
+  let probe = SimProbe::default();
+  probe.set_ready(true);
+  let fixture = Fixture::with_probe(probe.clone());
+  fixture.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_secs(6)).await;
+  assert!(fixture.controller().snapshot().info.is_some());
+
+  fixture.controller().shutdown_server().await.unwrap();
+  let snapshot = fixture.controller().snapshot();
+  assert_eq!(snapshot.state, ServerState::Off);
+  assert_eq!(snapshot.info, None);
+}
+
+#[tokio::test]
+async fn test_slow_probe_doesnt_block_operations() {
+  time::pause();
+  let probe = SimProbe::default();
+  probe.set_ready(true);
+  probe.set_delay(Duration::from_secs(60));
+  let fixture = Fixture::with_probe(probe.clone());
+  fixture.controller().boot_server().await.unwrap();
+  // Let the unit become active, so a refresh starts probing.
+  time::sleep(Duration::from_secs(6)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Booting);
+
+  let start = Instant::now();
+  fixture.controller().cancel_operation().await.unwrap();
+  assert!(Instant::now() - start < Duration::from_secs(10));
+  assert_eq!(fixture.controller().server_state(), ServerState::Off);
+
+  // The probe finishing late doesn't bring the server back on.
+  time::sleep(Duration::from_secs(60)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Off);
+}
+
+#[tokio::test]
+async fn test_slow_start_hook_doesnt_block_operations() {
+  time::pause();
+  let fixture = Fixture::new();
+  fixture
+    .controller()
+    .add_start_hook(Box::new(|| Box::pin(time::sleep(Duration::from_secs(10)))));
+
+  let (booted, ()) = join!(fixture.controller().boot_server(), async {
+    time::sleep(Duration::from_secs(1)).await;
+    let start = Instant::now();
+    assert!(fixture.controller().reset_server().await.is_err());
+    assert_eq!(Instant::now(), start);
+    assert_eq!(fixture.controller().server_state(), ServerState::Booting);
+  });
+  booted.unwrap();
+  assert_eq!(fixture.controller().server_state(), ServerState::Booting);
+}
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Boots a server with an idle policy, returning once it's on.
+async fn idle_test(probe: &SimProbe) -> Fixture {
+  time::pause();
+  probe.set_ready(true);
+  let fixture = Fixture::with_idle_timeout(probe.clone(), IDLE_TIMEOUT);
+  fixture.controller().boot_server().await.unwrap();
+  time::sleep(Duration::from_millis(5500)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+  fixture
+}
+
+#[tokio::test]
+async fn test_idle_server_shuts_down() {
+  let probe = SimProbe::default();
+  let fixture = idle_test(&probe).await;
+  let mut events = fixture.controller().events();
+
+  time::sleep(IDLE_TIMEOUT - Duration::from_secs(2)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+  assert_eq!(fixture.controller().snapshot().last_auto_shutdown, None);
+
+  time::sleep(Duration::from_secs(2)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Shutdown);
+  let auto_shutdown = fixture.controller().snapshot().last_auto_shutdown.unwrap();
+  assert_eq!(auto_shutdown.reason, "No players were online for 60s");
+
+  time::sleep(Duration::from_secs(6)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Off);
+
+  assert!(matches!(
+    events.try_recv().unwrap(),
+    ControllerEvent::IdleShutdownWarning { shutdown_in } if shutdown_in <= IDLE_WARNING
+  ));
+  assert_eq!(
+    events.try_recv().unwrap(),
+    ControllerEvent::AutoShutdown {
+      reason: auto_shutdown.reason
+    }
+  );
+  assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_warns_before_idle_shutdown() {
+  let probe = SimProbe::default();
+  let fixture = idle_test(&probe).await;
+  let mut events = fixture.controller().events();
+
+  time::sleep(IDLE_TIMEOUT - IDLE_WARNING - Duration::from_secs(2)).await;
+  assert!(events.try_recv().is_err());
+  time::sleep(Duration::from_secs(2)).await;
+  assert!(matches!(
+    events.try_recv().unwrap(),
+    ControllerEvent::IdleShutdownWarning { .. }
+  ));
+
+  // Only one warning is sent.
+  time::sleep(Duration::from_secs(10)).await;
+  assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_players_keep_server_on() {
+  let probe = SimProbe::default();
+  probe.set_players_online(1);
+  let fixture = idle_test(&probe).await;
+  let mut events = fixture.controller().events();
+
+  time::sleep(2 * IDLE_TIMEOUT).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+  assert!(events.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_player_joining_resets_idle_time() {
+  let probe = SimProbe::default();
+  let fixture = idle_test(&probe).await;
+
+  time::sleep(IDLE_TIMEOUT - Duration::from_secs(10)).await;
+  probe.set_players_online(1);
+  time::sleep(REFRESH_INTERVAL).await;
+  probe.set_players_online(0);
+
+  time::sleep(IDLE_TIMEOUT - Duration::from_secs(2)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+  time::sleep(Duration::from_secs(4)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::Shutdown);
+}
+
+#[tokio::test]
+async fn test_idle_without_policy_stays_on() {
+  time::pause();
+  let probe = SimProbe::default();
+  probe.set_ready(true);
+  let fixture = Fixture::with_probe(probe);
+  fixture.controller().boot_server().await.unwrap();
+
+  time::sleep(Duration::from_secs(3600)).await;
+  assert_eq!(fixture.controller().server_state(), ServerState::On);
+}
@@ -0,0 +1,144 @@
+use std::{net::SocketAddr, time::Duration};
+
+use pc_landing_page::{
+  controller::ServerController,
+  proto::ServerState,
+  sleep_proxy::{SleepProxy, SLEEPING_MOTD, WAKING_MESSAGE},
+  slp::{self, read_varint, write_varint},
+  systemctl::sim_unit::SimUnit,
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  time,
+};
+
+/// Picks a port nothing is listening on.
+async fn free_addr() -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  listener.local_addr().unwrap()
+}
+
+/// Starts a sleep proxy on a free port for a simulated server, which the
+/// proxy boots when a player joins.
+async fn sleeping_server() -> (SocketAddr, ServerController<SimUnit>) {
+  let addr = free_addr().await;
+  (addr, sleeping_server_on(addr))
+}
+
+/// Starts a sleep proxy on `addr` for a simulated server.
+fn sleeping_server_on(addr: SocketAddr) -> ServerController<SimUnit> {
+  let controller = ServerController::new(SimUnit::new("sleepy.service".to_owned()));
+  SleepProxy::spawn(addr, &controller);
+  controller
+}
+
+/// Connects to `addr`, waiting for the proxy to start listening.
+async fn connect(addr: SocketAddr) -> TcpStream {
+  for _ in 0..100 {
+    if let Ok(stream) = TcpStream::connect(addr).await {
+      return stream;
+    }
+    time::sleep(Duration::from_millis(10)).await;
+  }
+  panic!("Nothing is listening on {addr}");
+}
+
+fn login_handshake(addr: SocketAddr) -> Vec<u8> {
+  let host = addr.ip().to_string();
+  let mut data = Vec::new();
+  write_varint(&mut data, 765);
+  slp::write_string(&mut data, &host);
+  data.extend(addr.port().to_be_bytes());
+  write_varint(&mut data, 2);
+  slp::packet(0x00, &data)
+}
+
+/// Reads a login disconnect packet, returning its reason.
+async fn read_disconnect(stream: &mut TcpStream) -> String {
+  let len = read_varint(stream).await.unwrap();
+  let mut body = vec![0; len as usize];
+  stream.read_exact(&mut body).await.unwrap();
+  let mut fields = body.as_slice();
+  assert_eq!(read_varint(&mut fields).await.unwrap(), 0x00);
+  let len = read_varint(&mut fields).await.unwrap() as usize;
+  String::from_utf8(fields[..len].to_vec()).unwrap()
+}
+
+async fn wait_for_state(controller: &ServerController<SimUnit>, state: ServerState) {
+  let mut snapshots = controller.subscribe();
+  time::timeout(
+    Duration::from_secs(1),
+    snapshots.wait_for(|snapshot| snapshot.state == state),
+  )
+  .await
+  .unwrap()
+  .unwrap();
+}
+
+#[tokio::test]
+async fn test_ping_while_sleeping() {
+  let (addr, controller) = sleeping_server().await;
+  drop(connect(addr).await);
+
+  let info = slp::ping("127.0.0.1", addr.port()).await.unwrap();
+  assert_eq!(info.motd, SLEEPING_MOTD);
+  assert_eq!((info.players_online, info.players_max), (0, 0));
+  assert_eq!(controller.server_state(), ServerState::Off);
+}
+
+#[tokio::test]
+async fn test_login_wakes_server() {
+  let (addr, controller) = sleeping_server().await;
+  let mut stream = connect(addr).await;
+  stream.write_all(&login_handshake(addr)).await.unwrap();
+
+  let reason = read_disconnect(&mut stream).await;
+  assert_eq!(
+    serde_json::from_str::<serde_json::Value>(&reason).unwrap(),
+    serde_json::json!({ "text": WAKING_MESSAGE })
+  );
+  wait_for_state(&controller, ServerState::Booting).await;
+  // The real server can take over the port.
+  TcpListener::bind(addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_boot_releases_port() {
+  let (addr, controller) = sleeping_server().await;
+  drop(connect(addr).await);
+
+  controller.boot_server().await.unwrap();
+  assert_eq!(controller.server_state(), ServerState::Booting);
+  TcpListener::bind(addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ignores_other_packets() {
+  let (addr, controller) = sleeping_server().await;
+  let mut stream = connect(addr).await;
+  stream.write_all(&slp::packet(0x7a, &[])).await.unwrap();
+
+  // The proxy hangs up without booting the server.
+  assert_eq!(stream.read(&mut [0; 16]).await.unwrap(), 0);
+  assert_eq!(controller.server_state(), ServerState::Off);
+  assert_eq!(
+    slp::ping("127.0.0.1", addr.port()).await.unwrap().motd,
+    SLEEPING_MOTD
+  );
+}
+
+#[tokio::test]
+async fn test_boot_while_port_taken() {
+  let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let controller = sleeping_server_on(taken.local_addr().unwrap());
+  wait_for_state(&controller, ServerState::Off).await;
+  time::sleep(Duration::from_millis(50)).await;
+
+  // The proxy backs off without holding up the boot.
+  time::timeout(Duration::from_secs(1), controller.boot_server())
+    .await
+    .unwrap()
+    .unwrap();
+  assert_eq!(controller.server_state(), ServerState::Booting);
+}
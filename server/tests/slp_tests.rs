@@ -0,0 +1,150 @@
+use pc_landing_page::{
+  readiness::{ReadinessProbe, SlpProbe},
+  slp::{self, read_varint, write_varint, ServerInfo},
+};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+  task::JoinHandle,
+};
+
+const STATUS_JSON: &str = r#"{
+  "version": { "name": "1.20.4", "protocol": 765 },
+  "players": {
+    "max": 20,
+    "online": 3,
+    "sample": [
+      { "name": "alice", "id": "4566e69f-c907-48ee-8d71-d7ba5aa00d20" },
+      { "name": "bob", "id": "4566e69f-c907-48ee-8d71-d7ba5aa00d21" },
+      { "name": "carol", "id": "4566e69f-c907-48ee-8d71-d7ba5aa00d22" }
+    ]
+  },
+  "description": { "text": "§6Friends only" }
+}"#;
+
+/// The handshake a fake server received.
+#[derive(Debug, PartialEq, Eq)]
+struct Handshake {
+  host: String,
+  port: u16,
+  next_state: i32,
+}
+
+async fn read_packet(stream: &mut TcpStream) -> (i32, Vec<u8>) {
+  let len = read_varint(stream).await.unwrap();
+  let mut body = vec![0; len as usize];
+  stream.read_exact(&mut body).await.unwrap();
+  let mut fields = body.as_slice();
+  let id = read_varint(&mut fields).await.unwrap();
+  (id, fields.to_vec())
+}
+
+async fn read_handshake(stream: &mut TcpStream) -> Handshake {
+  let (id, fields) = read_packet(stream).await;
+  assert_eq!(id, 0x00);
+  let mut fields = fields.as_slice();
+  read_varint(&mut fields).await.unwrap();
+  let host_len = read_varint(&mut fields).await.unwrap() as usize;
+  let host = String::from_utf8(fields[..host_len].to_vec()).unwrap();
+  let mut fields = &fields[host_len..];
+  let port = fields.read_u16().await.unwrap();
+  let next_state = read_varint(&mut fields).await.unwrap();
+  Handshake {
+    host,
+    port,
+    next_state,
+  }
+}
+
+fn status_response(packet_id: i32, json: &str) -> Vec<u8> {
+  let mut body = Vec::new();
+  write_varint(&mut body, packet_id);
+  write_varint(&mut body, json.len() as i32);
+  body.extend(json.as_bytes());
+
+  let mut packet = Vec::new();
+  write_varint(&mut packet, body.len() as i32);
+  packet.extend(body);
+  packet
+}
+
+/// Starts a fake Minecraft server which answers one status request with
+/// `response`, returning its port and a task resolving to the handshake it
+/// received.
+async fn fake_server(response: Vec<u8>) -> (u16, JoinHandle<Handshake>) {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  let server = tokio::spawn(async move {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let handshake = read_handshake(&mut stream).await;
+    let (id, fields) = read_packet(&mut stream).await;
+    assert_eq!((id, fields.as_slice()), (0x00, [].as_slice()));
+    stream.write_all(&response).await.unwrap();
+    handshake
+  });
+  (port, server)
+}
+
+#[tokio::test]
+async fn test_ping() {
+  let (port, server) = fake_server(status_response(0x00, STATUS_JSON)).await;
+  assert_eq!(
+    slp::ping("127.0.0.1", port).await.unwrap(),
+    ServerInfo {
+      motd: "Friends only".to_owned(),
+      version: "1.20.4".to_owned(),
+      players_online: 3,
+      players_max: 20,
+      players: vec!["alice".to_owned(), "bob".to_owned(), "carol".to_owned()],
+    }
+  );
+  assert_eq!(
+    server.await.unwrap(),
+    Handshake {
+      host: "127.0.0.1".to_owned(),
+      port,
+      next_state: 1,
+    }
+  );
+}
+
+#[tokio::test]
+async fn test_ping_without_player_sample() {
+  let json = r#"{
+    "version": { "name": "1.20.4", "protocol": 765 },
+    "players": { "max": 20, "online": 0 },
+    "description": "Empty"
+  }"#;
+  let (port, _) = fake_server(status_response(0x00, json)).await;
+  let info = slp::ping("127.0.0.1", port).await.unwrap();
+  assert_eq!((info.players_online, info.players), (0, vec![]));
+}
+
+#[tokio::test]
+async fn test_ping_rejects_wrong_packet() {
+  let (port, _) = fake_server(status_response(0x01, STATUS_JSON)).await;
+  assert!(slp::ping("127.0.0.1", port).await.is_err());
+}
+
+#[tokio::test]
+async fn test_ping_rejects_truncated_response() {
+  let mut response = status_response(0x00, STATUS_JSON);
+  response.truncate(response.len() / 2);
+  let (port, _) = fake_server(response).await;
+  assert!(slp::ping("127.0.0.1", port).await.is_err());
+}
+
+#[tokio::test]
+async fn test_ping_fails_without_server() {
+  let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+  let port = listener.local_addr().unwrap().port();
+  drop(listener);
+  assert!(slp::ping("127.0.0.1", port).await.is_err());
+}
+
+#[tokio::test]
+async fn test_slp_probe() {
+  let (port, _) = fake_server(status_response(0x00, STATUS_JSON)).await;
+  let probe = SlpProbe::parse(&format!("127.0.0.1:{port}")).unwrap();
+  assert_eq!(probe.probe().await.unwrap().players_online, 3);
+}